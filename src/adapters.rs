@@ -0,0 +1,244 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::{Error, Result},
+    PurchaseInput, SkuType,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+const FLUTTER_SOURCE_APP_STORE: &str = "app_store";
+const FLUTTER_SOURCE_GOOGLE_PLAY: &str = "google_play";
+
+/// A serialized `PurchaseDetails` of Flutter's `in_app_purchase` plugin.
+///
+/// See <https://pub.dev/documentation/in_app_purchase_platform_interface/latest/in_app_purchase_platform_interface/PurchaseDetails-class.html>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlutterPurchaseDetails {
+    /// The transaction id on the App Store, or the order id on Google Play
+    #[serde(rename = "purchaseID")]
+    pub purchase_id: Option<String>,
+    /// The product id of the purchased product
+    #[serde(rename = "productID")]
+    pub product_id: String,
+    /// Either `pending`, `purchased`, `error`, `restored` or `canceled`
+    pub status: Option<String>,
+    /// Time of the purchase, in milliseconds since the Epoch
+    pub transaction_date: Option<String>,
+    /// The data used to verify the purchase
+    pub verification_data: FlutterVerificationData,
+}
+
+/// `PurchaseVerificationData` of Flutter's `in_app_purchase` plugin.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlutterVerificationData {
+    /// The original purchase json on Google Play, the base64 encoded receipt on the App Store
+    pub local_verification_data: String,
+    /// The purchase token on Google Play, the base64 encoded receipt or the signed transaction (JWS) on the App Store
+    pub server_verification_data: String,
+    /// Either `app_store` or `google_play`
+    pub source: String,
+}
+
+impl FlutterPurchaseDetails {
+    /// Deserialize the `FlutterPurchaseDetails` sent by the client
+    /// # Errors
+    /// Will return an error if the json cannot be deserialized
+    pub fn from(json_str: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json_str)?)
+    }
+}
+
+impl TryFrom<FlutterPurchaseDetails> for PurchaseInput {
+    type Error = Error;
+
+    fn try_from(details: FlutterPurchaseDetails) -> Result<Self> {
+        let data = details.verification_data;
+
+        match data.source.as_str() {
+            FLUTTER_SOURCE_APP_STORE => Ok(apple_input(
+                data.server_verification_data,
+                details.purchase_id.ok_or_else(|| missing("purchaseID"))?,
+            )),
+            FLUTTER_SOURCE_GOOGLE_PLAY => {
+                let purchase = GooglePurchaseJson::from(&data.local_verification_data)?;
+                Ok(Self::GooglePlay {
+                    sku_type: purchase.sku_type(),
+                    package_name: purchase.package_name,
+                    product_id: details.product_id,
+                    purchase_token: data.server_verification_data,
+                })
+            }
+            source => Err(Error::Custom(format!(
+                "unsupported in_app_purchase source: {source}"
+            ))),
+        }
+    }
+}
+
+/// A serialized `Purchase` of `react-native-iap`, on either iOS or Android.
+///
+/// See <https://react-native-iap.hyo.dev/docs/api/types#purchase>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactNativePurchase {
+    /// The product id of the purchased product
+    pub product_id: String,
+    /// The transaction id on the App Store, or the order id on Google Play
+    pub transaction_id: Option<String>,
+    /// Time of the purchase, in milliseconds since the Epoch
+    pub transaction_date: Option<f64>,
+    /// The base64 encoded receipt on iOS, the original purchase json on Android
+    pub transaction_receipt: Option<String>,
+    /// The purchase token, only set on Android
+    pub purchase_token: Option<String>,
+    /// The original purchase json, only set on Android
+    pub data_android: Option<String>,
+    /// The package name of the app, only set on Android
+    pub package_name_android: Option<String>,
+    /// Whether the subscription will automatically renew, only set on Android
+    pub auto_renewing_android: Option<bool>,
+    /// The transaction id of the original purchase, only set on iOS
+    #[serde(rename = "originalTransactionIdentifierIOS")]
+    pub original_transaction_identifier_ios: Option<String>,
+}
+
+impl ReactNativePurchase {
+    /// Deserialize the `ReactNativePurchase` sent by the client
+    /// # Errors
+    /// Will return an error if the json cannot be deserialized
+    pub fn from(json_str: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json_str)?)
+    }
+
+    /// Returns true if the purchase was made on Android
+    #[must_use]
+    pub const fn is_android(&self) -> bool {
+        self.data_android.is_some() || self.package_name_android.is_some()
+    }
+}
+
+impl TryFrom<ReactNativePurchase> for PurchaseInput {
+    type Error = Error;
+
+    fn try_from(purchase: ReactNativePurchase) -> Result<Self> {
+        if purchase.is_android() {
+            let json = purchase
+                .data_android
+                .or(purchase.transaction_receipt)
+                .ok_or_else(|| missing("dataAndroid"))?;
+            let data = GooglePurchaseJson::from(&json)?;
+            let sku_type = if purchase.auto_renewing_android == Some(true) {
+                SkuType::Subs
+            } else {
+                data.sku_type()
+            };
+
+            Ok(Self::GooglePlay {
+                package_name: purchase.package_name_android.unwrap_or(data.package_name),
+                product_id: purchase.product_id,
+                purchase_token: purchase
+                    .purchase_token
+                    .or(data.purchase_token)
+                    .ok_or_else(|| missing("purchaseToken"))?,
+                sku_type,
+            })
+        } else {
+            Ok(apple_input(
+                purchase
+                    .transaction_receipt
+                    .ok_or_else(|| missing("transactionReceipt"))?,
+                purchase
+                    .transaction_id
+                    .ok_or_else(|| missing("transactionId"))?,
+            ))
+        }
+    }
+}
+
+/// The original purchase json of the Play Billing Library, see <https://developer.android.com/reference/com/android/billingclient/api/Purchase#getOriginalJson()>
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GooglePurchaseJson {
+    package_name: String,
+    purchase_token: Option<String>,
+    /// Only set for subscriptions
+    auto_renewing: Option<bool>,
+}
+
+impl GooglePurchaseJson {
+    fn from(json_str: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json_str)?)
+    }
+
+    /// The original purchase json has no product type, but only subscriptions have the `autoRenewing` field.
+    const fn sku_type(&self) -> SkuType {
+        if self.auto_renewing.is_some() {
+            SkuType::Subs
+        } else {
+            SkuType::Inapp
+        }
+    }
+}
+
+/// App Store clients send either a base64 encoded receipt or, with StoreKit 2, a signed transaction (JWS).
+fn apple_input(receipt: String, transaction_id: String) -> PurchaseInput {
+    if receipt.split('.').count() == 3 {
+        PurchaseInput::AppleSignedTransaction(receipt)
+    } else {
+        PurchaseInput::AppleReceipt {
+            receipt_data: receipt,
+            transaction_id,
+        }
+    }
+}
+
+fn missing(field: &str) -> Error {
+    Error::Custom(format!("missing {field} in the purchase"))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn read(path: &str) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_flutter() {
+        let ios = FlutterPurchaseDetails::from(&read("res/test_flutter_ios.json")).unwrap();
+        assert!(matches!(
+            PurchaseInput::try_from(ios).unwrap(),
+            PurchaseInput::AppleReceipt { transaction_id, .. } if transaction_id == "1000000812345678"
+        ));
+
+        let android = FlutterPurchaseDetails::from(&read("res/test_flutter_android.json")).unwrap();
+        assert!(matches!(
+            PurchaseInput::try_from(android).unwrap(),
+            PurchaseInput::GooglePlay { package_name, product_id, sku_type: SkuType::Subs, .. }
+                if package_name == "com.example.app" && product_id == "monthly"
+        ));
+    }
+
+    #[test]
+    fn test_react_native() {
+        let ios = ReactNativePurchase::from(&read("res/test_react_native_ios.json")).unwrap();
+        assert!(!ios.is_android());
+        assert!(matches!(
+            PurchaseInput::try_from(ios).unwrap(),
+            PurchaseInput::AppleReceipt { transaction_id, .. } if transaction_id == "1000000812345678"
+        ));
+
+        let android =
+            ReactNativePurchase::from(&read("res/test_react_native_android.json")).unwrap();
+        assert!(matches!(
+            PurchaseInput::try_from(android).unwrap(),
+            PurchaseInput::GooglePlay { purchase_token, sku_type: SkuType::Inapp, .. }
+                if purchase_token == "ofkmnlkcaffpnjobfbmfdmjd.AO-J1Oz"
+        ));
+    }
+}
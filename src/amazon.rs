@@ -0,0 +1,321 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::{Error, Result},
+    redact::redact,
+    PurchaseResponse, UnityPurchaseReceipt,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{body, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+const AMAZON_PROD_VERIFY_RECEIPT: &str = "https://appstore-sdk.amazon.com";
+const AMAZON_TEST_VERIFY_RECEIPT: &str = "https://appstore-sdk.amazon.com/sandbox";
+const AMAZON_PRODUCT_TYPE_SUBSCRIPTION: &str = "SUBSCRIPTION";
+
+/// Convenience struct for storing the base URLs of the Amazon Receipt Verification Service (RVS).
+///
+/// Receipts of purchases made with the App Tester are verified against the sandbox, which is only used if it is
+/// enabled with `UnityPurchaseValidator::set_amazon_sandbox`.
+/// See: <https://developer.amazon.com/docs/in-app-purchasing/iap-rvs-for-android-apps.html>
+pub struct AmazonUrls<'a> {
+    /// By default, <https://appstore-sdk.amazon.com>
+    pub production: &'a str,
+    /// By default, <https://appstore-sdk.amazon.com/sandbox>
+    pub sandbox: &'a str,
+}
+
+impl Default for AmazonUrls<'_> {
+    fn default() -> Self {
+        AmazonUrls {
+            production: AMAZON_PROD_VERIFY_RECEIPT,
+            sandbox: AMAZON_TEST_VERIFY_RECEIPT,
+        }
+    }
+}
+
+/// The payload of a Unity IAP receipt for purchases made through the Amazon Appstore.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct AmazonReceiptData {
+    /// Unique identifier of the purchase
+    #[serde(rename = "receiptId")]
+    pub receipt_id: String,
+    /// Amazon user id of the customer
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    /// True if the purchase was made with the App Tester. Reported by the client, so it does not decide which RVS
+    /// endpoint the receipt is verified against.
+    #[serde(rename = "isSandbox", default)]
+    pub is_sandbox: bool,
+}
+
+impl AmazonReceiptData {
+    /// Construct the `AmazonReceiptData` from the `UnityPurchaseReceipt` payload
+    /// # Errors
+    /// Will return an error if the payload cannot be deserialized
+    pub fn from(payload: &str) -> Result<Self> {
+        Ok(serde_json::from_str(payload)?)
+    }
+}
+
+/// See <https://developer.amazon.com/docs/in-app-purchasing/iap-rvs-for-android-apps.html#rvs-response-syntax> for more details on each field.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AmazonResponse {
+    /// Unique identifier of the purchase
+    pub receipt_id: Option<String>,
+    /// The SKU of the purchased item
+    pub product_id: Option<String>,
+    /// Either `CONSUMABLE`, `ENTITLED` or `SUBSCRIPTION`
+    pub product_type: Option<String>,
+    /// Time of the purchase, in milliseconds since the Epoch
+    pub purchase_date: Option<i64>,
+    /// Time at which the subscription will renew, in milliseconds since the Epoch. Only set for subscriptions.
+    pub renewal_date: Option<i64>,
+    /// Time at which the purchase was cancelled, or the subscription has ended, in milliseconds since the Epoch.
+    /// Not set if the purchase has not been cancelled.
+    pub cancel_date: Option<i64>,
+    /// Reason for the cancellation: 0 - cancelled by the customer, 1 - cancelled by the system, 2 - not set
+    pub cancel_reason: Option<i64>,
+    /// True if the purchase was made with the App Tester or by a license tester
+    pub test_transaction: Option<bool>,
+    /// The quantity purchased, only set for consumables
+    pub quantity: Option<i64>,
+    /// Whether the subscription will automatically renew
+    pub auto_renewing: Option<bool>,
+}
+
+impl AmazonResponse {
+    /// Returns true if the response is for a subscription purchase
+    #[must_use]
+    pub fn is_subscription(&self) -> bool {
+        self.product_type.as_deref() == Some(AMAZON_PRODUCT_TYPE_SUBSCRIPTION)
+    }
+}
+
+/// Retrieves the response body from the Amazon Receipt Verification Service
+/// # Errors
+/// Will return an error if the `payload` in the `UnityPurchaseReceipt` is malformed, or if the RVS endpoint responds with an error.
+pub async fn fetch_amazon_receipt_data(
+    receipt: &UnityPurchaseReceipt,
+    secret: &str,
+) -> Result<AmazonResponse> {
+    fetch_amazon_receipt_data_with_urls(
+        receipt,
+        &AmazonUrls::default(),
+        Some(&secret.to_string()),
+        false,
+    )
+    .await
+}
+
+/// Response call with `AmazonUrls` parameter for tests. The receipt is verified against the sandbox if `sandbox`
+/// is true, which accepts App Tester receipts, so only enable it for development builds.
+/// # Errors
+/// Will return an error if no amazon shared secret is set in `secret`, if the `payload` in the `UnityPurchaseReceipt` is malformed,
+/// or if the RVS endpoint responds with an error. An `AmazonApiError` is returned for non success http statuses.
+pub async fn fetch_amazon_receipt_data_with_urls(
+    receipt: &UnityPurchaseReceipt,
+    amazon_urls: &AmazonUrls<'_>,
+    secret: Option<&String>,
+    sandbox: bool,
+) -> Result<AmazonResponse> {
+    let secret =
+        secret.ok_or_else(|| Error::NotConfigured("no amazon shared secret has been set"))?;
+    let data = AmazonReceiptData::from(&receipt.payload)?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+
+    let base_url = if sandbox {
+        amazon_urls.sandbox
+    } else {
+        amazon_urls.production
+    };
+
+    let req = Request::builder()
+        .method("GET")
+        .uri(format!(
+            "{}/version/1.0/verifyReceiptId/developer/{}/user/{}/receiptId/{}",
+            base_url,
+            utf8_percent_encode(secret, NON_ALPHANUMERIC),
+            utf8_percent_encode(&data.user_id, NON_ALPHANUMERIC),
+            utf8_percent_encode(&data.receipt_id, NON_ALPHANUMERIC),
+        ))
+        .body(Body::empty())?;
+
+    let resp = client.request(req).await?;
+    let status = resp.status();
+    let buf = body::to_bytes(resp).await?;
+
+    tracing::debug!(
+        status = %status,
+        response_body = %redact(&String::from_utf8_lossy(&buf).replace('\n', "")),
+        "amazon response"
+    );
+
+    if !status.is_success() {
+        return Err(Error::AmazonApiError {
+            status,
+            body: String::from_utf8_lossy(&buf).into_owned(),
+        });
+    }
+
+    let response = serde_json::from_slice::<AmazonResponse>(&buf)?;
+
+    tracing::info!(target = "amazon_response",
+        product_id = ?response.product_id,
+        product_type = ?response.product_type,
+        cancel_date = ?response.cancel_date,
+        renewal_date = ?response.renewal_date,
+        test_transaction = ?response.test_transaction,
+    );
+
+    Ok(response)
+}
+
+/// Validates an Amazon purchase. Subscriptions are valid until their `cancel_date` has passed,
+/// all other purchases are valid unless they have been cancelled.
+#[must_use]
+pub fn validate_amazon_purchase(response: &AmazonResponse, now: DateTime<Utc>) -> PurchaseResponse {
+    let to_date_time = |millis: i64| Utc.timestamp_millis_opt(millis).single();
+
+    let (valid, expiry_time) = if response.is_subscription() {
+        (
+            response
+                .cancel_date
+                .map_or(true, |cancel_date| cancel_date > now.timestamp_millis()),
+            response
+                .cancel_date
+                .or(response.renewal_date)
+                .and_then(to_date_time),
+        )
+    } else {
+        (response.cancel_date.is_none(), None)
+    };
+
+    tracing::info!(
+        valid,
+        now = %now,
+        receipt_id = ?response.receipt_id.as_deref().map(redact),
+        cancel_date = ?response.cancel_date,
+        "amazon receipt verification"
+    );
+
+    PurchaseResponse {
+        valid,
+        product_id: response.product_id.clone(),
+        original_transaction_id: response.receipt_id.clone(),
+        expiry_time,
+        ..PurchaseResponse::default()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{InvalidReason, Platform, PurchaseVerdict, UnityPurchaseValidator, Validator};
+    use mockito::mock;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_amazon() {
+        let now = Utc::now();
+        let amazon_response = AmazonResponse {
+            receipt_id: Some("receipt".to_string()),
+            product_id: Some("prod".to_string()),
+            product_type: Some("SUBSCRIPTION".to_string()),
+            renewal_date: Some((now + chrono::Duration::days(1)).timestamp_millis()),
+            test_transaction: Some(true),
+            ..AmazonResponse::default()
+        };
+
+        let _m = mock(
+            "GET",
+            "/sb/version/1.0/verifyReceiptId/developer/secret/user/user/receiptId/receipt%3D",
+        )
+        .with_status(200)
+        .with_body(serde_json::to_string(&amazon_response).unwrap())
+        .create();
+
+        let url = &mockito::server_url();
+        let sandbox = format!("{url}/sb");
+        let validator = UnityPurchaseValidator {
+            amazon_urls: AmazonUrls {
+                production: url,
+                sandbox: &sandbox,
+            },
+            ..UnityPurchaseValidator::default()
+        }
+        .set_amazon_secret("secret".to_string())
+        .set_amazon_sandbox(true);
+
+        let receipt = UnityPurchaseReceipt::from(
+            r#"{"Store": "AmazonApps", "TransactionID": "receipt=", "Payload": "{\"receiptId\": \"receipt=\", \"userId\": \"user\", \"isSandbox\": true}"}"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.store, Platform::AmazonAppStore);
+
+        let response = validator.validate(now, &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("prod".to_string()));
+
+        // the client cannot opt into the sandbox
+        let production = mock(
+            "GET",
+            "/version/1.0/verifyReceiptId/developer/secret/user/user/receiptId/receipt%3D",
+        )
+        .with_status(400)
+        .expect(1)
+        .create();
+
+        let validator = validator.set_amazon_sandbox(false);
+        assert!(!validator.verdict(now, &receipt).await.is_valid());
+        production.assert();
+
+        let cancelled = AmazonResponse {
+            cancel_date: Some((now - chrono::Duration::days(1)).timestamp_millis()),
+            ..amazon_response
+        };
+        assert!(!validate_amazon_purchase(&cancelled, now).valid);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_amazon_invalid_receipt() {
+        let _m = mock(
+            "GET",
+            "/version/1.0/verifyReceiptId/developer/secret/user/user/receiptId/receipt",
+        )
+        .with_status(400)
+        .create();
+
+        let url = &mockito::server_url();
+        let validator = UnityPurchaseValidator {
+            amazon_urls: AmazonUrls {
+                production: url,
+                sandbox: url,
+            },
+            ..UnityPurchaseValidator::default()
+        }
+        .set_amazon_secret("secret".to_string());
+
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::AmazonAppStore,
+            payload: r#"{"receiptId": "receipt", "userId": "user"}"#.to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        assert!(matches!(
+            validator.verdict(Utc::now(), &receipt).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::PurchaseTokenInvalid,
+                ..
+            }
+        ));
+    }
+}
@@ -1,13 +1,14 @@
 #![allow(clippy::module_name_repetitions)]
 
 use super::{
-    error::{Error::IoError, Result},
-    PurchaseResponse, UnityPurchaseReceipt,
+    error::{is_retryable_apple_status, Error, Error::IoError, Result},
+    redact::redact,
+    HttpsClient, Product, ProductCatalog, ProductType, PurchaseResponse, SharedClients,
+    UnityPurchaseReceipt,
 };
 use async_recursion::async_recursion;
-use chrono::{DateTime, Utc};
-use hyper::{body, Body, Client, Request};
-use hyper_tls::HttpsConnector;
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{body, Body, Request};
 use serde::{Deserialize, Serialize};
 
 /// <https://developer.apple.com/documentation/appstorereceipts/status>
@@ -17,8 +18,9 @@ const APPLE_STATUS_VALID: i32 = 0;
 const APPLE_PROD_VERIFY_RECEIPT: &str = "https://buy.itunes.apple.com";
 const APPLE_TEST_VERIFY_RECEIPT: &str = "https://sandbox.itunes.apple.com";
 
-/// Convenience struct for storing our production and sandbox URLs. Best practice is to attempt to verify
-/// against production, and if that fails, to then request verification from the sandbox.
+/// Convenience struct for storing our production and sandbox URLs.
+///
+/// Best practice is to attempt to verify against production, and if that fails, to then request verification from the sandbox.
 /// See: <https://developer.apple.com/documentation/appstorereceipts/verifyreceipt>
 pub struct AppleUrls<'a> {
     /// By default, <https://buy.itunes.apple.com>
@@ -58,7 +60,10 @@ pub struct AppleLatestReceipt {
     pub original_purchase_date: Option<String>,
     pub product_id: Option<String>,
     pub purchase_date: Option<String>,
+    /// The time of the purchase, in milliseconds since the Epoch.
+    pub purchase_date_ms: Option<String>,
     pub transaction_id: Option<String>,
+    pub original_transaction_id: Option<String>,
 }
 
 /// See <https://developer.apple.com/documentation/appstorereceipts/responsebody> for more details on each field
@@ -79,6 +84,28 @@ pub struct AppleResponse {
     pub latest_receipt_info: Option<Vec<AppleLatestReceipt>>,
     /// A JSON representation of the receipt that was sent for verification
     pub receipt: Option<AppleReceipt>,
+    /// The pending renewal information of each auto-renewable subscription. Only returned for receipts that contain
+    /// auto-renewable subscriptions.
+    pub pending_renewal_info: Option<Vec<ApplePendingRenewal>>,
+}
+
+/// See <https://developer.apple.com/documentation/appstorereceipts/responsebody/pending_renewal_info> for more details on each field.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct ApplePendingRenewal {
+    /// The product identifier of the product that renews at the next billing period
+    pub auto_renew_product_id: Option<String>,
+    /// `1` if the subscription will renew at the end of the current period, `0` if the customer turned off automatic renewal
+    pub auto_renew_status: Option<String>,
+    /// The reason a subscription expired
+    pub expiration_intent: Option<String>,
+    /// The time at which the grace period for subscription renewals expires, in milliseconds since the Epoch
+    pub grace_period_expires_date_ms: Option<String>,
+    /// `1` if the App Store is attempting to renew an expired subscription after a billing issue
+    pub is_in_billing_retry_period: Option<String>,
+    /// The transaction identifier of the original purchase
+    pub original_transaction_id: Option<String>,
+    /// The product identifier of the subscription
+    pub product_id: Option<String>,
 }
 
 impl AppleResponse {
@@ -88,8 +115,7 @@ impl AppleResponse {
         transaction_id.is_empty()
             || self
                 .get_receipt(transaction_id)
-                .filter(AppleInAppReceipt::is_subscription)
-                .is_some()
+                .is_some_and(|receipt| receipt.is_subscription())
     }
 
     #[must_use]
@@ -116,6 +142,41 @@ impl AppleResponse {
             .and_then(AppleReceipt::get_latest_receipt)
             .cloned()
     }
+    #[must_use]
+    /// Get all transactions of `receipt.in_app` and `latest_receipt_info`, sorted by purchase date. Transactions
+    /// listed in both are taken from `latest_receipt_info`, which also contains renewals and refunds.
+    pub fn get_transactions(&self) -> Vec<AppleInAppReceipt> {
+        let mut transactions = self
+            .latest_receipt_info
+            .iter()
+            .flatten()
+            .cloned()
+            .map(AppleInAppReceipt::from)
+            .collect::<Vec<_>>();
+
+        for in_app in self
+            .receipt
+            .as_ref()
+            .and_then(|receipt| receipt.in_app.as_ref())
+            .into_iter()
+            .flatten()
+        {
+            if !transactions
+                .iter()
+                .any(|transaction| transaction.transaction_id == in_app.transaction_id)
+            {
+                transactions.push(in_app.clone());
+            }
+        }
+
+        transactions.sort_by_key(|transaction| {
+            transaction
+                .purchase_date_ms
+                .as_deref()
+                .and_then(|purchase_date| purchase_date.parse::<i64>().ok())
+        });
+        transactions
+    }
 }
 
 /// See <https://developer.apple.com/documentation/appstorereceipts/responsebody/receipt> for more details on each field
@@ -160,13 +221,22 @@ impl AppleReceipt {
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct AppleInAppReceipt {
     /// The unique identifier of the product purchased. You provide this value when creating the product
-    /// in App Store Connect, and it corresponds to the productIdentifier property of the SKPayment object stored in the
+    /// in App Store Connect, and it corresponds to the productIdentifier property of the `SKPayment` object stored in the
     /// transaction's payment property.
     pub product_id: Option<String>,
     /// A unique identifier for a transaction such as a purchase, restore, or renewal.
     pub transaction_id: Option<String>,
+    /// The transaction identifier of the original purchase, which stays the same across restores and renewals.
+    pub original_transaction_id: Option<String>,
     pub expires_date_ms: Option<String>,
     pub expires_date: Option<String>,
+    /// The time of the purchase, in milliseconds since the Epoch.
+    pub purchase_date_ms: Option<String>,
+    /// The number of consumable products purchased.
+    pub quantity: Option<String>,
+    /// The time Apple customer support refunded the transaction, or the time the subscription was upgraded,
+    /// in milliseconds since the Epoch.
+    pub cancellation_date_ms: Option<String>,
 }
 
 impl AppleInAppReceipt {
@@ -175,6 +245,21 @@ impl AppleInAppReceipt {
     }
 }
 
+impl From<AppleLatestReceipt> for AppleInAppReceipt {
+    fn from(latest: AppleLatestReceipt) -> Self {
+        Self {
+            product_id: latest.product_id,
+            transaction_id: latest.transaction_id,
+            original_transaction_id: latest.original_transaction_id,
+            expires_date_ms: latest.expires_date_ms,
+            expires_date: latest.expires_date,
+            purchase_date_ms: latest.purchase_date_ms,
+            quantity: latest.quantity,
+            cancellation_date_ms: latest.cancellation_date_ms,
+        }
+    }
+}
+
 /// Retrieves the responseBody data from Apple
 /// # Errors
 /// Will return an error if no apple secret is set in `password` or
@@ -191,14 +276,29 @@ pub async fn fetch_apple_receipt_data(
 /// # Errors
 /// Will return an error if no apple secret is set in `password` or
 /// if there is there is valid response from the `apple_urls` endpoints.
+/// An `AppleHttpError` is returned for non success http statuses, and an `AppleStatusError` if the response `status`
+/// indicates a temporary issue on Apple's side.
 pub async fn fetch_apple_receipt_data_with_urls(
     receipt: &UnityPurchaseReceipt,
     apple_urls: &AppleUrls<'_>,
     password: Option<&String>,
 ) -> Result<AppleResponse> {
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    fetch_apple_receipt_data_with_client(
+        SharedClients::default().client(),
+        receipt,
+        apple_urls,
+        password,
+    )
+    .await
+}
 
+/// Same as `fetch_apple_receipt_data_with_urls`, with an existing http client.
+pub async fn fetch_apple_receipt_data_with_client(
+    client: &HttpsClient,
+    receipt: &UnityPurchaseReceipt,
+    apple_urls: &AppleUrls<'_>,
+    password: Option<&String>,
+) -> Result<AppleResponse> {
     let password = password.cloned().ok_or_else(|| {
         IoError(std::io::Error::new(
             std::io::ErrorKind::NotFound,
@@ -210,7 +310,7 @@ pub async fn fetch_apple_receipt_data_with_urls(
         password,
     })?;
     fetch_apple_response(
-        &client,
+        client,
         &request_body,
         apple_urls,
         &receipt.transaction_id,
@@ -226,40 +326,38 @@ pub fn validate_apple_subscription(
     transaction_id: &str,
     now: DateTime<Utc>,
 ) -> PurchaseResponse {
-    let (valid, product_id) = if transaction_id.is_empty() {
+    if transaction_id.is_empty() {
         validate_expiration(now, response.get_latest_receipt())
     } else {
-        let mut result = validate_expiration(now, response.get_receipt(transaction_id));
+        let result = validate_expiration(now, response.get_receipt(transaction_id));
 
-        let (valid, _) = result;
-        if !valid {
+        if result.valid {
+            result
+        } else {
             tracing::warn!(
-                "Received an expired transaction_id: {}, attempting to find latest receipt",
-                transaction_id
+                transaction_id = %redact(transaction_id),
+                "Received an expired transaction_id, attempting to find latest receipt"
             );
-            result = validate_expiration(now, response.get_latest_receipt());
+            validate_expiration(now, response.get_latest_receipt())
         }
-
-        result
-    };
-
-    PurchaseResponse { valid, product_id }
+    }
 }
 
 fn validate_expiration(
     now: DateTime<Utc>,
     in_app_receipt: Option<AppleInAppReceipt>,
-) -> (bool, Option<String>) {
+) -> PurchaseResponse {
     in_app_receipt
         .and_then(|receipt| {
             receipt.expires_date_ms.as_ref().and_then(|expiry| {
                 expiry
                     .parse::<i64>()
-                    .map(|expiry_time| {
-                        (
-                            expiry_time > now.timestamp_millis(),
-                            receipt.product_id.clone(),
-                        )
+                    .map(|expiry_time| PurchaseResponse {
+                        valid: expiry_time > now.timestamp_millis(),
+                        product_id: receipt.product_id.clone(),
+                        original_transaction_id: receipt.original_transaction_id.clone(),
+                        expiry_time: Utc.timestamp_millis_opt(expiry_time).single(),
+                        ..PurchaseResponse::default()
                     })
                     .ok()
             })
@@ -267,21 +365,146 @@ fn validate_expiration(
         .unwrap_or_default()
 }
 
+/// Validates a single transaction of the receipt, as returned by `AppleResponse::get_transactions`.
+///
+/// Unlike `validate_apple_subscription`, an expired transaction is invalid even if the subscription was renewed since.
+#[allow(clippy::must_use_candidate)]
+pub fn validate_apple_in_app(
+    response: &AppleResponse,
+    transaction: &AppleInAppReceipt,
+    now: DateTime<Utc>,
+) -> PurchaseResponse {
+    let expiry_time = transaction
+        .expires_date_ms
+        .as_deref()
+        .and_then(|expiry| expiry.parse::<i64>().ok())
+        .and_then(|expiry| Utc.timestamp_millis_opt(expiry).single());
+
+    let valid = response.status == APPLE_STATUS_VALID
+        && transaction.product_id.is_some()
+        && transaction.cancellation_date_ms.is_none()
+        && (!transaction.is_subscription() || expiry_time.is_some_and(|expiry| expiry > now));
+
+    PurchaseResponse {
+        valid,
+        product_id: transaction.product_id.clone(),
+        original_transaction_id: transaction.original_transaction_id.clone(),
+        expiry_time,
+        ..PurchaseResponse::default()
+    }
+}
+
+/// Validates a non-renewing subscription, which Apple returns without expiry.
+///
+/// The expiry is computed from the purchase date and the `duration` of the product in the `catalog`. Consecutive purchases of the same product,
+/// or of products in the same `group`, are stacked: each one extends the period from the end of the previous one.
+/// Refunded purchases are not stacked, and a refunded `transaction_id` is never valid.
+/// # Errors
+/// Will return an error if the product is not a non-renewing subscription with a `duration` in the `catalog`
+pub fn validate_apple_non_renewing(
+    response: &AppleResponse,
+    transaction_id: &str,
+    catalog: &ProductCatalog,
+    now: DateTime<Utc>,
+) -> Result<PurchaseResponse> {
+    let receipt = response.get_receipt(transaction_id);
+    let product_id = receipt
+        .as_ref()
+        .and_then(|receipt| receipt.product_id.clone());
+
+    let Some(product) = product_id
+        .as_deref()
+        .and_then(|product_id| catalog.product(product_id))
+        .filter(|product| product.product_type == ProductType::NonRenewing)
+    else {
+        return Err(Error::Custom(format!(
+            "not a non-renewing subscription in the product catalog: {product_id:?}"
+        )));
+    };
+
+    let stacked = |other: &Product| {
+        other.product_type == ProductType::NonRenewing
+            && product.group.as_ref().map_or_else(
+                || other.id == product.id,
+                |group| other.group.as_ref() == Some(group),
+            )
+    };
+
+    let mut purchases = response
+        .receipt
+        .as_ref()
+        .and_then(|receipt| receipt.in_app.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|in_app| in_app.cancellation_date_ms.is_none())
+        .filter_map(|in_app| {
+            let other = catalog.product(in_app.product_id.as_deref()?)?;
+            let purchase_date = in_app
+                .purchase_date_ms
+                .as_deref()?
+                .parse::<i64>()
+                .ok()
+                .and_then(|millis| Utc.timestamp_millis_opt(millis).single())?;
+            stacked(other).then_some((purchase_date, other))
+        })
+        .collect::<Vec<_>>();
+    purchases.sort_by_key(|(purchase_date, _)| *purchase_date);
+
+    let mut expiry_time: Option<DateTime<Utc>> = None;
+    for (purchase_date, other) in purchases {
+        let duration = other.duration.ok_or_else(|| {
+            Error::Custom(format!(
+                "no duration for the non-renewing subscription: {}",
+                other.id
+            ))
+        })?;
+        let start = expiry_time.map_or(purchase_date, |end| end.max(purchase_date));
+        expiry_time = duration.add_to(start);
+    }
+
+    let valid = response.status == APPLE_STATUS_VALID
+        && receipt
+            .as_ref()
+            .is_some_and(|receipt| receipt.cancellation_date_ms.is_none())
+        && expiry_time.is_some_and(|end| end > now);
+
+    tracing::info!(
+        "apple non-renewing subscription verification, valid: {}, now: {}, product_id: {:?}, expiry_time: {:?}",
+        valid,
+        now,
+        product_id,
+        expiry_time,
+    );
+
+    Ok(PurchaseResponse {
+        valid,
+        product_id,
+        original_transaction_id: receipt.and_then(|receipt| receipt.original_transaction_id),
+        expiry_time,
+        ..PurchaseResponse::default()
+    })
+}
+
 /// Validates that a package status is valid
 #[allow(clippy::must_use_candidate)]
 pub fn validate_apple_package(response: &AppleResponse, transaction_id: &str) -> PurchaseResponse {
-    let product_id = response.get_product_id(transaction_id);
+    let receipt = response.get_receipt(transaction_id);
+    let product_id = receipt
+        .as_ref()
+        .and_then(|receipt| receipt.product_id.clone());
     let valid = response.status == APPLE_STATUS_VALID && product_id.is_some();
 
     PurchaseResponse {
         valid,
-        product_id: response.get_product_id(transaction_id),
+        product_id,
+        original_transaction_id: receipt.and_then(|receipt| receipt.original_transaction_id),
+        ..PurchaseResponse::default()
     }
 }
 
 #[async_recursion]
 async fn fetch_apple_response(
-    client: &Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    client: &HttpsClient,
     request_body: &str,
     apple_urls: &AppleUrls,
     transaction_id: &str,
@@ -300,13 +523,22 @@ async fn fetch_apple_response(
         .body(Body::from(request_body.to_owned()))?;
 
     let resp = client.request(req).await?;
+    let status = resp.status();
     let buf = body::to_bytes(resp).await?;
 
     tracing::debug!(
-        "apple response: {}",
-        String::from_utf8_lossy(&buf).replace('\n', "")
+        status = %status,
+        response_body = %redact(&String::from_utf8_lossy(&buf).replace('\n', "")),
+        "apple response"
     );
 
+    if !status.is_success() {
+        return Err(Error::AppleHttpError {
+            status,
+            body: String::from_utf8_lossy(&buf).into_owned(),
+        });
+    }
+
     let response = serde_json::from_slice::<AppleResponse>(&buf)?;
 
     let latest_expires_date = response
@@ -322,6 +554,11 @@ async fn fetch_apple_response(
 
     if response.status == APPLE_STATUS_CODE_TEST {
         fetch_apple_response(client, request_body, apple_urls, transaction_id, false).await
+    } else if is_retryable_apple_status(response.status) {
+        Err(Error::AppleStatusError {
+            status: response.status,
+            is_retryable: response.is_retryable,
+        })
     } else {
         Ok(response)
     }
@@ -0,0 +1,384 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::Result, redact::redact, InvalidReason, PurchaseInput, PurchaseResponse, PurchaseVerdict,
+    UnityPurchaseReceipt, Validator,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::Mutex};
+
+const DEFAULT_TTL_MINUTES: i64 = 10;
+const DEFAULT_NEGATIVE_TTL_SECONDS: i64 = 30;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    receipt_hash: String,
+    transaction_id: String,
+}
+
+impl CacheKey {
+    fn new(receipt: &UnityPurchaseReceipt) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{:?}", receipt.store));
+        hasher.update(&receipt.payload);
+
+        Self {
+            receipt_hash: format!("{:x}", hasher.finalize()),
+            transaction_id: receipt.transaction_id.clone(),
+        }
+    }
+}
+
+struct CacheEntry {
+    response: PurchaseResponse,
+    /// `None` if the cached verdict is valid
+    invalid_reason: Option<InvalidReason>,
+    expires_at: DateTime<Utc>,
+}
+
+impl CacheEntry {
+    fn verdict(&self) -> PurchaseVerdict {
+        self.invalid_reason.as_ref().map_or_else(
+            || PurchaseVerdict::Valid(self.response.clone()),
+            |reason| PurchaseVerdict::Invalid {
+                reason: reason.clone(),
+                product_id: self.response.product_id.clone(),
+            },
+        )
+    }
+}
+
+/// Caching layer around any `Validator`. Results are keyed by the hash of the receipt and its transaction id.
+///
+/// Valid results are cached until the subscription expires or the `ttl` has passed, whichever comes first.
+/// Invalid results are cached for `negative_ttl`. Validations which could not be completed, including `Stale`
+/// verdicts, are never cached.
+/// ```
+/// use iap::{CachedValidator, UnityPurchaseValidator};
+///
+/// let validator = CachedValidator::new(UnityPurchaseValidator::default())
+///     .set_ttl(chrono::Duration::minutes(5))
+///     .set_negative_ttl(chrono::Duration::seconds(10));
+/// ```
+pub struct CachedValidator<V> {
+    validator: V,
+    ttl: Duration,
+    negative_ttl: Duration,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+impl<V: Validator> CachedValidator<V> {
+    /// Wraps `validator` with the default ttl of 10 minutes and negative ttl of 30 seconds.
+    pub fn new(validator: V) -> Self {
+        Self {
+            validator,
+            ttl: Duration::minutes(DEFAULT_TTL_MINUTES),
+            negative_ttl: Duration::seconds(DEFAULT_NEGATIVE_TTL_SECONDS),
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Sets the maximum time a valid result is cached.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_ttl(self, ttl: Duration) -> Self {
+        let mut new = self;
+        new.ttl = ttl;
+        new
+    }
+
+    /// Sets the time an invalid result is cached.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_negative_ttl(self, negative_ttl: Duration) -> Self {
+        let mut new = self;
+        new.negative_ttl = negative_ttl;
+        new
+    }
+
+    /// The wrapped validator
+    pub const fn inner(&self) -> &V {
+        &self.validator
+    }
+
+    /// Drops the cached result of `receipt`.
+    pub fn evict(&self, receipt: &UnityPurchaseReceipt) {
+        self.entries().remove(&CacheKey::new(receipt));
+    }
+
+    /// Drops every cached result for the transaction, matching either the transaction id of the receipt or the
+    /// `original_transaction_id` of the response. Use this when a server notification reports a state change.
+    pub fn evict_transaction(&self, transaction_id: &str) {
+        self.entries().retain(|key, entry| {
+            key.transaction_id != transaction_id
+                && entry.response.original_transaction_id.as_deref() != Some(transaction_id)
+        });
+    }
+
+    /// Drops all cached results.
+    pub fn clear(&self) {
+        self.entries().clear();
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<CacheKey, CacheEntry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// The cached verdict of `key`, if it has not expired
+    fn cached(&self, now: DateTime<Utc>, key: &CacheKey) -> Option<PurchaseVerdict> {
+        let verdict = self
+            .entries()
+            .get(key)
+            .filter(|entry| entry.expires_at > now)
+            .map(CacheEntry::verdict)?;
+
+        tracing::debug!(
+            transaction_id = %redact(&key.transaction_id),
+            valid = verdict.is_valid(),
+            "validation cache hit"
+        );
+        Some(verdict)
+    }
+
+    fn insert(&self, now: DateTime<Utc>, key: CacheKey, verdict: &PurchaseVerdict) {
+        let entry = match verdict {
+            PurchaseVerdict::Valid(response) => CacheEntry {
+                response: response.clone(),
+                invalid_reason: None,
+                expires_at: response
+                    .expiry_time
+                    .map_or(now + self.ttl, |expiry| expiry.min(now + self.ttl)),
+            },
+            PurchaseVerdict::Invalid { reason, product_id } => CacheEntry {
+                response: PurchaseResponse {
+                    product_id: product_id.clone(),
+                    ..PurchaseResponse::default()
+                },
+                invalid_reason: Some(reason.clone()),
+                expires_at: now + self.negative_ttl,
+            },
+            PurchaseVerdict::Indeterminate(_) | PurchaseVerdict::Stale { .. } => return,
+        };
+
+        let mut entries = self.entries();
+        entries.retain(|_, entry| entry.expires_at > now);
+        entries.insert(key, entry);
+    }
+}
+
+#[async_trait]
+impl<V: Validator> Validator for CachedValidator<V> {
+    async fn validate(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> Result<PurchaseResponse> {
+        self.verdict(now, receipt).await.into_result()
+    }
+
+    async fn verdict(&self, now: DateTime<Utc>, receipt: &UnityPurchaseReceipt) -> PurchaseVerdict {
+        let key = CacheKey::new(receipt);
+
+        if let Some(verdict) = self.cached(now, &key) {
+            return verdict;
+        }
+
+        let verdict = self.validator.verdict(now, receipt).await;
+        self.insert(now, key, &verdict);
+        verdict
+    }
+
+    /// Unity IAP receipts are cached like in `verdict`, all other inputs are passed on to the wrapped validator.
+    async fn verdict_input(&self, now: DateTime<Utc>, input: &PurchaseInput) -> PurchaseVerdict {
+        match input {
+            PurchaseInput::Unity(receipt) => self.verdict(now, receipt).await,
+            _ => self.validator.verdict_input(now, input).await,
+        }
+    }
+
+    /// Cached Unity IAP receipts are served from the cache, all other inputs are validated with one `verdict_many`
+    /// call of the wrapped validator, which keeps its batching.
+    async fn verdict_many(
+        &self,
+        now: DateTime<Utc>,
+        inputs: &[PurchaseInput],
+        concurrency: usize,
+    ) -> Vec<PurchaseVerdict> {
+        let mut verdicts = inputs
+            .iter()
+            .map(|input| match input {
+                PurchaseInput::Unity(receipt) => self.cached(now, &CacheKey::new(receipt)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let misses = verdicts
+            .iter()
+            .enumerate()
+            .filter(|(_, verdict)| verdict.is_none())
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let miss_inputs = misses
+            .iter()
+            .map(|index| inputs[*index].clone())
+            .collect::<Vec<_>>();
+
+        let fresh = self
+            .validator
+            .verdict_many(now, &miss_inputs, concurrency)
+            .await;
+
+        for (index, verdict) in misses.into_iter().zip(fresh) {
+            if let PurchaseInput::Unity(receipt) = &inputs[index] {
+                self.insert(now, CacheKey::new(receipt), &verdict);
+            }
+            verdicts[index] = Some(verdict);
+        }
+
+        verdicts.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingValidator {
+        calls: AtomicUsize,
+        batches: AtomicUsize,
+        response: PurchaseResponse,
+    }
+
+    #[async_trait]
+    impl Validator for CountingValidator {
+        async fn validate(
+            &self,
+            _now: DateTime<Utc>,
+            _receipt: &UnityPurchaseReceipt,
+        ) -> Result<PurchaseResponse> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.response.clone())
+        }
+
+        async fn verdict_many(
+            &self,
+            now: DateTime<Utc>,
+            inputs: &[PurchaseInput],
+            _concurrency: usize,
+        ) -> Vec<PurchaseVerdict> {
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            let mut verdicts = Vec::new();
+            for input in inputs {
+                verdicts.push(self.verdict_input(now, input).await);
+            }
+            verdicts
+        }
+    }
+
+    fn receipt() -> UnityPurchaseReceipt {
+        UnityPurchaseReceipt {
+            payload: "payload".to_string(),
+            transaction_id: "txn".to_string(),
+            ..UnityPurchaseReceipt::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_valid_until_expiry() {
+        let now = Utc::now();
+        let validator = CachedValidator::new(CountingValidator {
+            response: PurchaseResponse {
+                valid: true,
+                expiry_time: Some(now + Duration::minutes(1)),
+                original_transaction_id: Some("original_txn".to_string()),
+                ..PurchaseResponse::default()
+            },
+            ..CountingValidator::default()
+        });
+
+        assert!(validator.validate(now, &receipt()).await.unwrap().valid);
+        assert!(validator.validate(now, &receipt()).await.unwrap().valid);
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 1);
+
+        // expired before the ttl
+        let later = now + Duration::minutes(2);
+        validator.validate(later, &receipt()).await.unwrap();
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 2);
+
+        // evicting a still live entry
+        validator.validate(now, &receipt()).await.unwrap();
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 2);
+        validator.evict_transaction("original_txn");
+        validator.validate(now, &receipt()).await.unwrap();
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cache_verdict_many() {
+        let now = Utc::now();
+        let validator = CachedValidator::new(CountingValidator {
+            response: PurchaseResponse {
+                valid: true,
+                ..PurchaseResponse::default()
+            },
+            ..CountingValidator::default()
+        });
+        assert!(validator.verdict(now, &receipt()).await.is_valid());
+
+        let other = UnityPurchaseReceipt {
+            transaction_id: "other".to_string(),
+            ..receipt()
+        };
+        let inputs = [
+            PurchaseInput::Unity(receipt()),
+            PurchaseInput::Unity(other.clone()),
+            PurchaseInput::Unity(receipt()),
+        ];
+        let verdicts = validator.verdict_many(now, &inputs, 2).await;
+        assert_eq!(verdicts.len(), 3);
+        assert!(verdicts.iter().all(PurchaseVerdict::is_valid));
+
+        // only the miss reached the wrapped validator, in a single batch, and is cached since
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 2);
+        assert_eq!(validator.inner().batches.load(Ordering::SeqCst), 1);
+        assert!(validator.verdict(now, &other).await.is_valid());
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_negative_ttl() {
+        let now = Utc::now();
+        let validator = CachedValidator::new(CountingValidator::default())
+            .set_negative_ttl(Duration::seconds(10));
+
+        assert!(!validator.validate(now, &receipt()).await.unwrap().valid);
+        assert!(
+            !validator
+                .validate(now + Duration::seconds(5), &receipt())
+                .await
+                .unwrap()
+                .valid
+        );
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 1);
+
+        validator
+            .validate(now + Duration::seconds(11), &receipt())
+            .await
+            .unwrap();
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 2);
+
+        validator.evict(&receipt());
+        validator
+            .validate(now + Duration::seconds(11), &receipt())
+            .await
+            .unwrap();
+        assert_eq!(validator.inner().calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,227 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::{Error, Result},
+    SkuType,
+};
+use chrono::{DateTime, Days, Months, Utc};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// The kind of a product, which determines how its purchases are validated and how long they grant entitlements.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProductType {
+    /// Can be purchased repeatedly and is used up, ie: coins
+    Consumable,
+    /// Purchased once and owned forever, ie: removing ads
+    NonConsumable,
+    /// Subscription renewed by the store until cancelled
+    AutoRenewable,
+    /// Subscription for a fixed `duration`, which the user has to purchase again once it ends
+    NonRenewing,
+}
+
+impl ProductType {
+    /// The `SkuType` of the product on Google Play
+    #[must_use]
+    pub const fn sku_type(self) -> SkuType {
+        match self {
+            Self::AutoRenewable => SkuType::Subs,
+            Self::Consumable | Self::NonConsumable | Self::NonRenewing => SkuType::Inapp,
+        }
+    }
+}
+
+/// A calendar duration in the ISO 8601 format, ie: `P1M` for one month or `P7D` for seven days.
+/// Only a single unit of years (`Y`), months (`M`), weeks (`W`) or days (`D`) is supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ProductDuration {
+    /// A number of days
+    Days(u32),
+    /// A number of weeks
+    Weeks(u32),
+    /// A number of months
+    Months(u32),
+    /// A number of years
+    Years(u32),
+}
+
+impl ProductDuration {
+    /// The end of the duration when starting at `start`, or `None` if it is out of range
+    #[must_use]
+    pub fn add_to(self, start: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Days(days) => start.checked_add_days(Days::new(days.into())),
+            Self::Weeks(weeks) => start.checked_add_days(Days::new(u64::from(weeks) * 7)),
+            Self::Months(months) => start.checked_add_months(Months::new(months)),
+            Self::Years(years) => start.checked_add_months(Months::new(years.checked_mul(12)?)),
+        }
+    }
+}
+
+impl TryFrom<String> for ProductDuration {
+    type Error = Error;
+
+    fn try_from(duration: String) -> Result<Self> {
+        let invalid = || Error::Custom(format!("invalid product duration: {duration}"));
+
+        let period = duration.strip_prefix('P').ok_or_else(invalid)?;
+        let (count, unit) = period.split_at(period.len().saturating_sub(1));
+        let count = count.parse::<u32>().map_err(|_| invalid())?;
+
+        match unit {
+            "D" => Ok(Self::Days(count)),
+            "W" => Ok(Self::Weeks(count)),
+            "M" => Ok(Self::Months(count)),
+            "Y" => Ok(Self::Years(count)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<ProductDuration> for String {
+    fn from(duration: ProductDuration) -> Self {
+        match duration {
+            ProductDuration::Days(count) => format!("P{count}D"),
+            ProductDuration::Weeks(count) => format!("P{count}W"),
+            ProductDuration::Months(count) => format!("P{count}M"),
+            ProductDuration::Years(count) => format!("P{count}Y"),
+        }
+    }
+}
+
+/// A product of the `ProductCatalog`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Product {
+    /// The id of the product in the catalog
+    pub id: String,
+    /// The ids of the product on each store, ie: `com.game.vip.monthly` on the App Store and `vip_monthly` on
+    /// Google Play. The catalog `id` is always matched as well.
+    #[serde(default)]
+    pub store_ids: Vec<String>,
+    /// The kind of the product
+    #[serde(rename = "type")]
+    pub product_type: ProductType,
+    /// The length of a subscription period, required for non-renewing subscriptions
+    pub duration: Option<ProductDuration>,
+    /// The subscription group of the product. A user should only have one active subscription per group
+    pub group: Option<String>,
+    /// The entitlements granted by a valid purchase of the product, ie: `vip`
+    #[serde(default)]
+    pub entitlements: Vec<String>,
+}
+
+impl Product {
+    /// Returns true if `product_id` is the catalog id or one of the store ids of the product
+    #[must_use]
+    pub fn matches(&self, product_id: &str) -> bool {
+        self.id == product_id || self.store_ids.iter().any(|id| id == product_id)
+    }
+}
+
+/// The products of an app and the entitlements they grant, loaded from TOML or JSON:
+/// ```toml
+/// [[products]]
+/// id = "vip_monthly"
+/// store_ids = ["com.game.vip.monthly"]
+/// type = "auto-renewable"
+/// duration = "P1M"
+/// group = "vip"
+/// entitlements = ["vip"]
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProductCatalog {
+    /// All products of the catalog
+    #[serde(default)]
+    pub products: Vec<Product>,
+}
+
+impl ProductCatalog {
+    /// Deserialize the `ProductCatalog` from JSON
+    /// # Errors
+    /// Will return an error if the json cannot be deserialized
+    pub fn from_json(json_str: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json_str)?)
+    }
+
+    /// Deserialize the `ProductCatalog` from TOML
+    /// # Errors
+    /// Will return an error if the toml cannot be deserialized
+    pub fn from_toml(toml_str: &str) -> Result<Self> {
+        Ok(toml::from_str(toml_str)?)
+    }
+
+    /// The product with the catalog or store id `product_id`
+    #[must_use]
+    pub fn product(&self, product_id: &str) -> Option<&Product> {
+        self.products
+            .iter()
+            .find(|product| product.matches(product_id))
+    }
+
+    /// The entitlements granted by a valid purchase of `product_id`, empty for unknown products
+    #[must_use]
+    pub fn entitlements(&self, product_id: &str) -> Vec<String> {
+        self.product(product_id)
+            .map(|product| product.entitlements.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_catalog() {
+        let toml = ProductCatalog::from_toml(
+            &std::fs::read_to_string("res/test_product_catalog.toml").unwrap(),
+        )
+        .unwrap();
+        let json = ProductCatalog::from_json(
+            &std::fs::read_to_string("res/test_product_catalog.json").unwrap(),
+        )
+        .unwrap();
+
+        for catalog in [toml, json] {
+            assert_eq!(catalog.entitlements("com.game.vip.monthly"), vec!["vip"]);
+            assert_eq!(catalog.entitlements("vip_yearly"), vec!["vip"]);
+            assert!(catalog.entitlements("unknown").is_empty());
+
+            let product = catalog.product("vip_yearly").unwrap();
+            assert_eq!(product.product_type, ProductType::AutoRenewable);
+            assert_eq!(product.product_type.sku_type(), SkuType::Subs);
+            assert_eq!(product.duration, Some(ProductDuration::Years(1)));
+
+            let coins = catalog.product("coins_100").unwrap();
+            assert_eq!(coins.product_type, ProductType::Consumable);
+            assert_eq!(coins.product_type.sku_type(), SkuType::Inapp);
+        }
+    }
+
+    #[test]
+    fn test_duration() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 31, 0, 0, 0).unwrap();
+
+        let month = ProductDuration::try_from("P1M".to_string()).unwrap();
+        assert_eq!(
+            month.add_to(start),
+            Utc.with_ymd_and_hms(2024, 2, 29, 0, 0, 0).single()
+        );
+        assert_eq!(
+            ProductDuration::try_from("P2W".to_string())
+                .unwrap()
+                .add_to(start),
+            Utc.with_ymd_and_hms(2024, 2, 14, 0, 0, 0).single()
+        );
+        assert_eq!(String::from(month), "P1M");
+
+        assert!(ProductDuration::try_from("1M".to_string()).is_err());
+        assert!(ProductDuration::try_from("P1H".to_string()).is_err());
+        assert!(ProductDuration::try_from("P".to_string()).is_err());
+    }
+}
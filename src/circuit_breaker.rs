@@ -0,0 +1,315 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::{Error, Result},
+    Platform,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The state of the circuit of a store
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Calls go through, consecutive infrastructure failures are counted
+    Closed,
+    /// Calls are short-circuited with a `CircuitOpen` error until `open_duration` has passed
+    Open,
+    /// A limited number of probe calls go through, the circuit closes if they succeed and opens again if they fail
+    HalfOpen,
+}
+
+/// The thresholds of a `CircuitBreaker`
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive infrastructure failures after which the circuit opens
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before probing the store again
+    pub open_duration: Duration,
+    /// Number of concurrent probe calls let through while the circuit is half-open
+    pub half_open_probes: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+            half_open_probes: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    probes: u32,
+    /// When the circuit opened, or when probing started while half-open
+    since: Instant,
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            failures: 0,
+            probes: 0,
+            since: Instant::now(),
+        }
+    }
+}
+
+/// Per-store circuit breaker, so that validations fail fast during a store outage instead of waiting on the store.
+///
+/// Only infrastructure failures (network errors, store outages, see `Error::is_retryable`) count towards opening
+/// the circuit, rejected receipts do not.
+/// ```
+/// use iap::{CircuitBreaker, CircuitBreakerConfig, CircuitState, Platform, UnityPurchaseValidator};
+/// use std::sync::Arc;
+///
+/// let circuit_breaker = Arc::new(CircuitBreaker::new(CircuitBreakerConfig::default()));
+/// let validator = UnityPurchaseValidator::default().set_circuit_breaker(circuit_breaker.clone());
+///
+/// assert_eq!(circuit_breaker.state(Platform::AppleAppStore), CircuitState::Closed);
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    circuits: Mutex<HashMap<Platform, Circuit>>,
+}
+
+impl CircuitBreaker {
+    /// Creates a circuit breaker with all circuits closed
+    #[must_use]
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The state of the circuit of `store`. An open circuit whose `open_duration` has passed is reported as
+    /// half-open, as the next call will probe the store.
+    #[must_use]
+    pub fn state(&self, store: Platform) -> CircuitState {
+        self.circuits
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(&store)
+            .map_or(CircuitState::Closed, |circuit| {
+                self.effective_state(circuit)
+            })
+    }
+
+    /// The states of the circuits of all stores called so far, ie: for health checks
+    #[must_use]
+    pub fn states(&self) -> HashMap<Platform, CircuitState> {
+        self.circuits
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .iter()
+            .map(|(store, circuit)| (*store, self.effective_state(circuit)))
+            .collect()
+    }
+
+    /// Checks whether a call to `store` may go through, which has to be followed by `record_success` or
+    /// `record_failure` once the call completes.
+    /// # Errors
+    /// Will return a `CircuitOpen` error if the circuit is open, or half-open with all probes in flight
+    pub fn acquire(&self, store: Platform) -> Result<()> {
+        self.with_circuit(store, |circuit| {
+            let elapsed = circuit.since.elapsed();
+
+            match circuit.state {
+                CircuitState::Closed => return Ok(()),
+                CircuitState::Open if elapsed < self.config.open_duration => {
+                    return Err(Error::CircuitOpen {
+                        store,
+                        retry_after: self.config.open_duration.saturating_sub(elapsed),
+                    });
+                }
+                CircuitState::Open => {
+                    tracing::info!("circuit half-open, probing store: {:?}", store);
+                    circuit.state = CircuitState::HalfOpen;
+                    circuit.probes = 0;
+                    circuit.since = Instant::now();
+                }
+                // probes which never completed (ie: cancelled calls) are given up on after `open_duration`
+                CircuitState::HalfOpen if elapsed >= self.config.open_duration => {
+                    circuit.probes = 0;
+                    circuit.since = Instant::now();
+                }
+                CircuitState::HalfOpen => {}
+            }
+
+            if circuit.probes < self.config.half_open_probes {
+                circuit.probes += 1;
+                Ok(())
+            } else {
+                Err(Error::CircuitOpen {
+                    store,
+                    retry_after: Duration::ZERO,
+                })
+            }
+        })
+    }
+
+    /// Records a completed call to `store`, closing a half-open circuit
+    pub fn record_success(&self, store: Platform) {
+        self.with_circuit(store, |circuit| match circuit.state {
+            CircuitState::Closed => circuit.failures = 0,
+            CircuitState::HalfOpen => {
+                tracing::info!("circuit closed, store: {:?}", store);
+                *circuit = Circuit::default();
+            }
+            CircuitState::Open => {}
+        });
+    }
+
+    /// Records an infrastructure failure of a call to `store`, opening the circuit after `failure_threshold`
+    /// consecutive failures, or if it was half-open
+    pub fn record_failure(&self, store: Platform) {
+        self.with_circuit(store, |circuit| {
+            circuit.failures = circuit.failures.saturating_add(1);
+
+            let open = match circuit.state {
+                CircuitState::Closed => circuit.failures >= self.config.failure_threshold,
+                CircuitState::HalfOpen => true,
+                CircuitState::Open => false,
+            };
+
+            if open {
+                tracing::warn!(
+                    "circuit opened, store: {:?}, consecutive failures: {}",
+                    store,
+                    circuit.failures
+                );
+                circuit.state = CircuitState::Open;
+                circuit.probes = 0;
+                circuit.since = Instant::now();
+            }
+        });
+    }
+
+    /// Records the outcome of a call to `store`, only infrastructure failures count as failures. Calls rejected by the
+    /// rate limiter never reached the store, they only give their probe back.
+    pub(crate) fn record<T>(&self, store: Platform, result: &Result<T>) {
+        match result {
+            Err(Error::RateLimited { .. }) => self.release_probe(store),
+            Err(err) if is_infrastructure_failure(err) => self.record_failure(store),
+            _ => self.record_success(store),
+        }
+    }
+
+    /// Lets another probe through a half-open circuit, for a call which did not reach the store
+    fn release_probe(&self, store: Platform) {
+        self.with_circuit(store, |circuit| {
+            if circuit.state == CircuitState::HalfOpen {
+                circuit.probes = circuit.probes.saturating_sub(1);
+            }
+        });
+    }
+
+    /// Runs `f` on the circuit of `store` while holding the lock
+    fn with_circuit<R>(&self, store: Platform, f: impl FnOnce(&mut Circuit) -> R) -> R {
+        f(self
+            .circuits
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .entry(store)
+            .or_default())
+    }
+
+    fn effective_state(&self, circuit: &Circuit) -> CircuitState {
+        if circuit.state == CircuitState::Open
+            && circuit.since.elapsed() >= self.config.open_duration
+        {
+            CircuitState::HalfOpen
+        } else {
+            circuit.state
+        }
+    }
+}
+
+/// Retryable errors are caused by the network or the store, except the ones raised by the validator itself.
+fn is_infrastructure_failure(err: &Error) -> bool {
+    err.is_retryable() && !matches!(err, Error::RateLimited { .. } | Error::CircuitOpen { .. })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn outage() -> Result<()> {
+        Err(Error::AppleStatusError {
+            status: 21005,
+            is_retryable: None,
+        })
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let circuit_breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_duration: Duration::from_millis(50),
+            half_open_probes: 1,
+        });
+        let store = Platform::AppleAppStore;
+
+        // rejected receipts are no failures
+        circuit_breaker.acquire(store).unwrap();
+        circuit_breaker.record(store, &outage());
+        circuit_breaker.acquire(store).unwrap();
+        circuit_breaker.record::<()>(store, &Err(Error::Custom("malformed".to_string())));
+        circuit_breaker.acquire(store).unwrap();
+        circuit_breaker.record(store, &outage());
+        assert_eq!(circuit_breaker.state(store), CircuitState::Closed);
+
+        circuit_breaker.acquire(store).unwrap();
+        circuit_breaker.record(store, &outage());
+        assert_eq!(circuit_breaker.state(store), CircuitState::Open);
+        assert_eq!(
+            circuit_breaker.state(Platform::GooglePlay),
+            CircuitState::Closed
+        );
+
+        let err = circuit_breaker.acquire(store).unwrap_err();
+        assert!(err.is_retryable());
+        assert!(matches!(
+            err,
+            Error::CircuitOpen {
+                store: Platform::AppleAppStore,
+                ..
+            }
+        ));
+
+        // a failed probe opens the circuit again
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(circuit_breaker.state(store), CircuitState::HalfOpen);
+        circuit_breaker.acquire(store).unwrap();
+        assert!(circuit_breaker.acquire(store).is_err());
+        circuit_breaker.record(store, &outage());
+        assert_eq!(circuit_breaker.state(store), CircuitState::Open);
+
+        // a rate limited probe leaves it half-open, a successful probe closes it
+        std::thread::sleep(Duration::from_millis(60));
+        circuit_breaker.acquire(store).unwrap();
+        circuit_breaker.record::<()>(
+            store,
+            &Err(Error::RateLimited {
+                retry_after: Duration::from_secs(1),
+            }),
+        );
+        assert_eq!(circuit_breaker.state(store), CircuitState::HalfOpen);
+        circuit_breaker.acquire(store).unwrap();
+        circuit_breaker.record(store, &Ok(()));
+        assert_eq!(circuit_breaker.states()[&store], CircuitState::Closed);
+        circuit_breaker.acquire(store).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::Result,
+    huawei::{self, HuaweiCredentials, HuaweiUrls},
+};
+use futures::lock::Mutex;
+use hyper::{client::HttpConnector, Client};
+use hyper_tls::HttpsConnector;
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use yup_oauth2::{authenticator::Authenticator, ServiceAccountAuthenticator, ServiceAccountKey};
+
+const GOOGLE_ANDROID_PUBLISHER_SCOPE: &str = "https://www.googleapis.com/auth/androidpublisher";
+/// Access tokens are requested again this long before they expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// The http client and access tokens shared by all validations of a `UnityPurchaseValidator`.
+///
+/// Connections are reused, and the Google and Huawei access tokens are only requested again once they expire.
+#[derive(Default)]
+pub struct SharedClients {
+    client: OnceLock<HttpsClient>,
+    google_authenticator: Mutex<Option<(String, Authenticator<HttpsConnector<HttpConnector>>)>>,
+    huawei_token: Mutex<Option<(String, String, Instant)>>,
+}
+
+impl SharedClients {
+    /// The http client, created on first use
+    pub(crate) fn client(&self) -> &HttpsClient {
+        self.client
+            .get_or_init(|| Client::builder().build::<_, hyper::Body>(HttpsConnector::new()))
+    }
+
+    /// The access token for the Google Play Developer API, `None` if no service account key is set.
+    /// The authenticator is rebuilt if the key changes.
+    pub(crate) async fn google_token(
+        &self,
+        service_account_key: Option<&ServiceAccountKey>,
+    ) -> Result<Option<String>> {
+        let Some(key) = service_account_key else {
+            return Ok(None);
+        };
+
+        // the lock is held while requesting the token, so that concurrent validations share the same one
+        let mut google_authenticator = self.google_authenticator.lock().await;
+
+        let authenticator = match google_authenticator.as_ref() {
+            Some((client_email, authenticator)) if *client_email == key.client_email => {
+                authenticator.clone()
+            }
+            _ => {
+                let authenticator =
+                    ServiceAccountAuthenticator::with_client(key.clone(), self.client().clone())
+                        .build()
+                        .await?;
+                *google_authenticator = Some((key.client_email.clone(), authenticator.clone()));
+                authenticator
+            }
+        };
+
+        let auth_token = authenticator
+            .token(&[GOOGLE_ANDROID_PUBLISHER_SCOPE])
+            .await?;
+        drop(google_authenticator);
+
+        Ok(Some(auth_token.as_str().to_string()))
+    }
+
+    /// The access token for the Huawei IAP server APIs, requested again for other client credentials or once it expires
+    pub(crate) async fn huawei_token(
+        &self,
+        credentials: &HuaweiCredentials,
+        urls: &HuaweiUrls<'_>,
+    ) -> Result<String> {
+        // the lock is held while requesting the token, so that concurrent validations share the same one
+        let mut huawei_token = self.huawei_token.lock().await;
+
+        if let Some((client_id, access_token, expires_at)) = huawei_token.as_ref() {
+            if *client_id == credentials.client_id && Instant::now() < *expires_at {
+                return Ok(access_token.clone());
+            }
+        }
+
+        let (access_token, expires_in) =
+            huawei::fetch_access_token(self.client(), credentials, urls).await?;
+        *huawei_token = Some((
+            credentials.client_id.clone(),
+            access_token.clone(),
+            Instant::now() + expires_in.saturating_sub(TOKEN_EXPIRY_MARGIN),
+        ));
+        drop(huawei_token);
+
+        Ok(access_token)
+    }
+}
@@ -0,0 +1,324 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::Error, redact::redact, Platform, ProductCatalog, PurchaseInput, PurchaseResponse,
+    PurchaseVerdict, Validator,
+};
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use std::{collections::BTreeMap, fmt};
+
+/// The purchase granting an entitlement
+#[derive(Clone, PartialEq, Eq)]
+pub struct EntitlementSource {
+    /// The store on which the purchase was made
+    pub store: Platform,
+    /// The product id of the purchase
+    pub product_id: Option<String>,
+    /// The `original_transaction_id` of the purchase
+    pub transaction_id: Option<String>,
+    /// Time at which the purchase expires, `None` if it does not expire
+    pub expiry_time: Option<DateTime<Utc>>,
+}
+
+/// The `transaction_id` is redacted, it holds the purchase token of Google Play purchases.
+impl fmt::Debug for EntitlementSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntitlementSource")
+            .field("store", &self.store)
+            .field("product_id", &self.product_id)
+            .field(
+                "transaction_id",
+                &self.transaction_id.as_deref().map(redact),
+            )
+            .field("expiry_time", &self.expiry_time)
+            .finish()
+    }
+}
+
+impl EntitlementSource {
+    /// Returns true if `self` grants access for longer than `other`. Purchases without expiry never end.
+    fn outlasts(&self, other: &Self) -> bool {
+        match (self.expiry_time, other.expiry_time) {
+            (None, Some(_)) => true,
+            (Some(expiry), Some(other)) => expiry > other,
+            (_, None) => false,
+        }
+    }
+}
+
+/// An entitlement granted to the user by at least one valid purchase
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entitlement {
+    /// The name of the entitlement, as listed in the `ProductCatalog`
+    pub name: String,
+    /// The effective expiry, the latest of all purchases granting the entitlement. `None` if it does not expire
+    pub expiry_time: Option<DateTime<Utc>>,
+    /// The purchase granting the effective expiry
+    pub source: EntitlementSource,
+}
+
+/// Several active subscriptions of the same subscription group, ie: a user subscribed on both iOS and Android
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntitlementConflict {
+    /// The `group` of the products in the `ProductCatalog`
+    pub group: String,
+    /// The active subscriptions of the group
+    pub sources: Vec<EntitlementSource>,
+}
+
+/// The merged entitlements of all purchases of a user
+#[derive(Debug, Default)]
+pub struct EntitlementSet {
+    /// The entitlements currently granted, sorted by name
+    pub entitlements: Vec<Entitlement>,
+    /// Subscription groups with more than one active subscription
+    pub conflicts: Vec<EntitlementConflict>,
+    /// Purchases whose validation could not be completed, with the index of their input. The entitlements
+    /// they may grant are missing from `entitlements`, so these should be retried rather than revoked.
+    pub errors: Vec<(usize, Error)>,
+}
+
+impl EntitlementSet {
+    /// The entitlement named `name`, if granted
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Entitlement> {
+        self.entitlements
+            .iter()
+            .find(|entitlement| entitlement.name == name)
+    }
+
+    /// Returns true if the entitlement named `name` is granted
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+}
+
+/// Merges the purchases a user made across all stores into a single `EntitlementSet`, so that a subscription
+/// bought on iOS is honoured on Android and vice versa.
+///
+/// The entitlements each product grants are looked up in the `ProductCatalog`, or taken from
+/// `PurchaseResponse::entitlements` for products missing from the catalog.
+/// ```
+/// use iap::{EntitlementAggregator, ProductCatalog, UnityPurchaseValidator};
+///
+/// let aggregator = EntitlementAggregator::new(
+///     UnityPurchaseValidator::default(),
+///     ProductCatalog::default(),
+/// );
+/// ```
+pub struct EntitlementAggregator<V> {
+    validator: V,
+    catalog: ProductCatalog,
+}
+
+impl<V: Validator> EntitlementAggregator<V> {
+    /// Aggregates the purchases validated by `validator`, with the entitlements and groups of `catalog`
+    pub const fn new(validator: V, catalog: ProductCatalog) -> Self {
+        Self { validator, catalog }
+    }
+
+    /// The wrapped validator
+    pub const fn inner(&self) -> &V {
+        &self.validator
+    }
+
+    /// Validates all `inputs` concurrently and merges the valid purchases into an `EntitlementSet`
+    pub async fn aggregate(&self, now: DateTime<Utc>, inputs: &[PurchaseInput]) -> EntitlementSet {
+        let verdicts = join_all(
+            inputs
+                .iter()
+                .map(|input| self.validator.verdict_input(now, input)),
+        )
+        .await;
+
+        let mut set = EntitlementSet::default();
+        let mut entitlements = BTreeMap::<String, EntitlementSource>::new();
+        let mut groups = BTreeMap::<String, Vec<EntitlementSource>>::new();
+
+        for (index, (input, verdict)) in inputs.iter().zip(verdicts).enumerate() {
+            let response = match verdict {
+                PurchaseVerdict::Valid(response) | PurchaseVerdict::Stale { response, .. } => {
+                    response
+                }
+                PurchaseVerdict::Invalid { .. } => continue,
+                PurchaseVerdict::Indeterminate(err) => {
+                    tracing::warn!(
+                        "entitlement aggregation, validation failed, index: {}, store: {:?}, error: {}",
+                        index,
+                        input.store(),
+                        err
+                    );
+                    set.errors.push((index, err));
+                    continue;
+                }
+            };
+
+            let source = EntitlementSource {
+                store: input.store(),
+                product_id: response.product_id.clone(),
+                transaction_id: response.original_transaction_id.clone(),
+                expiry_time: response.expiry_time,
+            };
+
+            for name in self.entitlements(&response) {
+                let current = entitlements.entry(name).or_insert_with(|| source.clone());
+                if source.outlasts(current) {
+                    *current = source.clone();
+                }
+            }
+
+            if let Some(group) = self.group(&response) {
+                let sources = groups.entry(group).or_default();
+                if !sources.iter().any(|other| {
+                    other.store == source.store && other.transaction_id == source.transaction_id
+                }) {
+                    sources.push(source);
+                }
+            }
+        }
+
+        set.entitlements = entitlements
+            .into_iter()
+            .map(|(name, source)| Entitlement {
+                name,
+                expiry_time: source.expiry_time,
+                source,
+            })
+            .collect();
+
+        set.conflicts = groups
+            .into_iter()
+            .filter(|(_, sources)| sources.len() > 1)
+            .map(|(group, sources)| EntitlementConflict { group, sources })
+            .collect();
+
+        for conflict in &set.conflicts {
+            tracing::warn!(
+                group = %conflict.group,
+                sources = ?conflict.sources,
+                "entitlement aggregation, several active subscriptions"
+            );
+        }
+
+        set
+    }
+
+    fn entitlements(&self, response: &PurchaseResponse) -> Vec<String> {
+        response
+            .product_id
+            .as_deref()
+            .and_then(|product_id| self.catalog.product(product_id))
+            .map_or_else(
+                || response.entitlements.clone(),
+                |product| product.entitlements.clone(),
+            )
+    }
+
+    fn group(&self, response: &PurchaseResponse) -> Option<String> {
+        response
+            .product_id
+            .as_deref()
+            .and_then(|product_id| self.catalog.product(product_id))
+            .and_then(|product| product.group.clone())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{error::Result, UnityPurchaseReceipt};
+    use async_trait::async_trait;
+    use chrono::Duration;
+
+    /// Returns the response in the payload of the receipt
+    struct PayloadValidator;
+
+    #[async_trait]
+    impl Validator for PayloadValidator {
+        async fn validate(
+            &self,
+            _now: DateTime<Utc>,
+            receipt: &UnityPurchaseReceipt,
+        ) -> Result<PurchaseResponse> {
+            if receipt.payload.is_empty() {
+                return Err(Error::Custom("store unavailable".to_string()));
+            }
+            Ok(serde_json::from_str(&receipt.payload)?)
+        }
+    }
+
+    fn input(store: Platform, response: &PurchaseResponse) -> PurchaseInput {
+        PurchaseInput::Unity(UnityPurchaseReceipt {
+            store,
+            payload: serde_json::to_string(response).unwrap(),
+            ..UnityPurchaseReceipt::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_aggregate() {
+        let now = Utc::now();
+        let catalog = ProductCatalog::from_toml(
+            &std::fs::read_to_string("res/test_product_catalog.toml").unwrap(),
+        )
+        .unwrap();
+        let aggregator = EntitlementAggregator::new(PayloadValidator, catalog);
+
+        let apple = PurchaseResponse {
+            valid: true,
+            product_id: Some("com.game.vip.monthly".to_string()),
+            original_transaction_id: Some("apple_txn".to_string()),
+            expiry_time: Some(now + Duration::days(10)),
+            ..PurchaseResponse::default()
+        };
+        let google = PurchaseResponse {
+            valid: true,
+            product_id: Some("vip_yearly".to_string()),
+            original_transaction_id: Some("google_token".to_string()),
+            expiry_time: Some(now + Duration::days(300)),
+            ..PurchaseResponse::default()
+        };
+        let ads = PurchaseResponse {
+            valid: true,
+            product_id: Some("remove_ads".to_string()),
+            ..PurchaseResponse::default()
+        };
+        let expired = PurchaseResponse {
+            product_id: Some("season_pass".to_string()),
+            ..PurchaseResponse::default()
+        };
+
+        let set = aggregator
+            .aggregate(
+                now,
+                &[
+                    input(Platform::AppleAppStore, &apple),
+                    input(Platform::GooglePlay, &google),
+                    input(Platform::AppleAppStore, &apple),
+                    input(Platform::SamsungGalaxyStore, &ads),
+                    input(Platform::GooglePlay, &expired),
+                    PurchaseInput::Unity(UnityPurchaseReceipt::default()),
+                ],
+            )
+            .await;
+
+        let vip = set.get("vip").unwrap();
+        assert_eq!(vip.expiry_time, google.expiry_time);
+        assert_eq!(vip.source.store, Platform::GooglePlay);
+        assert_eq!(vip.source.transaction_id, Some("google_token".to_string()));
+        assert!(!format!("{:?}", vip.source).contains("google_token"));
+        assert_eq!(set.get("no_ads").unwrap().expiry_time, None);
+        assert!(!set.contains("season_pass"));
+
+        // the same apple subscription twice is no conflict, but apple and google are
+        assert_eq!(set.conflicts.len(), 1);
+        assert_eq!(set.conflicts[0].group, "vip");
+        assert_eq!(set.conflicts[0].sources.len(), 2);
+
+        assert_eq!(set.errors.len(), 1);
+        assert_eq!(set.errors[0].0, 5);
+    }
+}
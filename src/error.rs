@@ -1,16 +1,25 @@
 //! Convenience types for lib specific error handling
 #![allow(clippy::enum_variant_names)]
 
+use crate::huawei::HUAWEI_RESPONSE_CODE_INTERNAL_ERROR;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// <https://developer.apple.com/documentation/appstorereceipts/status>
+const APPLE_STATUS_SERVER_UNAVAILABLE: i32 = 21005;
+/// <https://developer.apple.com/documentation/appstorereceipts/status>
+const APPLE_STATUS_INTERNAL_DATA_ACCESS: i32 = 21009;
+
 /// General Error type that will wrap other error types for our convenience.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
-    /// serde_json Errors
+    /// `serde_json` Errors
     #[error("serde_json error: {0}")]
     SerdeError(#[from] serde_json::Error),
 
-    /// hyper::http errors
+    /// `hyper::http` errors
     #[error("http error: {0}")]
     HttpError(#[from] hyper::http::Error),
 
@@ -18,7 +27,7 @@ pub enum Error {
     #[error("hyper error: {0}")]
     HyperError(#[from] hyper::Error),
 
-    /// yup_oauth2 errors
+    /// `yup_oauth2` errors
     #[error("yup_oauth error: {0}")]
     YupOauth2Error(#[from] yup_oauth2::Error),
 
@@ -34,10 +43,252 @@ pub enum Error {
     #[error("utf8 error: {0}")]
     FromUtf8Error(#[from] std::string::FromUtf8Error),
 
+    /// The Google Play Developer API responded with a non success http status
+    #[error("google api error, http status: {status}, error: {error}")]
+    GoogleApiError {
+        /// Http status of the response
+        status: StatusCode,
+        /// The parsed error body
+        error: GoogleErrorDetails,
+    },
+
+    /// The purchase token is no longer valid, ie: the purchase has expired too long ago to be queried (http status 410)
+    #[error("google purchase token is no longer valid: {0}")]
+    GooglePurchaseTokenInvalid(GoogleErrorDetails),
+
+    /// Apple's verifyReceipt endpoint responded with a non success http status
+    #[error(
+        "apple http error, http status: {status}, body: {}",
+        crate::redact::redact(body)
+    )]
+    AppleHttpError {
+        /// Http status of the response
+        status: StatusCode,
+        /// The raw response body
+        body: String,
+    },
+
+    /// Apple could not verify the receipt because of an issue on their side, see <https://developer.apple.com/documentation/appstorereceipts/status>
+    #[error("apple status error, status: {status}, is_retryable: {is_retryable:?}")]
+    AppleStatusError {
+        /// The `status` field of the response body
+        status: i32,
+        /// The `is-retryable` field of the response body
+        is_retryable: Option<bool>,
+    },
+
+    /// The Amazon Receipt Verification Service responded with a non success http status,
+    /// see <https://developer.amazon.com/docs/in-app-purchasing/iap-rvs-for-android-apps.html#rvs-responses>
+    #[error(
+        "amazon api error, http status: {status}, body: {}",
+        crate::redact::redact(body)
+    )]
+    AmazonApiError {
+        /// Http status of the response
+        status: StatusCode,
+        /// The raw response body
+        body: String,
+    },
+
+    /// The Huawei IAP server APIs responded with a non success http status or an internal error
+    #[error("huawei api error, http status: {status}, response code: {response_code}, message: {message:?}")]
+    HuaweiApiError {
+        /// Http status of the response
+        status: StatusCode,
+        /// The `responseCode` of the response body
+        response_code: String,
+        /// The `responseMessage` of the response body, or the raw body if it could not be parsed
+        message: Option<String>,
+    },
+
+    /// The Microsoft Store services or Azure AD responded with a non success http status
+    #[error(
+        "microsoft api error, http status: {status}, body: {}",
+        crate::redact::redact(body)
+    )]
+    MicrosoftApiError {
+        /// Http status of the response
+        status: StatusCode,
+        /// The raw response body
+        body: String,
+    },
+
+    /// The Steam partner Web API responded with a non success http status
+    #[error(
+        "steam api error, http status: {status}, body: {}",
+        crate::redact::redact(body)
+    )]
+    SteamApiError {
+        /// Http status of the response
+        status: StatusCode,
+        /// The raw response body
+        body: String,
+    },
+
+    /// The Galaxy Store IAP server responded with a non success http status
+    #[error(
+        "samsung api error, http status: {status}, body: {}",
+        crate::redact::redact(body)
+    )]
+    SamsungApiError {
+        /// Http status of the response
+        status: StatusCode,
+        /// The raw response body
+        body: String,
+    },
+
+    /// The call was rejected by the rate limiter because the quota is exhausted
+    #[error("rate limited, retry after: {retry_after:?}")]
+    RateLimited {
+        /// How long until the quota allows the call
+        retry_after: std::time::Duration,
+    },
+
+    /// The circuit breaker of the store is open after repeated infrastructure failures, the store was not called
+    #[error("circuit open, store: {store:?}, retry after: {retry_after:?}")]
+    CircuitOpen {
+        /// The store whose circuit is open
+        store: crate::Platform,
+        /// How long until the circuit lets a probe call through, zero while probe calls are in flight
+        retry_after: std::time::Duration,
+    },
+
+    /// Base64 decode errors
+    #[error("base64 error: {0}")]
+    Base64Error(#[from] base64::DecodeError),
+
+    /// The configured public key could not be parsed
+    #[error("invalid public key: {0}")]
+    InvalidPublicKey(String),
+
+    /// The signature of the purchase data could not be verified
+    #[error("invalid signature")]
+    InvalidSignature,
+
+    /// jsonwebtoken errors
+    #[error("jwt error: {0}")]
+    JwtError(#[from] jsonwebtoken::errors::Error),
+
+    /// toml errors
+    #[error("toml error: {0}")]
+    TomlError(#[from] toml::de::Error),
+
+    /// rusqlite errors
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+
+    /// The failure of a call shared by several validations, ie: an App Store receipt fetched once for a batch
+    #[error(transparent)]
+    Shared(std::sync::Arc<Self>),
+
+    /// A store or feature was used without setting its credentials or configuration on the validator
+    #[error("not configured: {0}")]
+    NotConfigured(&'static str),
     /// Custom error
     #[error("custom error: {0}")]
     Custom(String),
 }
 
+impl Error {
+    /// Returns true if the error is caused by a temporary issue (network, store outage, rate limits),
+    /// and the validation can be retried at a later time. Errors caused by the receipt itself or by a
+    /// misconfiguration (ie: a missing or invalid service account key) are not retryable.
+    #[must_use]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::HyperError(_) | Self::RateLimited { .. } | Self::CircuitOpen { .. } => true,
+            Self::YupOauth2Error(err) => matches!(
+                err,
+                yup_oauth2::Error::HttpError(_) | yup_oauth2::Error::LowLevelError(_)
+            ),
+            Self::GoogleApiError { status, .. }
+            | Self::AppleHttpError { status, .. }
+            | Self::AmazonApiError { status, .. }
+            | Self::MicrosoftApiError { status, .. }
+            | Self::SteamApiError { status, .. }
+            | Self::SamsungApiError { status, .. } => is_retryable_status(*status),
+            Self::HuaweiApiError {
+                status,
+                response_code,
+                ..
+            } => {
+                is_retryable_status(*status) || response_code == HUAWEI_RESPONSE_CODE_INTERNAL_ERROR
+            }
+            Self::AppleStatusError {
+                status,
+                is_retryable,
+            } => is_retryable.unwrap_or_else(|| is_retryable_apple_status(*status)),
+            Self::Shared(err) => err.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the store reported the purchase token as no longer valid or expired.
+    #[must_use]
+    pub fn is_purchase_token_invalid(&self) -> bool {
+        match self {
+            Self::GooglePurchaseTokenInvalid(_) => true,
+            Self::AmazonApiError { status, .. } => {
+                *status == StatusCode::BAD_REQUEST || *status == StatusCode::GONE
+            }
+            Self::Shared(err) => err.is_purchase_token_invalid(),
+            _ => false,
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+/// Returns true for Apple status codes which indicate an issue on Apple's side rather than with the receipt.
+pub(crate) const fn is_retryable_apple_status(status: i32) -> bool {
+    matches!(
+        status,
+        APPLE_STATUS_SERVER_UNAVAILABLE | APPLE_STATUS_INTERNAL_DATA_ACCESS | 21100..=21199
+    )
+}
+
+/// The error object returned in the body of a failed Google API request.
+/// See <https://cloud.google.com/apis/design/errors#http_mapping>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct GoogleErrorDetails {
+    /// The http status code
+    pub code: u16,
+    /// The canonical error code, ie: `UNAUTHENTICATED` or `PERMISSION_DENIED`. Not always set by the Play Developer API.
+    pub status: Option<String>,
+    /// A developer-facing error message
+    pub message: String,
+    /// Additional error information, the `reason` field contains values like `purchaseTokenDoesNotMatchSubscriptionId`
+    #[serde(default)]
+    pub errors: Vec<GoogleErrorReason>,
+}
+
+impl std::fmt::Display for GoogleErrorDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.code,
+            self.status.as_deref().unwrap_or_default(),
+            self.message
+        )
+    }
+}
+
+/// Entry of the `errors` array in a Google API error body.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct GoogleErrorReason {
+    /// The error message
+    pub message: Option<String>,
+    /// The error domain, ie: `global` or `androidpublisher`
+    pub domain: Option<String>,
+    /// The reason of the error, ie: `subscriptionPurchaseNoLongerAvailable`
+    pub reason: Option<String>,
+}
+
 /// Convenience type for Results
 pub type Result<T> = std::result::Result<T, Error>;
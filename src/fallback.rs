@@ -0,0 +1,542 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{error::Result, Platform, PurchaseResponse, PurchaseVerdict};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+const DEFAULT_MAX_STALENESS_HOURS: i64 = 72;
+
+/// The last valid result of a purchase, used by the `OfflineFallback` while the store cannot be reached
+#[derive(Clone, Debug)]
+pub struct ValidationSnapshot {
+    /// The response of the last successful validation
+    pub response: PurchaseResponse,
+    /// When the purchase was last validated
+    pub validated_at: DateTime<Utc>,
+    /// Hex encoded SHA-256 of the receipt payload which was validated. Transaction ids are not secret, so the
+    /// snapshot is only used for the same payload.
+    pub payload_hash: String,
+}
+
+impl ValidationSnapshot {
+    /// Hex encoded SHA-256 of a receipt payload, as stored in `payload_hash`
+    #[must_use]
+    pub fn hash_payload(payload: &str) -> String {
+        format!("{:x}", Sha256::digest(payload.as_bytes()))
+    }
+}
+
+/// Storage for the last valid result of each purchase, keyed by the store and the transaction id
+/// (the purchase token for Google Play purchases).
+#[async_trait]
+pub trait SnapshotStore: Send + Sync {
+    /// The snapshot of the transaction, `None` if there is none
+    async fn load(
+        &self,
+        store: Platform,
+        transaction_id: &str,
+    ) -> Result<Option<ValidationSnapshot>>;
+
+    /// Stores the snapshot of the transaction, replacing the previous one
+    async fn save(
+        &self,
+        store: Platform,
+        transaction_id: &str,
+        snapshot: &ValidationSnapshot,
+    ) -> Result<()>;
+
+    /// Drops the snapshot of the transaction, called when the store reports the purchase as invalid
+    async fn remove(&self, store: Platform, transaction_id: &str) -> Result<()>;
+}
+
+/// `SnapshotStore` which keeps the snapshots in memory. Useful for tests or single instance deployments.
+#[derive(Default, Debug)]
+pub struct InMemorySnapshotStore {
+    snapshots: Mutex<HashMap<(Platform, String), ValidationSnapshot>>,
+}
+
+impl InMemorySnapshotStore {
+    fn snapshots(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<(Platform, String), ValidationSnapshot>> {
+        self.snapshots
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for InMemorySnapshotStore {
+    async fn load(
+        &self,
+        store: Platform,
+        transaction_id: &str,
+    ) -> Result<Option<ValidationSnapshot>> {
+        Ok(self
+            .snapshots()
+            .get(&(store, transaction_id.to_string()))
+            .cloned())
+    }
+
+    async fn save(
+        &self,
+        store: Platform,
+        transaction_id: &str,
+        snapshot: &ValidationSnapshot,
+    ) -> Result<()> {
+        self.snapshots()
+            .insert((store, transaction_id.to_string()), snapshot.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, store: Platform, transaction_id: &str) -> Result<()> {
+        self.snapshots()
+            .remove(&(store, transaction_id.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteSnapshotStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{Platform, Result, SnapshotStore, ValidationSnapshot};
+    use crate::sqlite::{store_key, SharedConnection};
+    use async_trait::async_trait;
+    use chrono::{TimeZone, Utc};
+    use rusqlite::{params, Connection, OptionalExtension};
+    use std::path::Path;
+
+    /// `SnapshotStore` backed by a `SQLite` database, the responses are stored as JSON. Requires the `sqlite`
+    /// feature.
+    pub struct SqliteSnapshotStore {
+        connection: SharedConnection,
+    }
+
+    impl SqliteSnapshotStore {
+        /// Opens (or creates) the database at `path` and creates the `iap_validation_snapshots` table if it does
+        /// not exist.
+        /// # Errors
+        /// Will return an error if the database cannot be opened or the table cannot be created
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            Self::new(Connection::open(path)?)
+        }
+
+        /// Creates the store from an existing connection, creating the `iap_validation_snapshots` table if it
+        /// does not exist.
+        /// # Errors
+        /// Will return an error if the table cannot be created
+        pub fn new(connection: Connection) -> Result<Self> {
+            connection.execute(
+                "CREATE TABLE IF NOT EXISTS iap_validation_snapshots (
+                    store TEXT NOT NULL,
+                    transaction_id TEXT NOT NULL,
+                    response TEXT NOT NULL,
+                    validated_at INTEGER NOT NULL,
+                    payload_hash TEXT NOT NULL,
+                    PRIMARY KEY (store, transaction_id)
+                )",
+                [],
+            )?;
+
+            Ok(Self {
+                connection: SharedConnection::new(connection),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl SnapshotStore for SqliteSnapshotStore {
+        async fn load(
+            &self,
+            store: Platform,
+            transaction_id: &str,
+        ) -> Result<Option<ValidationSnapshot>> {
+            let transaction_id = transaction_id.to_string();
+            let row = self
+                .connection
+                .call(move |connection| {
+                    Ok(connection
+                        .query_row(
+                            "SELECT response, validated_at, payload_hash FROM iap_validation_snapshots WHERE store = ?1 AND transaction_id = ?2",
+                            params![store_key(store), transaction_id],
+                            |row| {
+                                Ok((
+                                    row.get::<_, String>(0)?,
+                                    row.get::<_, i64>(1)?,
+                                    row.get::<_, String>(2)?,
+                                ))
+                            },
+                        )
+                        .optional()?)
+                })
+                .await?;
+
+            let Some((response, validated_at, payload_hash)) = row else {
+                return Ok(None);
+            };
+
+            Ok(Some(ValidationSnapshot {
+                response: serde_json::from_str(&response)?,
+                validated_at: Utc
+                    .timestamp_millis_opt(validated_at)
+                    .single()
+                    .unwrap_or_default(),
+                payload_hash,
+            }))
+        }
+
+        async fn save(
+            &self,
+            store: Platform,
+            transaction_id: &str,
+            snapshot: &ValidationSnapshot,
+        ) -> Result<()> {
+            let response = serde_json::to_string(&snapshot.response)?;
+            let transaction_id = transaction_id.to_string();
+            let validated_at = snapshot.validated_at.timestamp_millis();
+            let payload_hash = snapshot.payload_hash.clone();
+
+            self.connection
+                .call(move |connection| {
+                    connection.execute(
+                        "INSERT OR REPLACE INTO iap_validation_snapshots (store, transaction_id, response, validated_at, payload_hash) VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![store_key(store), transaction_id, response, validated_at, payload_hash],
+                    )?;
+                    Ok(())
+                })
+                .await
+        }
+
+        async fn remove(&self, store: Platform, transaction_id: &str) -> Result<()> {
+            let transaction_id = transaction_id.to_string();
+
+            self.connection
+                .call(move |connection| {
+                    connection.execute(
+                        "DELETE FROM iap_validation_snapshots WHERE store = ?1 AND transaction_id = ?2",
+                        params![store_key(store), transaction_id],
+                    )?;
+                    Ok(())
+                })
+                .await
+        }
+    }
+}
+
+/// Fallback policy keeping valid purchases valid while their store cannot be reached.
+///
+/// Each valid result is stored as a `ValidationSnapshot`. When a validation fails with a retryable error (network
+/// errors, store outages, open circuits), the last snapshot is returned as a `PurchaseVerdict::Stale` verdict, as
+/// long as it is at most `max_staleness` old, the subscription has not expired since and the receipt payload is the
+/// one which was validated. Invalid results for the same payload drop the snapshot.
+/// ```
+/// use iap::{InMemorySnapshotStore, OfflineFallback, UnityPurchaseValidator};
+/// use std::sync::Arc;
+///
+/// let validator = UnityPurchaseValidator::default().set_offline_fallback(
+///     OfflineFallback::new(Arc::new(InMemorySnapshotStore::default()))
+///         .set_max_staleness(chrono::Duration::hours(24)),
+/// );
+/// ```
+pub struct OfflineFallback {
+    snapshots: Arc<dyn SnapshotStore>,
+    max_staleness: Duration,
+}
+
+impl OfflineFallback {
+    /// Creates the fallback policy with the default maximum staleness of 72 hours
+    #[must_use]
+    pub fn new(snapshots: Arc<dyn SnapshotStore>) -> Self {
+        Self {
+            snapshots,
+            max_staleness: Duration::hours(DEFAULT_MAX_STALENESS_HOURS),
+        }
+    }
+
+    /// Sets how long after the last successful validation a snapshot may still be used
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_max_staleness(self, max_staleness: Duration) -> Self {
+        let mut new = self;
+        new.max_staleness = max_staleness;
+        new
+    }
+
+    /// Records the `verdict` of the transaction validated from `payload`, or replaces it with the last snapshot of the
+    /// same payload if the store failed
+    pub(crate) async fn apply(
+        &self,
+        now: DateTime<Utc>,
+        store: Platform,
+        transaction_id: &str,
+        payload: &str,
+        verdict: PurchaseVerdict,
+    ) -> PurchaseVerdict {
+        let payload_hash = ValidationSnapshot::hash_payload(payload);
+
+        let result = match &verdict {
+            PurchaseVerdict::Valid(response) => {
+                let snapshot = ValidationSnapshot {
+                    response: response.clone(),
+                    validated_at: now,
+                    payload_hash,
+                };
+                self.snapshots.save(store, transaction_id, &snapshot).await
+            }
+            PurchaseVerdict::Invalid { .. } => {
+                self.remove(store, transaction_id, &payload_hash).await
+            }
+            PurchaseVerdict::Indeterminate(err) if err.is_retryable() => {
+                return self
+                    .fallback(now, store, transaction_id, &payload_hash, verdict)
+                    .await;
+            }
+            PurchaseVerdict::Indeterminate(_) | PurchaseVerdict::Stale { .. } => Ok(()),
+        };
+
+        if let Err(err) = result {
+            tracing::warn!(
+                "failed to update validation snapshot, store: {:?}, error: {}",
+                store,
+                err
+            );
+        }
+
+        verdict
+    }
+
+    /// Drops the snapshot of the transaction if it was validated from the same payload, so invalid payloads sent with
+    /// the transaction id of someone else's purchase do not drop its snapshot
+    async fn remove(
+        &self,
+        store: Platform,
+        transaction_id: &str,
+        payload_hash: &str,
+    ) -> Result<()> {
+        match self.snapshots.load(store, transaction_id).await? {
+            Some(snapshot) if snapshot.payload_hash == payload_hash => {
+                self.snapshots.remove(store, transaction_id).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn fallback(
+        &self,
+        now: DateTime<Utc>,
+        store: Platform,
+        transaction_id: &str,
+        payload_hash: &str,
+        verdict: PurchaseVerdict,
+    ) -> PurchaseVerdict {
+        let PurchaseVerdict::Indeterminate(error) = verdict else {
+            return verdict;
+        };
+
+        let snapshot = match self.snapshots.load(store, transaction_id).await {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                tracing::warn!(
+                    "failed to load validation snapshot, store: {:?}, error: {}",
+                    store,
+                    err
+                );
+                None
+            }
+        };
+
+        match snapshot {
+            Some(snapshot)
+                if snapshot.payload_hash == payload_hash
+                    && now - snapshot.validated_at <= self.max_staleness
+                    && snapshot
+                        .response
+                        .expiry_time
+                        .map_or(true, |expiry_time| expiry_time > now) =>
+            {
+                tracing::warn!(
+                    "store unreachable, using validation snapshot, store: {:?}, validated_at: {}, error: {}",
+                    store,
+                    snapshot.validated_at,
+                    error
+                );
+                PurchaseVerdict::Stale {
+                    response: snapshot.response,
+                    validated_at: snapshot.validated_at,
+                    error,
+                }
+            }
+            _ => PurchaseVerdict::Indeterminate(error),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{error::Error, InvalidReason};
+
+    fn outage() -> PurchaseVerdict {
+        PurchaseVerdict::Indeterminate(Error::AppleStatusError {
+            status: 21005,
+            is_retryable: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_offline_fallback() {
+        let now = Utc::now();
+        let fallback = OfflineFallback::new(Arc::new(InMemorySnapshotStore::default()))
+            .set_max_staleness(Duration::hours(1));
+        let store = Platform::AppleAppStore;
+
+        assert!(matches!(
+            fallback.apply(now, store, "txn", "payload", outage()).await,
+            PurchaseVerdict::Indeterminate(_)
+        ));
+
+        let valid = PurchaseVerdict::Valid(PurchaseResponse {
+            valid: true,
+            expiry_time: Some(now + Duration::minutes(30)),
+            ..PurchaseResponse::default()
+        });
+        assert!(fallback
+            .apply(now, store, "txn", "payload", valid)
+            .await
+            .is_valid());
+
+        let later = now + Duration::minutes(10);
+        assert!(matches!(
+            fallback.apply(later, store, "txn", "payload", outage()).await,
+            PurchaseVerdict::Stale { validated_at, .. } if validated_at == now
+        ));
+
+        // not retryable
+        assert!(matches!(
+            fallback
+                .apply(
+                    later,
+                    store,
+                    "txn",
+                    "payload",
+                    PurchaseVerdict::Indeterminate(Error::Custom("config".to_string()))
+                )
+                .await,
+            PurchaseVerdict::Indeterminate(_)
+        ));
+
+        // a different payload with the same transaction id
+        assert!(matches!(
+            fallback
+                .apply(later, store, "txn", "forged", outage())
+                .await,
+            PurchaseVerdict::Indeterminate(_)
+        ));
+        let invalid = PurchaseVerdict::Invalid {
+            reason: InvalidReason::MalformedReceipt("forged".to_string()),
+            product_id: None,
+        };
+        fallback.apply(later, store, "txn", "forged", invalid).await;
+        assert!(fallback
+            .apply(later, store, "txn", "payload", outage())
+            .await
+            .is_stale());
+
+        // expired since
+        assert!(matches!(
+            fallback
+                .apply(
+                    now + Duration::minutes(40),
+                    store,
+                    "txn",
+                    "payload",
+                    outage()
+                )
+                .await,
+            PurchaseVerdict::Indeterminate(_)
+        ));
+
+        let invalid = PurchaseVerdict::Invalid {
+            reason: InvalidReason::NotPurchased,
+            product_id: None,
+        };
+        fallback
+            .apply(later, store, "txn", "payload", invalid)
+            .await;
+        assert!(matches!(
+            fallback
+                .apply(later, store, "txn", "payload", outage())
+                .await,
+            PurchaseVerdict::Indeterminate(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_max_staleness() {
+        let now = Utc::now();
+        let fallback = OfflineFallback::new(Arc::new(InMemorySnapshotStore::default()))
+            .set_max_staleness(Duration::hours(1));
+        let store = Platform::GooglePlay;
+
+        let valid = PurchaseVerdict::Valid(PurchaseResponse {
+            valid: true,
+            ..PurchaseResponse::default()
+        });
+        fallback.apply(now, store, "token", "payload", valid).await;
+
+        assert!(fallback
+            .apply(
+                now + Duration::minutes(59),
+                store,
+                "token",
+                "payload",
+                outage()
+            )
+            .await
+            .is_stale());
+        assert!(!fallback
+            .apply(
+                now + Duration::minutes(61),
+                store,
+                "token",
+                "payload",
+                outage()
+            )
+            .await
+            .is_stale());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_snapshot_store() {
+        let snapshots =
+            SqliteSnapshotStore::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        let store = Platform::GooglePlay;
+        let snapshot = ValidationSnapshot {
+            response: PurchaseResponse {
+                valid: true,
+                product_id: Some("sub".to_string()),
+                ..PurchaseResponse::default()
+            },
+            validated_at: Utc::now(),
+            payload_hash: ValidationSnapshot::hash_payload("payload"),
+        };
+
+        assert!(snapshots.load(store, "token").await.unwrap().is_none());
+        snapshots.save(store, "token", &snapshot).await.unwrap();
+        let loaded = snapshots.load(store, "token").await.unwrap().unwrap();
+        assert_eq!(loaded.response.product_id, snapshot.response.product_id);
+        assert_eq!(
+            loaded.validated_at.timestamp_millis(),
+            snapshot.validated_at.timestamp_millis()
+        );
+
+        snapshots.remove(store, "token").await.unwrap();
+        assert!(snapshots.load(store, "token").await.unwrap().is_none());
+    }
+}
@@ -1,12 +1,34 @@
 #![allow(clippy::module_name_repetitions)]
 
-use super::{error, error::Result, PurchaseResponse, UnityPurchaseReceipt};
-use chrono::{DateTime, Utc};
-use hyper::{body, Body, Client, Request};
-use hyper_tls::HttpsConnector;
-use serde::{de::Error, Deserialize, Serialize};
-use yup_oauth2::{ServiceAccountAuthenticator, ServiceAccountKey};
+use super::{
+    error,
+    error::{GoogleErrorDetails, Result},
+    redact::redact,
+    PurchaseResponse, SharedClients, UnityPurchaseReceipt,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{body, Body, Request, StatusCode};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use yup_oauth2::ServiceAccountKey;
 
+const GOOGLE_ANDROID_PUBLISHER: &str = "https://androidpublisher.googleapis.com";
+
+/// Convenience struct for storing the base URL of the Google Play Developer API.
+pub struct GoogleUrls<'a> {
+    /// By default, <https://androidpublisher.googleapis.com>
+    pub publisher: &'a str,
+}
+
+impl Default for GoogleUrls<'_> {
+    fn default() -> Self {
+        GoogleUrls {
+            publisher: GOOGLE_ANDROID_PUBLISHER,
+        }
+    }
+}
+
+/// Response body of the Google Play Developer API for subscriptions and products.
+///
 /// See <https://developers.google.com/android-publisher/api-ref/rest/v3/purchases.subscriptions#SubscriptionPurchase>
 /// and <https://developers.google.com/android-publisher/api-ref/rest/v3/purchases.products#ProductPurchase> for details
 /// on each field.
@@ -33,22 +55,50 @@ pub struct GoogleResponse {
     #[serde(rename = "purchaseState")]
     /// The purchase state of the order. Possible values are: 0. Purchased 1. Canceled 2. Pending
     pub purchase_state: Option<u32>,
+    /// The quantity associated with the purchase of the inapp product. If not present, the quantity is 1.
+    pub quantity: Option<u32>,
+    /// Whether the subscription will automatically be renewed when it reaches its current expiry time.
+    #[serde(rename = "autoRenewing")]
+    pub auto_renewing: Option<bool>,
+    /// The payment state of the subscription. Possible values are: 0. Payment pending 1. Payment received 2. Free trial 3. Pending deferred upgrade/downgrade
+    #[serde(rename = "paymentState")]
+    pub payment_state: Option<i32>,
+    /// The reason why a subscription was canceled or is not auto-renewing. Possible values are: 0. User canceled 1. Canceled by the system, ie: a billing problem 2. Replaced with a new subscription 3. Canceled by the developer
+    #[serde(rename = "cancelReason")]
+    pub cancel_reason: Option<i32>,
+    /// The purchase token of the subscription this purchase replaced, ie: when upgrading
+    #[serde(rename = "linkedPurchaseToken")]
+    pub linked_purchase_token: Option<String>,
 }
 
 /// Metadata related to the purchase, used to populate the get request to google
+///
+/// Play Billing Library 5+ purchases forward `productDetails` in place of `skuDetails`, and either may be a
+/// single json string or an array of them, one per product.
 #[derive(Serialize, Deserialize)]
 pub struct GooglePlayData {
     /// JSON data which contains the url parameters for the get request
     pub json: String,
-    ///
+    /// Signature of the json data
     pub signature: String,
-    /// Contains the `SkuType`
-    #[serde(rename = "skuDetails")]
-    pub sku_details: String,
+    /// Contains the `SkuType`, set by Play Billing Library versions before 5
+    #[serde(
+        rename = "skuDetails",
+        default,
+        deserialize_with = "deserialize_details"
+    )]
+    pub sku_details: Vec<String>,
+    /// Contains the `SkuType`, set by Play Billing Library 5+
+    #[serde(
+        rename = "productDetails",
+        default,
+        deserialize_with = "deserialize_details"
+    )]
+    pub product_details: Vec<String>,
 }
 
 /// enum for differentiating between product purchases and subscriptions
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SkuType {
     /// Subscription
     #[serde(rename = "subs")]
@@ -64,38 +114,89 @@ impl GooglePlayData {
         Ok(serde_json::from_str(payload)?)
     }
 
-    /// Construct the uri for the get request from the parameters in the json field
+    /// Construct the uri for the get request from the parameters in the json field, for the first product of the purchase
+    #[allow(clippy::trivially_copy_pass_by_ref)]
     pub fn get_uri(&self, sku_type: &SkuType) -> Result<String> {
-        let parameters: GooglePlayDataJson = serde_json::from_str(&self.json)?;
+        let parameters = self.get_parameters()?;
+        let product_id = parameters.first_product_id()?;
 
         tracing::debug!(
-            "google purchase/receipt params, package: {}, productId: {}, token: {}",
+            package_name = %parameters.package_name,
+            product_id = %product_id,
+            token = %redact(&parameters.token),
+            "google purchase/receipt params"
+        );
+
+        Ok(google_purchase_uri(
+            &GoogleUrls::default(),
             &parameters.package_name,
-            &parameters.product_id,
+            product_id,
             &parameters.token,
-        );
+            *sku_type,
+        ))
+    }
 
-        match sku_type {
-            SkuType::Subs => Ok(format!(
-                "https://androidpublisher.googleapis.com/androidpublisher/v3/applications/{}/purchases/subscriptions/{}/tokens/{}",
-                parameters.package_name, parameters.product_id, parameters.token
-            )),
-            SkuType::Inapp => Ok(format!(
-                "https://androidpublisher.googleapis.com/androidpublisher/v3/applications/{}/purchases/products/{}/tokens/{}",
-                parameters.package_name, parameters.product_id, parameters.token
-            ))
-        }
+    /// Extract the parameters from the json field
+    pub fn get_parameters(&self) -> Result<GooglePlayDataJson> {
+        Ok(serde_json::from_str(&self.json)?)
     }
 
-    /// Extract the `SkuDetails`
+    /// Extract the `SkuDetails` of the first product, from either `productDetails` or `skuDetails`.
+    /// All products of a purchase share the same `SkuType`.
     pub fn get_sku_details(&self) -> Result<SkuDetails> {
-        Ok(serde_json::from_str(&self.sku_details)?)
+        let details = self
+            .product_details
+            .first()
+            .or_else(|| self.sku_details.first())
+            .ok_or_else(|| {
+                serde_json::Error::custom("missing skuDetails and productDetails in the payload")
+            })?;
+
+        Ok(serde_json::from_str(details)?)
+    }
+}
+
+/// The details are forwarded either as a single json string or as an array of json strings.
+fn deserialize_details<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Details {
+        One(String),
+        Many(Vec<String>),
     }
+
+    Ok(match Option::<Details>::deserialize(deserializer)? {
+        Some(Details::One(details)) => vec![details],
+        Some(Details::Many(details)) => details,
+        None => Vec::new(),
+    })
+}
+
+/// Construct the uri of the purchases.subscriptions or purchases.products get request
+#[must_use]
+pub fn google_purchase_uri(
+    urls: &GoogleUrls<'_>,
+    package_name: &str,
+    product_id: &str,
+    token: &str,
+    sku_type: SkuType,
+) -> String {
+    let kind = match sku_type {
+        SkuType::Subs => "subscriptions",
+        SkuType::Inapp => "products",
+    };
+
+    format!(
+        "{}/androidpublisher/v3/applications/{package_name}/purchases/{kind}/{product_id}/tokens/{token}",
+        urls.publisher,
+    )
 }
 
 #[derive(Deserialize)]
 pub struct SkuDetails {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", alias = "productType")]
     pub sku_type: SkuType,
 }
 
@@ -103,8 +204,13 @@ pub struct SkuDetails {
 pub struct GooglePlayDataJson {
     #[serde(rename = "packageName")]
     pub package_name: String,
+    /// Set by Play Billing Library versions before 5
     #[serde(rename = "productId")]
-    pub product_id: String,
+    pub product_id: Option<String>,
+    /// Set by Play Billing Library 5+
+    #[serde(rename = "productIds", default)]
+    pub product_ids: Vec<String>,
+    pub quantity: Option<u32>,
     #[serde(rename = "purchaseToken")]
     pub token: String,
     pub acknowledged: bool,
@@ -118,6 +224,28 @@ pub struct GooglePlayDataJson {
     pub purchase_state: i64, //0 - unspecified, 1 - purchased, 2 - pending
 }
 
+impl GooglePlayDataJson {
+    /// The ids of all products in the purchase, from either `productIds` or `productId`
+    pub fn product_ids(&self) -> Vec<String> {
+        if self.product_ids.is_empty() {
+            self.product_id.iter().cloned().collect()
+        } else {
+            self.product_ids.clone()
+        }
+    }
+
+    /// The id of the first product in the purchase
+    pub fn first_product_id(&self) -> Result<&str> {
+        self.product_ids
+            .first()
+            .or(self.product_id.as_ref())
+            .map(String::as_str)
+            .ok_or_else(|| {
+                serde_json::Error::custom("missing productId and productIds in the purchase").into()
+            })
+    }
+}
+
 /// Retrieves the response body from google
 /// # Errors
 /// Will return an error if authentication fails, if there is no response from the endpoint, or if the `payload` in the `UnityPurchaseReceipt` is malformed.
@@ -137,65 +265,118 @@ pub async fn fetch_google_receipt_data<S: AsRef<[u8]> + Send>(
 /// Retrieves the google response with a specific uri, useful for running tests.
 /// # Errors
 /// Will return an error if authentication fails, if there is no response from the endpoint, or if the `payload` in the `UnityPurchaseReceipt` is malformed.
+/// If the endpoint responds with an error status, a `GoogleApiError` is returned, or `GooglePurchaseTokenInvalid` if the purchase token is no longer valid.
 pub async fn fetch_google_receipt_data_with_uri(
     service_account_key: Option<&ServiceAccountKey>,
     uri: String,
     data: Option<GooglePlayData>,
 ) -> Result<GoogleResponse> {
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    fetch_google_receipt_data_with_clients(
+        &SharedClients::default(),
+        service_account_key,
+        uri,
+        data,
+    )
+    .await
+}
 
+/// Same as `fetch_google_receipt_data_with_uri`, with the http client and access token of `clients`.
+pub async fn fetch_google_receipt_data_with_clients(
+    clients: &SharedClients,
+    service_account_key: Option<&ServiceAccountKey>,
+    uri: String,
+    data: Option<GooglePlayData>,
+) -> Result<GoogleResponse> {
     tracing::debug!(
-        "validate google parameters, service_account_key: {}, uri: {}",
-        service_account_key.map_or(&"key not set".to_string(), |key| &key.client_email),
-        uri.clone()
+        service_account_key = service_account_key.map_or("key not set", |key| key.client_email.as_str()),
+        uri = %redact_token_in_uri(&uri),
+        "validate google parameters"
     );
 
-    let req = if let Some(key) = service_account_key {
-        let authenticator = ServiceAccountAuthenticator::builder(key.clone())
-            .build()
-            .await?;
-
-        let scopes = &["https://www.googleapis.com/auth/androidpublisher"];
-        let auth_token = authenticator.token(scopes).await?;
-
+    let req = if let Some(auth_token) = clients.google_token(service_account_key).await? {
         Request::builder()
             .method("GET")
-            .header(
-                "Authorization",
-                format!("Bearer {}", auth_token.as_str()).as_str(),
-            )
+            .header("Authorization", format!("Bearer {auth_token}").as_str())
             .uri(uri)
             .body(Body::empty())
     } else {
         Request::builder()
             .method("GET")
-            .uri(format!("{}/test", uri).as_str())
+            .uri(format!("{uri}/test").as_str())
             .body(Body::empty())
     }?;
 
-    let response = client.request(req).await?;
+    let response = clients.client().request(req).await?;
+    let status = response.status();
     let buf = body::to_bytes(response).await?;
     let string = String::from_utf8(buf.to_vec())?.replace('\n', "");
-    tracing::debug!("Google response: {}", &string);
+    tracing::debug!(
+        status = %status,
+        response_body = %redact(&string),
+        "google response"
+    );
+
+    if !status.is_success() {
+        return Err(google_api_error(status, &buf));
+    }
+
     let mut response: GoogleResponse = serde_json::from_slice(&buf).map_err(|err| {
         error::Error::SerdeError(serde_json::Error::custom(format!(
-            "Failed to deserialize google response. Was the service account key set? Error message: {}", err)
-        ))
+            "Failed to deserialize google response. Was the service account key set? Error message: {err}"
+        )))
     })?;
 
     if response.product_id.is_none() {
         if let Some(data) = data {
             tracing::info!("Product id was not set in the response, getting from unity metadata");
-            let parameters: GooglePlayDataJson = serde_json::from_str(&data.json)?;
+            let parameters = data.get_parameters()?;
 
-            response.product_id = Some(parameters.product_id);
+            response.product_id = Some(parameters.first_product_id()?.to_string());
         }
     }
 
     Ok(response)
 }
 
+/// Body of an unsuccessful Google API response
+#[derive(Deserialize)]
+struct GoogleErrorResponse {
+    error: GoogleErrorDetails,
+}
+
+fn google_api_error(status: StatusCode, buf: &[u8]) -> error::Error {
+    let error = serde_json::from_slice::<GoogleErrorResponse>(buf).map_or_else(
+        |_| GoogleErrorDetails {
+            code: status.as_u16(),
+            message: String::from_utf8_lossy(buf).into_owned(),
+            ..GoogleErrorDetails::default()
+        },
+        |response| response.error,
+    );
+
+    tracing::warn!(
+        "google api error, http status: {}, code: {}, status: {:?}, message: {}",
+        status,
+        error.code,
+        error.status,
+        error.message,
+    );
+
+    if status == StatusCode::GONE {
+        error::Error::GooglePurchaseTokenInvalid(error)
+    } else {
+        error::Error::GoogleApiError { status, error }
+    }
+}
+
+/// The request uri with the purchase token in its last path segment redacted
+fn redact_token_in_uri(uri: &str) -> String {
+    uri.rsplit_once("/tokens/").map_or_else(
+        || uri.to_string(),
+        |(base, token)| format!("{base}/tokens/{}", redact(token)),
+    )
+}
+
 /// Simply validates based on whether or not the subscription's expiration has passed.
 /// # Errors
 /// Will return an error if the `expiry_time` in the response cannot be parsed as an `i64`
@@ -223,13 +404,15 @@ pub fn validate_google_subscription(
     Ok(PurchaseResponse {
         valid,
         product_id: response.product_id.clone(),
+        expiry_time: Utc.timestamp_millis_opt(expiry_time).single(),
+        ..PurchaseResponse::default()
     })
 }
 
 #[must_use]
 /// Simply validates product purchase
 pub fn validate_google_package(response: &GoogleResponse) -> PurchaseResponse {
-    let valid = response.purchase_state.filter(|i| *i == 0).is_some();
+    let valid = response.purchase_state == Some(0);
     tracing::info!(
         "google receipt verification, valid: {}, order_id: {}",
         valid,
@@ -239,6 +422,7 @@ pub fn validate_google_package(response: &GoogleResponse) -> PurchaseResponse {
     PurchaseResponse {
         valid,
         product_id: response.product_id.clone(),
+        ..PurchaseResponse::default()
     }
 }
 
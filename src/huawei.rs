@@ -0,0 +1,591 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    clients::{HttpsClient, SharedClients},
+    error::{Error, Result},
+    redact::redact,
+    PurchaseResponse, UnityPurchaseReceipt,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{body, Body, Request};
+use rsa::{pkcs8::DecodePublicKey, signature::Verifier, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{convert::TryFrom, time::Duration};
+
+const HUAWEI_OAUTH_TOKEN: &str = "https://oauth-login.cloud.huawei.com/oauth2/v3/token";
+const HUAWEI_SIGNATURE_ALGORITHM_PSS: &str = "SHA256WithRSA/PSS";
+const HUAWEI_RESPONSE_CODE_SUCCESS: &str = "0";
+/// <https://developer.huawei.com/consumer/en/doc/HMSCore-References/server-error-code-0000001050166248>
+pub const HUAWEI_RESPONSE_CODE_INTERNAL_ERROR: &str = "-1";
+/// <https://developer.huawei.com/consumer/en/doc/HMSCore-References/server-data-model-0000001050986133#section264617465219>
+const HUAWEI_KIND_SUBSCRIPTION: i32 = 2;
+
+/// Convenience struct for storing the site URLs of the Huawei IAP server APIs, which differ per region.
+///
+/// Use the site of the region in which the app is distributed, ie: `HuaweiUrls::europe()`.
+/// See: <https://developer.huawei.com/consumer/en/doc/HMSCore-Guides/iap-api-specification-0000001050048968>
+pub struct HuaweiUrls<'a> {
+    /// Site of the Order service
+    pub order: &'a str,
+    /// Site of the Subscription service
+    pub subscription: &'a str,
+    /// Endpoint to request an access token with OAuth client credentials
+    pub oauth: &'a str,
+}
+
+impl HuaweiUrls<'_> {
+    /// Sites for apps distributed in the Chinese mainland
+    #[must_use]
+    pub const fn china() -> Self {
+        HuaweiUrls {
+            order: "https://orders-drcn.iap.cloud.huawei.com.cn",
+            subscription: "https://subscr-drcn.iap.cloud.huawei.com.cn",
+            oauth: HUAWEI_OAUTH_TOKEN,
+        }
+    }
+
+    /// Sites for apps distributed in Europe
+    #[must_use]
+    pub const fn europe() -> Self {
+        HuaweiUrls {
+            order: "https://orders-dre.iap.cloud.huawei.eu",
+            subscription: "https://subscr-dre.iap.cloud.huawei.eu",
+            oauth: HUAWEI_OAUTH_TOKEN,
+        }
+    }
+
+    /// Sites for apps distributed in Asia, Africa and Latin America
+    #[must_use]
+    pub const fn asia() -> Self {
+        HuaweiUrls {
+            order: "https://orders-dra.iap.cloud.huawei.asia",
+            subscription: "https://subscr-dra.iap.cloud.huawei.asia",
+            oauth: HUAWEI_OAUTH_TOKEN,
+        }
+    }
+
+    /// Sites for apps distributed in Russia
+    #[must_use]
+    pub const fn russia() -> Self {
+        HuaweiUrls {
+            order: "https://orders-drru.iap.cloud.huawei.ru",
+            subscription: "https://subscr-drru.iap.cloud.huawei.ru",
+            oauth: HUAWEI_OAUTH_TOKEN,
+        }
+    }
+}
+
+impl Default for HuaweiUrls<'_> {
+    /// By default, the sites for Europe
+    fn default() -> Self {
+        Self::europe()
+    }
+}
+
+/// The credentials needed to validate Huawei purchases, see: <https://developer.huawei.com/consumer/en/doc/HMSCore-Guides/iap-query-public-key-0000001050034990>
+#[derive(Clone, Debug)]
+pub struct HuaweiCredentials {
+    /// The OAuth 2.0 client id, ie: the app id
+    pub client_id: String,
+    /// The OAuth 2.0 client secret, ie: the app secret
+    pub client_secret: String,
+    /// The IAP public key of the app, used to verify the signature of the purchase data
+    pub public_key: RsaPublicKey,
+}
+
+impl HuaweiCredentials {
+    /// Creates the credentials, `public_key` is the base64 encoded IAP public key shown in AppGallery Connect.
+    /// # Errors
+    /// Will return an error if `public_key` is not a valid base64 encoded RSA public key
+    pub fn new(client_id: String, client_secret: String, public_key: &str) -> Result<Self> {
+        let public_key = RsaPublicKey::from_public_key_der(&STANDARD.decode(public_key.trim())?)
+            .map_err(|err| Error::InvalidPublicKey(err.to_string()))?;
+
+        Ok(Self {
+            client_id,
+            client_secret,
+            public_key,
+        })
+    }
+}
+
+/// The payload of a Unity IAP receipt for purchases made through Huawei AppGallery.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct HuaweiReceiptData {
+    /// Json string containing the `HuaweiPurchaseData`
+    #[serde(rename = "InAppPurchaseData", alias = "inAppPurchaseData")]
+    pub in_app_purchase_data: String,
+    /// Base64 encoded signature of `in_app_purchase_data`
+    #[serde(rename = "InAppDataSignature", alias = "inAppDataSignature")]
+    pub in_app_data_signature: String,
+    /// Either `SHA256WithRSA` (default) or `SHA256WithRSA/PSS`
+    #[serde(rename = "SignatureAlgorithm", alias = "signatureAlgorithm", default)]
+    pub signature_algorithm: Option<String>,
+}
+
+impl HuaweiReceiptData {
+    /// Construct the `HuaweiReceiptData` from the `UnityPurchaseReceipt` payload
+    /// # Errors
+    /// Will return an error if the payload cannot be deserialized
+    pub fn from(payload: &str) -> Result<Self> {
+        Ok(serde_json::from_str(payload)?)
+    }
+
+    /// Parses the `HuaweiPurchaseData` contained in the receipt
+    /// # Errors
+    /// Will return an error if `in_app_purchase_data` cannot be deserialized
+    pub fn get_purchase_data(&self) -> Result<HuaweiPurchaseData> {
+        Ok(serde_json::from_str(&self.in_app_purchase_data)?)
+    }
+
+    /// Verifies the signature of `in_app_purchase_data` with the IAP public key of the app.
+    /// Returns false if the signature is invalid or malformed.
+    #[must_use]
+    pub fn verify_signature(&self, public_key: &RsaPublicKey) -> bool {
+        let Ok(signature) = STANDARD.decode(self.in_app_data_signature.trim()) else {
+            return false;
+        };
+        let data = self.in_app_purchase_data.as_bytes();
+
+        if self.signature_algorithm.as_deref() == Some(HUAWEI_SIGNATURE_ALGORITHM_PSS) {
+            rsa::pss::Signature::try_from(signature.as_slice()).is_ok_and(|signature| {
+                rsa::pss::VerifyingKey::<Sha256>::new(public_key.clone())
+                    .verify(data, &signature)
+                    .is_ok()
+            })
+        } else {
+            rsa::pkcs1v15::Signature::try_from(signature.as_slice()).is_ok_and(|signature| {
+                rsa::pkcs1v15::VerifyingKey::<Sha256>::new(public_key.clone())
+                    .verify(data, &signature)
+                    .is_ok()
+            })
+        }
+    }
+}
+
+/// The `InAppPurchaseData` of a Huawei purchase.
+///
+/// See <https://developer.huawei.com/consumer/en/doc/HMSCore-References/server-data-model-0000001050986133#section264617465219> for more details on each field.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HuaweiPurchaseData {
+    /// Product id
+    pub product_id: Option<String>,
+    /// Order id
+    pub order_id: Option<String>,
+    /// Purchase token, used to query the purchase on the Order and Subscription services
+    pub purchase_token: Option<String>,
+    /// Product type: 0 - consumable, 1 - non-consumable, 2 - subscription
+    pub kind: Option<i32>,
+    /// Order state: -1 - initialized and invisible, 0 - purchased, 1 - cancelled, 2 - refunded
+    pub purchase_state: Option<i32>,
+    /// Time of the purchase, in milliseconds since the Epoch
+    pub purchase_time: Option<i64>,
+    /// Subscription id, only set for subscriptions
+    pub subscription_id: Option<String>,
+    /// Time at which the subscription expires, in milliseconds since the Epoch. Only set for subscriptions.
+    pub expiration_date: Option<i64>,
+    /// Whether the subscription is valid, only set for subscriptions
+    pub sub_isvalid: Option<bool>,
+    /// Whether the subscription will automatically renew
+    pub auto_renewing: Option<bool>,
+    /// Purchase type: 0 - sandbox, 1 - promotion. Not set for normal purchases.
+    pub purchase_type: Option<i32>,
+    /// Quantity purchased
+    pub quantity: Option<i32>,
+}
+
+impl HuaweiPurchaseData {
+    /// Returns true if the purchase is a subscription
+    #[must_use]
+    pub fn is_subscription(&self) -> bool {
+        self.kind == Some(HUAWEI_KIND_SUBSCRIPTION)
+    }
+}
+
+/// Response of the Order service and the Subscription service.
+/// See <https://developer.huawei.com/consumer/en/doc/HMSCore-References/api-order-verify-purchase-token-0000001050746113>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HuaweiResponse {
+    /// Result code, 0 on success
+    pub response_code: String,
+    /// Description of the result
+    pub response_message: Option<String>,
+    /// Json string containing the `HuaweiPurchaseData`, returned by the Order service
+    pub purchase_token_data: Option<String>,
+    /// Json string containing the `HuaweiPurchaseData`, returned by the Subscription service
+    pub inapp_purchase_data: Option<String>,
+    /// Signature of the purchase data
+    pub data_signature: Option<String>,
+}
+
+impl HuaweiResponse {
+    /// Returns true if the `response_code` indicates success
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.response_code == HUAWEI_RESPONSE_CODE_SUCCESS
+    }
+
+    /// Parses the `HuaweiPurchaseData` contained in the response
+    /// # Errors
+    /// Will return an error if no purchase data is set or it cannot be deserialized
+    pub fn get_purchase_data(&self) -> Result<HuaweiPurchaseData> {
+        let data = self
+            .purchase_token_data
+            .as_ref()
+            .or(self.inapp_purchase_data.as_ref())
+            .ok_or_else(|| {
+                Error::Custom("huawei response contains no purchase data".to_string())
+            })?;
+
+        Ok(serde_json::from_str(data)?)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HuaweiOrderRequest<'a> {
+    purchase_token: &'a str,
+    product_id: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HuaweiSubscriptionRequest<'a> {
+    subscription_id: &'a str,
+    purchase_token: &'a str,
+}
+
+#[derive(Deserialize)]
+struct HuaweiTokenResponse {
+    access_token: String,
+    /// Seconds until the access token expires
+    #[serde(default)]
+    expires_in: u64,
+}
+
+/// Retrieves the purchase from the Huawei Order service, or the Subscription service for subscriptions,
+/// after verifying the signature of the purchase data.
+/// # Errors
+/// Will return an error if the `payload` in the `UnityPurchaseReceipt` is malformed or its signature is invalid,
+/// if authentication fails, or if the endpoint responds with an error.
+pub async fn fetch_huawei_receipt_data(
+    receipt: &UnityPurchaseReceipt,
+    credentials: &HuaweiCredentials,
+    urls: &HuaweiUrls<'_>,
+) -> Result<HuaweiResponse> {
+    let data = HuaweiReceiptData::from(&receipt.payload)?;
+    if !data.verify_signature(&credentials.public_key) {
+        return Err(Error::InvalidSignature);
+    }
+
+    fetch_huawei_purchase(&data.get_purchase_data()?, credentials, urls).await
+}
+
+/// Queries the Order service, or the Subscription service for subscriptions, for the purchase.
+/// Does not verify any signature.
+/// # Errors
+/// Will return an error if the purchase token is missing, if authentication fails, or if the endpoint responds with an error.
+pub async fn fetch_huawei_purchase(
+    purchase: &HuaweiPurchaseData,
+    credentials: &HuaweiCredentials,
+    urls: &HuaweiUrls<'_>,
+) -> Result<HuaweiResponse> {
+    fetch_huawei_purchase_with_clients(&SharedClients::default(), purchase, credentials, urls).await
+}
+
+/// Same as `fetch_huawei_purchase`, with the http client and access token of `clients`.
+/// # Errors
+/// Will return an error if the purchase token is missing, if authentication fails, or if the endpoint responds with an error.
+pub async fn fetch_huawei_purchase_with_clients(
+    clients: &SharedClients,
+    purchase: &HuaweiPurchaseData,
+    credentials: &HuaweiCredentials,
+    urls: &HuaweiUrls<'_>,
+) -> Result<HuaweiResponse> {
+    let purchase_token = purchase
+        .purchase_token
+        .as_deref()
+        .ok_or_else(|| Error::Custom("huawei purchase data has no purchase token".to_string()))?;
+
+    let (uri, request_body) = if purchase.is_subscription() {
+        (
+            format!("{}/sub/applications/v2/purchases/get", urls.subscription),
+            serde_json::to_string(&HuaweiSubscriptionRequest {
+                subscription_id: purchase.subscription_id.as_deref().unwrap_or_default(),
+                purchase_token,
+            })?,
+        )
+    } else {
+        (
+            format!("{}/applications/purchases/tokens/verify", urls.order),
+            serde_json::to_string(&HuaweiOrderRequest {
+                purchase_token,
+                product_id: purchase.product_id.as_deref().unwrap_or_default(),
+            })?,
+        )
+    };
+
+    let access_token = clients.huawei_token(credentials, urls).await?;
+
+    let req = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("Content-Type", "application/json; charset=UTF-8")
+        .header(
+            "Authorization",
+            format!("Basic {}", STANDARD.encode(format!("APPAT:{access_token}"))),
+        )
+        .body(Body::from(request_body))?;
+
+    let resp = clients.client().request(req).await?;
+    let status = resp.status();
+    let buf = body::to_bytes(resp).await?;
+
+    tracing::debug!(
+        status = %status,
+        response_body = %redact(&String::from_utf8_lossy(&buf).replace('\n', "")),
+        "huawei response"
+    );
+
+    let response = if status.is_success() {
+        serde_json::from_slice::<HuaweiResponse>(&buf)?
+    } else {
+        HuaweiResponse {
+            response_message: Some(String::from_utf8_lossy(&buf).into_owned()),
+            ..HuaweiResponse::default()
+        }
+    };
+
+    if !status.is_success() || response.response_code == HUAWEI_RESPONSE_CODE_INTERNAL_ERROR {
+        return Err(Error::HuaweiApiError {
+            status,
+            response_code: response.response_code,
+            message: response.response_message,
+        });
+    }
+
+    tracing::info!(target = "huawei_response",
+        product_id = ?purchase.product_id,
+        is_subscription = %purchase.is_subscription(),
+        response_code = %response.response_code,
+        response_message = ?response.response_message,
+    );
+
+    Ok(response)
+}
+
+/// Requests an access token with the OAuth client credentials, returns the token and how long it is valid for
+pub async fn fetch_access_token(
+    client: &HttpsClient,
+    credentials: &HuaweiCredentials,
+    urls: &HuaweiUrls<'_>,
+) -> Result<(String, Duration)> {
+    let request_body = format!(
+        "grant_type=client_credentials&client_id={}&client_secret={}",
+        percent_encoding::utf8_percent_encode(
+            &credentials.client_id,
+            percent_encoding::NON_ALPHANUMERIC
+        ),
+        percent_encoding::utf8_percent_encode(
+            &credentials.client_secret,
+            percent_encoding::NON_ALPHANUMERIC
+        ),
+    );
+
+    let req = Request::builder()
+        .method("POST")
+        .uri(urls.oauth)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(Body::from(request_body))?;
+
+    let resp = client.request(req).await?;
+    let status = resp.status();
+    let buf = body::to_bytes(resp).await?;
+
+    if !status.is_success() {
+        return Err(Error::HuaweiApiError {
+            status,
+            response_code: String::new(),
+            message: Some(String::from_utf8_lossy(&buf).into_owned()),
+        });
+    }
+
+    let token = serde_json::from_slice::<HuaweiTokenResponse>(&buf)?;
+    Ok((token.access_token, Duration::from_secs(token.expires_in)))
+}
+
+/// Validates a Huawei purchase. Subscriptions are valid if they have not expired,
+/// all other purchases are valid if they are in the purchased state.
+#[must_use]
+pub fn validate_huawei_purchase(
+    purchase: &HuaweiPurchaseData,
+    now: DateTime<Utc>,
+) -> PurchaseResponse {
+    let (valid, expiry_time) = if purchase.is_subscription() {
+        let expiry_time = purchase
+            .expiration_date
+            .and_then(|millis| Utc.timestamp_millis_opt(millis).single());
+        (
+            purchase.sub_isvalid.unwrap_or(true)
+                && expiry_time.is_some_and(|expiry_time| expiry_time > now),
+            expiry_time,
+        )
+    } else {
+        (purchase.purchase_state == Some(0), None)
+    };
+
+    tracing::info!(
+        valid,
+        now = %now,
+        order_id = ?purchase.order_id.as_deref().map(redact),
+        expiration_date = ?purchase.expiration_date,
+        "huawei receipt verification"
+    );
+
+    PurchaseResponse {
+        valid,
+        product_id: purchase.product_id.clone(),
+        original_transaction_id: purchase.purchase_token.clone(),
+        expiry_time,
+        ..PurchaseResponse::default()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{InvalidReason, Platform, PurchaseVerdict, UnityPurchaseValidator, Validator};
+    use mockito::mock;
+    use rsa::{
+        pkcs8::EncodePublicKey, rand_core::OsRng, signature::RandomizedSigner,
+        signature::SignatureEncoding, RsaPrivateKey,
+    };
+    use serial_test::serial;
+
+    fn sign(private_key: &RsaPrivateKey, purchase_data: &str) -> HuaweiReceiptData {
+        let signing_key = rsa::pkcs1v15::SigningKey::<Sha256>::new(private_key.clone());
+        let signature = signing_key.sign_with_rng(&mut OsRng, purchase_data.as_bytes());
+
+        HuaweiReceiptData {
+            in_app_purchase_data: purchase_data.to_string(),
+            in_app_data_signature: STANDARD.encode(signature.to_bytes()),
+            signature_algorithm: None,
+        }
+    }
+
+    fn encoded_public_key(private_key: &RsaPrivateKey) -> String {
+        STANDARD.encode(
+            RsaPublicKey::from(private_key)
+                .to_public_key_der()
+                .unwrap()
+                .as_bytes(),
+        )
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let private_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let credentials = HuaweiCredentials::new(
+            "id".to_string(),
+            "secret".to_string(),
+            &encoded_public_key(&private_key),
+        )
+        .unwrap();
+
+        let mut data = sign(
+            &private_key,
+            r#"{"productId": "prod", "purchaseToken": "token", "kind": 0, "purchaseState": 0}"#,
+        );
+
+        assert!(data.verify_signature(&credentials.public_key));
+        assert!(validate_huawei_purchase(&data.get_purchase_data().unwrap(), Utc::now()).valid);
+
+        data.in_app_purchase_data = data.in_app_purchase_data.replace("prod", "other");
+        assert!(!data.verify_signature(&credentials.public_key));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_huawei_subscription() {
+        let private_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let expiry = (Utc::now() + chrono::Duration::days(1)).timestamp_millis();
+        let purchase_data = format!(
+            r#"{{"productId": "prod", "purchaseToken": "token", "subscriptionId": "sub", "kind": 2, "expirationDate": {expiry}, "subIsvalid": true}}"#
+        );
+
+        let oauth_mock = mock("POST", "/oauth")
+            .with_status(200)
+            .with_body(r#"{"access_token": "access", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+        let _sub = mock("POST", "/sub/applications/v2/purchases/get")
+            .match_header("Authorization", "Basic QVBQQVQ6YWNjZXNz")
+            .with_status(200)
+            .with_body(
+                serde_json::to_string(&HuaweiResponse {
+                    response_code: "0".to_string(),
+                    inapp_purchase_data: Some(purchase_data.clone()),
+                    ..HuaweiResponse::default()
+                })
+                .unwrap(),
+            )
+            .create();
+
+        let url = mockito::server_url();
+        let oauth = format!("{url}/oauth");
+        let validator = UnityPurchaseValidator::default()
+            .set_huawei_credentials(
+                "id".to_string(),
+                "secret".to_string(),
+                &encoded_public_key(&private_key),
+            )
+            .unwrap()
+            .set_huawei_urls(HuaweiUrls {
+                order: &url,
+                subscription: &url,
+                oauth: &oauth,
+            });
+
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::HuaweiAppGallery,
+            payload: serde_json::to_string(&sign(&private_key, &purchase_data)).unwrap(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        let response = validator.validate(Utc::now(), &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("prod".to_string()));
+
+        // the access token is reused until it expires
+        assert!(
+            validator
+                .validate(Utc::now(), &receipt)
+                .await
+                .unwrap()
+                .valid
+        );
+        oauth_mock.assert();
+
+        let tampered = UnityPurchaseReceipt {
+            payload: serde_json::to_string(&HuaweiReceiptData {
+                in_app_purchase_data: purchase_data.replace("prod", "other"),
+                ..sign(&private_key, &purchase_data)
+            })
+            .unwrap(),
+            ..receipt
+        };
+
+        assert!(matches!(
+            validator.verdict(Utc::now(), &tampered).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::InvalidSignature,
+                ..
+            }
+        ));
+    }
+}
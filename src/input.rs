@@ -0,0 +1,172 @@
+use super::{
+    google::GooglePlayData, storekit::AppleTransaction, Platform, SkuType, UnityPurchaseReceipt,
+};
+
+/// Store-agnostic input for validation, for purchases which were not made through Unity IAP.
+///
+/// Every `UnityPurchaseReceipt` converts into a `PurchaseInput`, see `Validator::verdict_input`.
+#[derive(Clone, Debug)]
+pub enum PurchaseInput {
+    /// A bare base64 encoded App Store receipt, as read from `Bundle.main.appStoreReceiptURL`,
+    /// validated through the verifyReceipt endpoint
+    AppleReceipt {
+        /// The base64 encoded receipt data
+        receipt_data: String,
+        /// The id of the transaction to validate
+        transaction_id: String,
+    },
+    /// A StoreKit 2 signed transaction (JWS), as returned by `VerificationResult.jwsRepresentation`,
+    /// validated through the App Store Server API
+    AppleSignedTransaction(String),
+    /// A Google Play purchase, as returned by the Play Billing Library
+    GooglePlay {
+        /// The package name of the app
+        package_name: String,
+        /// The product id of the purchased product or subscription
+        product_id: String,
+        /// The purchase token
+        purchase_token: String,
+        /// Whether the product is a subscription or an in-app product
+        sku_type: SkuType,
+    },
+    /// Any other Unity IAP receipt
+    Unity(UnityPurchaseReceipt),
+}
+
+impl PurchaseInput {
+    /// The store on which the purchase was made
+    #[must_use]
+    pub const fn store(&self) -> Platform {
+        match self {
+            Self::AppleReceipt { .. } | Self::AppleSignedTransaction(_) => Platform::AppleAppStore,
+            Self::GooglePlay { .. } => Platform::GooglePlay,
+            Self::Unity(receipt) => receipt.store,
+        }
+    }
+
+    /// The base64 encoded receipt and the transaction id of App Store receipts, `None` for other inputs
+    pub(crate) fn apple_receipt(&self) -> Option<(&str, &str)> {
+        match self {
+            Self::AppleReceipt {
+                receipt_data,
+                transaction_id,
+            } => Some((receipt_data, transaction_id)),
+            Self::Unity(receipt) if receipt.store == Platform::AppleAppStore => {
+                Some((&receipt.payload, &receipt.transaction_id))
+            }
+            _ => None,
+        }
+    }
+
+    /// The receipt the purchase is validated from: the receipt data, signed transaction, purchase token or payload
+    pub(crate) fn payload(&self) -> &str {
+        match self {
+            Self::AppleReceipt { receipt_data, .. } => receipt_data,
+            Self::AppleSignedTransaction(jws) => jws,
+            Self::GooglePlay { purchase_token, .. } => purchase_token,
+            Self::Unity(receipt) => &receipt.payload,
+        }
+    }
+
+    /// The id identifying the purchase in its store: the transaction id, or the purchase token for Google Play
+    /// purchases. `None` if the input does not contain one.
+    pub(crate) fn transaction_key(&self) -> Option<String> {
+        let transaction_id = match self {
+            Self::AppleReceipt { transaction_id, .. } => transaction_id.clone(),
+            Self::AppleSignedTransaction(jws) => {
+                AppleTransaction::from_unverified_jws(jws)
+                    .ok()?
+                    .transaction_id?
+            }
+            Self::GooglePlay { purchase_token, .. } => purchase_token.clone(),
+            Self::Unity(receipt) => receipt.transaction_id.clone(),
+        };
+
+        (!transaction_id.is_empty()).then_some(transaction_id)
+    }
+}
+
+impl From<UnityPurchaseReceipt> for PurchaseInput {
+    /// Apple and Google receipts are converted into their store specific inputs. Google receipts with a
+    /// malformed payload or with multiple products are kept as `Unity`, so validation can report them as
+    /// malformed or validate each product.
+    fn from(receipt: UnityPurchaseReceipt) -> Self {
+        match receipt.store {
+            Platform::AppleAppStore => Self::AppleReceipt {
+                receipt_data: receipt.payload,
+                transaction_id: receipt.transaction_id,
+            },
+            Platform::GooglePlay => {
+                let input = GooglePlayData::from(&receipt.payload).and_then(|data| {
+                    let sku_type = data.get_sku_details()?.sku_type;
+                    let parameters = data.get_parameters()?;
+                    let mut product_ids = parameters.product_ids();
+                    Ok((product_ids.len() == 1).then(|| Self::GooglePlay {
+                        package_name: parameters.package_name,
+                        product_id: product_ids.remove(0),
+                        purchase_token: parameters.token,
+                        sku_type,
+                    }))
+                });
+
+                input.ok().flatten().unwrap_or(Self::Unity(receipt))
+            }
+            _ => Self::Unity(receipt),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_unity_receipt() {
+        let apple = UnityPurchaseReceipt {
+            store: Platform::AppleAppStore,
+            payload: "receipt".to_string(),
+            transaction_id: "txn".to_string(),
+        };
+        assert!(matches!(
+            PurchaseInput::from(apple),
+            PurchaseInput::AppleReceipt { receipt_data, transaction_id }
+                if receipt_data == "receipt" && transaction_id == "txn"
+        ));
+
+        let json = serde_json::json!({
+            "packageName": "com.example.app",
+            "productId": "sub",
+            "purchaseToken": "token",
+            "acknowledged": false,
+            "purchaseTime": 1_611_752_122_282_i64,
+            "orderId": "GPA.1234",
+            "purchaseState": 0,
+        });
+        let google = UnityPurchaseReceipt {
+            store: Platform::GooglePlay,
+            payload: serde_json::json!({
+                "json": json.to_string(),
+                "signature": "",
+                "skuDetails": r#"{"type": "subs"}"#,
+            })
+            .to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+        assert!(matches!(
+            PurchaseInput::from(google),
+            PurchaseInput::GooglePlay { sku_type: SkuType::Subs, purchase_token, .. }
+                if purchase_token == "token"
+        ));
+
+        let malformed = UnityPurchaseReceipt {
+            store: Platform::GooglePlay,
+            payload: "{}".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+        assert!(matches!(
+            PurchaseInput::from(malformed),
+            PurchaseInput::Unity(_)
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{error::Result, Platform, PurchaseVerdict};
+use async_trait::async_trait;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Mutex,
+};
+
+/// A consumable purchase granted to a user
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumableGrant {
+    /// The store on which the purchase was made
+    pub store: Platform,
+    /// Apple's `transaction_id` or Google's `orderId`
+    pub transaction_id: String,
+    /// The product id of the purchase
+    pub product_id: Option<String>,
+    /// The number of items purchased
+    pub quantity: u32,
+    /// The user the purchase was granted to
+    pub user_id: String,
+}
+
+/// The result of `UnityPurchaseValidator::redeem_consumable`
+#[derive(Debug)]
+pub enum ConsumableRedemption {
+    /// The purchase is valid and was granted now, the items should be delivered to the user
+    Granted(ConsumableGrant),
+    /// The purchase was granted before, the items must not be delivered again
+    AlreadyGranted(ConsumableGrant),
+    /// The purchase could not be granted, see the verdict for the reason
+    NotGranted(PurchaseVerdict),
+}
+
+impl ConsumableRedemption {
+    /// Returns true if the purchase was granted by this redemption
+    #[must_use]
+    pub const fn is_granted(&self) -> bool {
+        matches!(self, Self::Granted(_))
+    }
+}
+
+/// Storage for granted consumables, used to grant each consumable purchase exactly once.
+/// Grants are identified by the store and the transaction id.
+#[async_trait]
+pub trait ConsumableLedger: Send + Sync {
+    /// Records the grant, unless the transaction was already granted before.
+    /// This has to be atomic, returns the earlier grant if there is one, `None` if `grant` was recorded.
+    async fn record(&self, grant: &ConsumableGrant) -> Result<Option<ConsumableGrant>>;
+}
+
+/// `ConsumableLedger` which keeps the grants in memory. Useful for tests or single instance deployments.
+#[derive(Default, Debug)]
+pub struct InMemoryConsumableLedger {
+    grants: Mutex<HashMap<(Platform, String), ConsumableGrant>>,
+}
+
+#[async_trait]
+impl ConsumableLedger for InMemoryConsumableLedger {
+    async fn record(&self, grant: &ConsumableGrant) -> Result<Option<ConsumableGrant>> {
+        match self
+            .grants
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .entry((grant.store, grant.transaction_id.clone()))
+        {
+            Entry::Occupied(existing) => Ok(Some(existing.get().clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(grant.clone());
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteConsumableLedger;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{ConsumableGrant, ConsumableLedger, Result};
+    use crate::sqlite::{store_key, SharedConnection};
+    use async_trait::async_trait;
+    use rusqlite::{params, Connection};
+    use std::path::Path;
+
+    /// `ConsumableLedger` backed by a `SQLite` database. Requires the `sqlite` feature.
+    pub struct SqliteConsumableLedger {
+        connection: SharedConnection,
+    }
+
+    impl SqliteConsumableLedger {
+        /// Opens (or creates) the database at `path` and creates the `iap_consumable_grants` table if it does not exist.
+        /// # Errors
+        /// Will return an error if the database cannot be opened or the table cannot be created
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            Self::new(Connection::open(path)?)
+        }
+
+        /// Creates the ledger from an existing connection, creating the `iap_consumable_grants` table if it does
+        /// not exist.
+        /// # Errors
+        /// Will return an error if the table cannot be created
+        pub fn new(connection: Connection) -> Result<Self> {
+            connection.execute(
+                "CREATE TABLE IF NOT EXISTS iap_consumable_grants (
+                    store TEXT NOT NULL,
+                    transaction_id TEXT NOT NULL,
+                    product_id TEXT,
+                    quantity INTEGER NOT NULL,
+                    user_id TEXT NOT NULL,
+                    granted_at INTEGER NOT NULL,
+                    PRIMARY KEY (store, transaction_id)
+                )",
+                [],
+            )?;
+
+            Ok(Self {
+                connection: SharedConnection::new(connection),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl ConsumableLedger for SqliteConsumableLedger {
+        async fn record(&self, grant: &ConsumableGrant) -> Result<Option<ConsumableGrant>> {
+            let grant = grant.clone();
+
+            self.connection
+                .call(move |connection| {
+                    let store = store_key(grant.store);
+                    let inserted = connection.execute(
+                        "INSERT OR IGNORE INTO iap_consumable_grants (store, transaction_id, product_id, quantity, user_id, granted_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            store,
+                            grant.transaction_id,
+                            grant.product_id,
+                            grant.quantity,
+                            grant.user_id,
+                            chrono::Utc::now().timestamp()
+                        ],
+                    )?;
+
+                    if inserted > 0 {
+                        return Ok(None);
+                    }
+
+                    Ok(Some(connection.query_row(
+                        "SELECT product_id, quantity, user_id FROM iap_consumable_grants WHERE store = ?1 AND transaction_id = ?2",
+                        params![store, grant.transaction_id],
+                        |row| {
+                            Ok(ConsumableGrant {
+                                store: grant.store,
+                                transaction_id: grant.transaction_id.clone(),
+                                product_id: row.get(0)?,
+                                quantity: row.get(1)?,
+                                user_id: row.get(2)?,
+                            })
+                        },
+                    )?))
+                })
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn grant(store: Platform, user_id: &str) -> ConsumableGrant {
+        ConsumableGrant {
+            store,
+            transaction_id: "txn".to_string(),
+            product_id: Some("coins_100".to_string()),
+            quantity: 2,
+            user_id: user_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_record() {
+        let ledger = InMemoryConsumableLedger::default();
+        let first = grant(Platform::AppleAppStore, "user1");
+
+        assert_eq!(ledger.record(&first).await.unwrap(), None);
+        assert_eq!(
+            ledger
+                .record(&grant(Platform::AppleAppStore, "user2"))
+                .await
+                .unwrap(),
+            Some(first)
+        );
+        assert_eq!(
+            ledger
+                .record(&grant(Platform::GooglePlay, "user2"))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_record() {
+        let ledger =
+            SqliteConsumableLedger::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        let first = grant(Platform::GooglePlay, "user1");
+
+        assert_eq!(ledger.record(&first).await.unwrap(), None);
+        assert_eq!(
+            ledger
+                .record(&grant(Platform::GooglePlay, "user2"))
+                .await
+                .unwrap(),
+            Some(first)
+        );
+    }
+}
@@ -3,13 +3,31 @@
 //! ## Current Features
 //! - Validating receipt data received from [Unity's IAP plugin](https://docs.unity3d.com/Manual/UnityIAP.html) to verify subscriptions and if they are valid and not expired
 //! - Helper functions to receive response data from Google/Apple for more granular error handling or validation
+//! - Validating Amazon Appstore purchases through the Receipt Verification Service
+//! - Validating Huawei AppGallery purchases, verifying the purchase data signature and querying the Order and Subscription services
+//! - Validating Microsoft Store durables, consumables and subscriptions through the Store collections and recurrence services
+//! - Validating and finalizing Steam microtransactions, including subscriptions through recurring billing agreements
+//! - Validating Samsung Galaxy Store purchases through the Galaxy Store receipt verification
+//! - Validating purchases not received through Unity IAP: bare App Store receipts, StoreKit 2 signed transactions and Google Play purchase tokens (see `PurchaseInput`)
+//! - Parsing purchases sent by Flutter's `in_app_purchase` and `react-native-iap` clients into a `PurchaseInput`, on both iOS and Android (see `FlutterPurchaseDetails` and `ReactNativePurchase`)
+//! - Mapping products onto the entitlements they grant with a `ProductCatalog` loaded from TOML or JSON, see `PurchaseResponse::entitlements`
+//! - Merging the purchases of a user across all stores into their current entitlements (see `EntitlementAggregator`)
+//! - Subscription lifecycle events (started, renewed, grace period, billing retry, expired, refunded, upgraded, ...) from stored and fresh store responses (see `SubscriptionSnapshot`)
+//! - Apple non-renewing subscriptions, expiring after the `duration` configured in the `ProductCatalog` (see `validate_apple_non_renewing`)
+//! - Idempotent consumable grants, recording each granted purchase in a ledger (see `UnityPurchaseValidator::redeem_consumable`)
+//! - Batch validation with a concurrency limit, fetching each App Store receipt once (see `Validator::verdict_many`)
+//! - Validation of all transactions of an App Store receipt, ie: to restore purchases (see `UnityPurchaseValidator::apple_transaction_verdicts`)
+//! - Rate limiting of the calls to the Google Play Developer API (see `GoogleRateLimiter`)
+//! - Failing fast during store outages with a per-store circuit breaker (see `CircuitBreaker`)
+//! - Honoring the last valid result of a purchase while its store cannot be reached (see `OfflineFallback`)
+//! - Redacting receipt payloads, purchase tokens, transaction ids and store responses from the logs, hashed by default (see `set_redaction_level` and `REDACTED_FIELDS`)
+//! - Replay protection, refusing the redemption of the same purchase by multiple users (see `UnityPurchaseValidator::redeem`)
 //!
 //! ### Supported Transaction Types
 //! - Subscriptions
-//!
-//! ### Coming Features
-//! - Non-subscription purchase types
-//! - Manual input of data for verification not received through Unity IAP
+//! - Non-renewing subscriptions (App Store, with a `ProductCatalog`)
+//! - Consumables
+//! - Non-consumables
 //!
 //! ## Usage
 //!
@@ -41,6 +59,44 @@
 //! }
 //! ```
 //!
+//! `validate` returns an error whenever the validation could not be completed. To tell apart a purchase that
+//! should be denied from one that should be retried later, use `Validator::verdict` instead:
+//!
+//! ```ignore
+//! match validator.verdict(chrono::Utc::now(), &unity_receipt).await {
+//!     PurchaseVerdict::Valid(response) => println!("valid: {:?}", response.product_id),
+//!     PurchaseVerdict::Invalid { reason, .. } => println!("deny purchase: {:?}", reason),
+//!     PurchaseVerdict::Indeterminate(err) => println!("retry later: {}", err.is_retryable()),
+//!     PurchaseVerdict::Stale { validated_at, .. } => println!("store unreachable, valid at: {}", validated_at),
+//! }
+//! ```
+//!
+//! ### For purchases not made through Unity IAP
+//! Native apps can send the purchase data directly, which is validated with `Validator::validate_input`.
+//! StoreKit 2 signed transactions require the App Store Server API credentials.
+//!
+//! ```ignore
+//! let validator = UnityPurchaseValidator::default()
+//!     .set_google_service_account_key(GOOGLE_KEY.to_string())?
+//!     .set_app_store_server_credentials(AppStoreServerCredentials::new(
+//!         "<ISSUER ID>".to_string(),
+//!         "<KEY ID>".to_string(),
+//!         "<BUNDLE ID>".to_string(),
+//!         APP_STORE_PRIVATE_KEY,
+//!     )?);
+//!
+//! let input = PurchaseInput::GooglePlay {
+//!     package_name: "com.example.app".to_string(),
+//!     product_id: "monthly".to_string(),
+//!     purchase_token: "<PURCHASE TOKEN>".to_string(),
+//!     sku_type: SkuType::Subs,
+//! };
+//! let response = validator.validate_input(chrono::Utc::now(), &input).await?;
+//!
+//! let input = PurchaseInput::AppleSignedTransaction("<JWS>".to_string());
+//! let response = validator.validate_input(chrono::Utc::now(), &input).await?;
+//! ```
+//!
 //! If you wanted more granular control and access to the response from the store's endpoint, we provide helper functions to do so.
 //!
 //! For the Play Store:
@@ -83,39 +139,135 @@
 //TODO: remove once async_trait works with this again
 #![allow(clippy::no_effect_underscore_binding)]
 
+mod adapters;
+mod amazon;
 mod apple;
+mod cache;
+mod catalog;
+mod circuit_breaker;
+mod clients;
+mod entitlements;
+mod fallback;
 mod google;
+mod huawei;
+mod input;
+mod ledger;
+mod lifecycle;
+mod microsoft;
+mod rate_limit;
+mod redact;
+mod samsung;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod steam;
+mod storekit;
+mod transaction_store;
+mod verdict;
 
 pub mod error;
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use clients::HttpsClient;
 use error::Result;
+use futures::{
+    future::join_all,
+    stream::{self, StreamExt},
+};
+use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 use yup_oauth2::ServiceAccountKey;
 
+pub use adapters::{FlutterPurchaseDetails, FlutterVerificationData, ReactNativePurchase};
+pub use amazon::{
+    fetch_amazon_receipt_data, fetch_amazon_receipt_data_with_urls, validate_amazon_purchase,
+    AmazonReceiptData, AmazonResponse, AmazonUrls,
+};
 pub use apple::{
-    fetch_apple_receipt_data, fetch_apple_receipt_data_with_urls, validate_apple_package,
-    validate_apple_subscription, AppleResponse, AppleUrls,
+    fetch_apple_receipt_data, fetch_apple_receipt_data_with_urls, validate_apple_in_app,
+    validate_apple_non_renewing, validate_apple_package, validate_apple_subscription,
+    AppleResponse, AppleUrls,
+};
+pub use cache::CachedValidator;
+pub use catalog::{Product, ProductCatalog, ProductDuration, ProductType};
+pub use circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+pub use clients::SharedClients;
+pub use entitlements::{
+    Entitlement, EntitlementAggregator, EntitlementConflict, EntitlementSet, EntitlementSource,
 };
+#[cfg(feature = "sqlite")]
+pub use fallback::SqliteSnapshotStore;
+pub use fallback::{InMemorySnapshotStore, OfflineFallback, SnapshotStore, ValidationSnapshot};
 pub use google::{
-    fetch_google_receipt_data, fetch_google_receipt_data_with_uri, validate_google_package,
-    validate_google_subscription, GoogleResponse, SkuType,
+    fetch_google_receipt_data, fetch_google_receipt_data_with_uri, google_purchase_uri,
+    validate_google_package, validate_google_subscription, GoogleResponse, GoogleUrls, SkuType,
 };
+pub use huawei::{
+    fetch_huawei_purchase, fetch_huawei_purchase_with_clients, fetch_huawei_receipt_data,
+    validate_huawei_purchase, HuaweiCredentials, HuaweiPurchaseData, HuaweiReceiptData,
+    HuaweiResponse, HuaweiUrls,
+};
+pub use input::PurchaseInput;
+#[cfg(feature = "sqlite")]
+pub use ledger::SqliteConsumableLedger;
+pub use ledger::{
+    ConsumableGrant, ConsumableLedger, ConsumableRedemption, InMemoryConsumableLedger,
+};
+pub use lifecycle::{SubscriptionEvent, SubscriptionSnapshot, SubscriptionState};
+pub use microsoft::{
+    consume_microsoft_purchase, fetch_microsoft_receipt_data, validate_microsoft_purchase,
+    MicrosoftCollectionItem, MicrosoftCredentials, MicrosoftReceiptData, MicrosoftRecurrenceItem,
+    MicrosoftResponse, MicrosoftUrls,
+};
+pub use rate_limit::{GoogleQuotas, GoogleRateLimiter, RateLimitBudget, RateLimitMetrics};
+pub use redact::{redaction_level, set_redaction_level, RedactionLevel, REDACTED_FIELDS};
+pub use samsung::{
+    fetch_samsung_receipt_data, validate_samsung_purchase, SamsungReceiptData, SamsungResponse,
+    SamsungUrls,
+};
+pub use steam::{
+    fetch_steam_agreements, fetch_steam_order, finalize_steam_order, validate_steam_agreement,
+    validate_steam_order, SteamAgreement, SteamAgreements, SteamCredentials, SteamError,
+    SteamFinalizedOrder, SteamOrder, SteamOrderItem, SteamReceiptData, SteamResponse, SteamUrls,
+};
+pub use storekit::{
+    fetch_apple_transaction, validate_apple_transaction, AppStoreServerCredentials,
+    AppStoreServerUrls, AppleTransaction,
+};
+#[cfg(feature = "sqlite")]
+pub use transaction_store::SqliteTransactionStore;
+pub use transaction_store::{InMemoryTransactionStore, TransactionStore};
+pub use verdict::{InvalidReason, PurchaseVerdict};
 
 /// This is the platform on which the purchase that created the unity receipt was made.
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Default, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Platform {
     /// iOS and macOS
+    #[default]
     AppleAppStore,
     /// Android
     GooglePlay,
-}
-
-impl Default for Platform {
-    fn default() -> Self {
-        Self::AppleAppStore
-    }
+    /// Amazon Appstore, reported by Unity IAP as `AmazonApps`
+    #[serde(rename = "AmazonApps", alias = "AmazonAppStore")]
+    AmazonAppStore,
+    /// Huawei AppGallery
+    HuaweiAppGallery,
+    /// Microsoft Store. Unity IAP's `WinRT` receipts are the XML app receipt and not supported, the client has to send
+    /// a receipt with `"Store": "MicrosoftStore"` and the Store ID keys as payload, see `MicrosoftReceiptData`.
+    MicrosoftStore,
+    /// Steam microtransactions
+    Steam,
+    /// Samsung Galaxy Store, reported by Unity IAP as `SamsungApps`
+    #[serde(rename = "SamsungApps", alias = "SamsungGalaxyStore")]
+    SamsungGalaxyStore,
+    /// Unity IAP's FakeStore, used in the editor and in development builds. Only accepted in dev mode,
+    /// see `UnityPurchaseValidator::set_fake_store_response`.
+    #[serde(rename = "fake", alias = "FakeStore")]
+    FakeStore,
 }
 
 /// Represents the deserialized contents of the Json string delivered by Unity IAP.
@@ -149,17 +301,96 @@ pub struct PurchaseResponse {
     pub valid: bool,
     /// Product identifier
     pub product_id: Option<String>,
+    /// Identifier which stays the same across restores and renewals of a purchase: Apple's `original_transaction_id`,
+    /// or the purchase token on Google Play.
+    pub original_transaction_id: Option<String>,
+    /// Time at which the subscription expires, only set for subscriptions.
+    pub expiry_time: Option<DateTime<Utc>>,
+    /// The entitlements granted by the purchase, according to the `ProductCatalog` of the validator.
+    #[serde(default)]
+    pub entitlements: Vec<String>,
 }
 
 /// The base trait for implementing a validator. Mock Validators can be made for running local tests by implementing this trait.
 #[async_trait]
 pub trait Validator: Send + Sync {
-    /// Called to perform the validation on whichever platform is described in the provided UnityPurchaseReceipt.
+    /// Called to perform the validation on whichever platform is described in the provided `UnityPurchaseReceipt`.
     async fn validate(
         &self,
         now: DateTime<Utc>,
         receipt: &UnityPurchaseReceipt,
     ) -> Result<PurchaseResponse>;
+
+    /// Same as `validate`, but returns a `PurchaseVerdict` which distinguishes receipts rejected by the store
+    /// from validations that could not be completed. By default the verdict is derived from the result of `validate`.
+    async fn verdict(&self, now: DateTime<Utc>, receipt: &UnityPurchaseReceipt) -> PurchaseVerdict {
+        PurchaseVerdict::from(self.validate(now, receipt).await)
+    }
+
+    /// Same as `validate`, for purchases which were not necessarily made through Unity IAP.
+    async fn validate_input(
+        &self,
+        now: DateTime<Utc>,
+        input: &PurchaseInput,
+    ) -> Result<PurchaseResponse> {
+        self.verdict_input(now, input).await.into_result()
+    }
+
+    /// Same as `verdict`, for purchases which were not necessarily made through Unity IAP. By default Unity IAP
+    /// receipts and bare Apple receipts are passed on to `verdict`, all other inputs are `Indeterminate`.
+    async fn verdict_input(&self, now: DateTime<Utc>, input: &PurchaseInput) -> PurchaseVerdict {
+        match input {
+            PurchaseInput::Unity(receipt) => self.verdict(now, receipt).await,
+            PurchaseInput::AppleReceipt {
+                receipt_data,
+                transaction_id,
+            } => {
+                let receipt = UnityPurchaseReceipt {
+                    store: Platform::AppleAppStore,
+                    payload: receipt_data.clone(),
+                    transaction_id: transaction_id.clone(),
+                };
+                self.verdict(now, &receipt).await
+            }
+            _ => PurchaseVerdict::Indeterminate(error::Error::Custom(format!(
+                "purchase input not supported by this validator, store: {:?}",
+                input.store()
+            ))),
+        }
+    }
+
+    /// Same as `verdict_input` for a batch of purchases, ie: when revalidating all purchases of a user at login.
+    /// At most `concurrency` validations run at the same time, the verdicts are in the order of `inputs`.
+    async fn verdict_many(
+        &self,
+        now: DateTime<Utc>,
+        inputs: &[PurchaseInput],
+        concurrency: usize,
+    ) -> Vec<PurchaseVerdict> {
+        let verdicts = inputs
+            .iter()
+            .map(|input| self.verdict_input(now, input))
+            .collect::<Vec<_>>();
+
+        stream::iter(verdicts)
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Same as `validate_input` for a batch of purchases, see `verdict_many`.
+    async fn validate_many(
+        &self,
+        now: DateTime<Utc>,
+        inputs: &[PurchaseInput],
+        concurrency: usize,
+    ) -> Vec<Result<PurchaseResponse>> {
+        self.verdict_many(now, inputs, concurrency)
+            .await
+            .into_iter()
+            .map(PurchaseVerdict::into_result)
+            .collect()
+    }
 }
 
 /// Trait which allows us to retrieve receipt data from an object's own secrets.
@@ -190,6 +421,7 @@ pub trait ReceiptValidator: ReceiptDataFetcher + Validator {}
 ///     .set_google_service_account_key("<GOOGLE_KEY>".to_string());
 /// ```
 #[derive(Default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct UnityPurchaseValidator<'a> {
     /// Apple's shared secret required by their requestBody. See: <https://developer.apple.com/documentation/appstorereceipts/requestbody>
     pub secret: Option<String>,
@@ -197,11 +429,63 @@ pub struct UnityPurchaseValidator<'a> {
     pub apple_urls: AppleUrls<'a>,
     /// The service account key required for Google's authentication.
     pub service_account_key: Option<ServiceAccountKey>,
+    /// Should always be default unless we are using mock urls for offline unit tests.
+    pub google_urls: GoogleUrls<'a>,
+    /// The `SkuType` of Google Play products, used when a receipt carries no `skuDetails`.
+    pub google_sku_types: HashMap<String, SkuType>,
+    /// The products of the app, used to return the entitlements granted by valid purchases.
+    pub product_catalog: Option<ProductCatalog>,
+    /// The shared secret of the Amazon Receipt Verification Service.
+    pub amazon_secret: Option<String>,
+    /// Should always be default unless we are using mock urls for offline unit tests.
+    pub amazon_urls: AmazonUrls<'a>,
+    /// Whether Amazon receipts are verified against the RVS sandbox, which accepts App Tester receipts.
+    pub amazon_sandbox: bool,
+    /// The credentials required for the Huawei IAP server APIs.
+    pub huawei_credentials: Option<HuaweiCredentials>,
+    /// The site urls of the Huawei IAP server APIs, should be set to the sites of the region the app is distributed in.
+    pub huawei_urls: HuaweiUrls<'a>,
+    /// The Azure AD credentials required for the Microsoft Store services.
+    pub microsoft_credentials: Option<MicrosoftCredentials>,
+    /// Should always be default unless we are using mock urls for offline unit tests.
+    pub microsoft_urls: MicrosoftUrls<'a>,
+    /// Should always be default unless we are using mock urls for offline unit tests.
+    pub samsung_urls: SamsungUrls<'a>,
+    /// The package name of the app on the Galaxy Store, purchases of other apps are rejected.
+    pub samsung_package_name: Option<String>,
+    /// Whether Galaxy Store test purchases (`mode` `TEST`) are accepted.
+    pub samsung_test_mode: bool,
+    /// The Web API key and app id required for Steam microtransactions.
+    pub steam_credentials: Option<SteamCredentials>,
+    /// Should always be default unless we are using mock urls for offline unit tests.
+    pub steam_urls: SteamUrls<'a>,
+    /// Whether Steam orders are queried through the `ISteamMicroTxnSandbox` interface.
+    pub steam_sandbox: bool,
+    /// The canned result returned for FakeStore receipts. Dev mode is enabled if set, FakeStore receipts are rejected otherwise.
+    pub fake_store_response: Option<PurchaseResponse>,
+    /// The in-app purchase key required for the App Store Server API, used to validate StoreKit 2 transactions.
+    pub app_store_server_credentials: Option<AppStoreServerCredentials>,
+    /// Should always be default unless we are using mock urls for offline unit tests.
+    pub app_store_server_urls: AppStoreServerUrls<'a>,
+    /// Whether StoreKit 2 transactions are looked up in the sandbox environment of the App Store Server API.
+    pub app_store_server_sandbox: bool,
+    /// Storage of redeemed transactions used by `redeem` for replay protection.
+    pub transaction_store: Option<Arc<dyn TransactionStore>>,
+    /// Storage of granted consumables used by `redeem_consumable` to grant each purchase once.
+    pub consumable_ledger: Option<Arc<dyn ConsumableLedger>>,
+    /// Rate limiter keeping the calls to the Google Play Developer API within the quotas of the app.
+    pub google_rate_limiter: Option<Arc<GoogleRateLimiter>>,
+    /// Circuit breaker short-circuiting the calls to stores which keep failing.
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Fallback policy honoring the last valid result of a purchase while its store cannot be reached.
+    pub offline_fallback: Option<OfflineFallback>,
+    /// The http client and Google access token shared by all validations.
+    pub clients: SharedClients,
 }
 
 impl ReceiptValidator for UnityPurchaseValidator<'_> {}
 
-impl UnityPurchaseValidator<'_> {
+impl<'a> UnityPurchaseValidator<'a> {
     /// Stores Apple's shared secret required by their requestBody. See: <https://developer.apple.com/documentation/appstorereceipts/requestbody>
     #[allow(clippy::missing_const_for_fn)]
     #[must_use]
@@ -229,173 +513,1707 @@ impl UnityPurchaseValidator<'_> {
         new.service_account_key = Some(google::get_service_account_key(secret)?);
         Ok(new)
     }
-}
 
-#[async_trait]
-impl Validator for UnityPurchaseValidator<'_> {
-    async fn validate(
+    /// Stores the `SkuType` of each Google Play product id. Used for receipts whose `skuDetails` are missing or
+    /// malformed, ie: restored purchases. Products not listed are looked up as a subscription first, then as an
+    /// in-app product if no such subscription is found.
+    #[must_use]
+    pub fn set_google_sku_types(self, sku_types: HashMap<String, SkuType>) -> Self {
+        tracing::info!("Setting google sku types, products: {}", sku_types.len());
+        let mut new = self;
+        new.google_sku_types = sku_types;
+        new
+    }
+
+    /// Stores the `ProductCatalog`, valid purchases then carry the entitlements their product grants in
+    /// `PurchaseResponse::entitlements`. The catalog is also used to infer the `SkuType` of Google Play products,
+    /// and for the durations of Apple non-renewing subscriptions.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_product_catalog(self, catalog: ProductCatalog) -> Self {
+        tracing::info!(
+            "Setting product catalog, products: {}",
+            catalog.products.len()
+        );
+        let mut new = self;
+        new.product_catalog = Some(catalog);
+        new
+    }
+
+    /// Stores the App Store Server API credentials required for validating StoreKit 2 signed transactions.
+    /// See: <https://developer.apple.com/documentation/appstoreserverapi/creating_api_keys_to_use_with_the_app_store_server_api>
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_app_store_server_credentials(self, credentials: AppStoreServerCredentials) -> Self {
+        tracing::info!(
+            "Setting app store server credentials, key_id: {}",
+            credentials.key_id
+        );
+        let mut new = self;
+        new.app_store_server_credentials = Some(credentials);
+        new
+    }
+
+    /// Looks StoreKit 2 transactions up in the sandbox environment of the App Store Server API instead of production,
+    /// for purchases made with sandbox accounts or in Xcode. Sandbox purchases are never paid for, never enable it in
+    /// production.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_app_store_server_sandbox(self, sandbox: bool) -> Self {
+        tracing::info!("Setting app store server sandbox: {}", sandbox);
+        let mut new = self;
+        new.app_store_server_sandbox = sandbox;
+        new
+    }
+
+    /// Stores the shared secret of the Amazon Receipt Verification Service. See: <https://developer.amazon.com/docs/in-app-purchasing/iap-rvs-for-android-apps.html>
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_amazon_secret(self, secret: String) -> Self {
+        tracing::info!("Setting amazon secret");
+        let mut new = self;
+        new.amazon_secret = Some(secret);
+        new
+    }
+
+    /// Verifies Amazon receipts against the RVS sandbox instead of production, for purchases made with the App Tester.
+    /// The sandbox accepts receipts which were never paid for, never enable it in production.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_amazon_sandbox(self, sandbox: bool) -> Self {
+        tracing::info!("Setting amazon sandbox: {}", sandbox);
+        let mut new = self;
+        new.amazon_sandbox = sandbox;
+        new
+    }
+
+    /// Stores the credentials required for validating Huawei AppGallery purchases. `public_key` is the base64 encoded
+    /// IAP public key of the app. See: <https://developer.huawei.com/consumer/en/doc/HMSCore-Guides/iap-query-public-key-0000001050034990>
+    /// # Errors
+    /// Will return an error if `public_key` is not a valid RSA public key
+    #[allow(clippy::must_use_candidate)]
+    pub fn set_huawei_credentials(
+        self,
+        client_id: String,
+        client_secret: String,
+        public_key: &str,
+    ) -> Result<Self> {
+        let mut new = self;
+        new.huawei_credentials = Some(HuaweiCredentials::new(
+            client_id,
+            client_secret,
+            public_key,
+        )?);
+        Ok(new)
+    }
+
+    /// Stores the site urls of the Huawei IAP server APIs, ie: `HuaweiUrls::china()`
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_huawei_urls(self, huawei_urls: HuaweiUrls<'a>) -> Self {
+        let mut new = self;
+        new.huawei_urls = huawei_urls;
+        new
+    }
+
+    /// Stores the Azure AD credentials required for validating Microsoft Store purchases.
+    /// See: <https://learn.microsoft.com/en-us/windows/uwp/monetize/view-and-grant-products-from-a-service>
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_microsoft_credentials(self, credentials: MicrosoftCredentials) -> Self {
+        tracing::info!("Setting microsoft credentials");
+        let mut new = self;
+        new.microsoft_credentials = Some(credentials);
+        new
+    }
+
+    /// Reports the quantity of a Microsoft Store consumable as fulfilled, after which it can be purchased again.
+    /// Retrying with the same unique `tracking_id` does not consume the item twice.
+    /// # Errors
+    /// Will return an error if no microsoft credentials are set, if the receipt payload is malformed or if the request fails.
+    pub async fn fulfill_microsoft_consumable(
         &self,
-        now: DateTime<Utc>,
         receipt: &UnityPurchaseReceipt,
+        tracking_id: &str,
+        quantity: i64,
     ) -> Result<PurchaseResponse> {
-        tracing::debug!(
-            "store: {:?}, transaction_id: {}, payload: {}",
-            receipt.store,
-            &receipt.transaction_id,
-            &receipt.payload,
-        );
-
-        match receipt.store {
-            Platform::AppleAppStore => {
-                let response = apple::fetch_apple_receipt_data_with_urls(
+        let credentials = self
+            .microsoft_credentials
+            .as_ref()
+            .ok_or_else(|| error::Error::NotConfigured("no microsoft credentials have been set"))?;
+
+        let item = self
+            .guarded(
+                Platform::MicrosoftStore,
+                consume_microsoft_purchase(
                     receipt,
-                    &self.apple_urls,
-                    self.secret.as_ref(),
-                )
-                .await?;
-
-                if response.status == 0 {
-                    if response.is_subscription(&receipt.transaction_id) {
-                        Ok(validate_apple_subscription(
-                            &response,
-                            &receipt.transaction_id,
-                            now,
-                        ))
-                    } else {
-                        Ok(validate_apple_package(&response, &receipt.transaction_id))
-                    }
-                } else {
-                    Ok(PurchaseResponse {
-                        valid: false,
-                        product_id: response.get_product_id(&receipt.transaction_id),
-                    })
-                }
-            }
-            Platform::GooglePlay => {
-                //TODO: clean all of this up if async move evey makes its way to rust stable
-                if let Ok((Ok(response_future), sku_type)) =
-                    google::GooglePlayData::from(&receipt.payload).and_then(|data| {
-                        data.get_sku_details().map(|sku_details| {
-                            let sku_type = sku_details.sku_type;
-                            (
-                                data.get_uri(&sku_type).map(|uri| {
-                                    fetch_google_receipt_data_with_uri(
-                                        self.service_account_key.as_ref(),
-                                        uri,
-                                        Some(data),
-                                    )
-                                }),
-                                sku_type,
-                            )
-                        })
-                    })
-                {
-                    if let Ok(response) = response_future.await {
-                        match sku_type {
-                            google::SkuType::Subs => validate_google_subscription(&response, now),
-                            google::SkuType::Inapp => Ok(validate_google_package(&response)),
-                        }
-                    } else {
-                        Ok(PurchaseResponse {
-                            valid: false,
-                            product_id: None,
-                        })
-                    }
-                } else {
-                    //TODO:
-                    Ok(PurchaseResponse {
-                        valid: false,
-                        product_id: None,
-                    })
-                }
-            }
+                    credentials,
+                    &self.microsoft_urls,
+                    tracking_id,
+                    quantity,
+                ),
+            )
+            .await?;
+
+        Ok(PurchaseResponse {
+            valid: true,
+            product_id: item.product_id,
+            original_transaction_id: item.transaction_id.or(item.order_id),
+            ..PurchaseResponse::default()
+        })
+    }
+
+    /// Stores the package name of the app, required for validating Galaxy Store purchases. The receipt verification
+    /// is unauthenticated, so the package name is what keeps purchases of other apps from validating.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_samsung_package_name(self, package_name: String) -> Self {
+        tracing::info!("Setting samsung package name: {}", package_name);
+        let mut new = self;
+        new.samsung_package_name = Some(package_name);
+        new
+    }
+
+    /// Accepts Galaxy Store test purchases, made by license testers or in the IAP test mode. Test purchases are never
+    /// paid for, never enable it in production.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_samsung_test_mode(self, test_mode: bool) -> Self {
+        tracing::info!("Setting samsung test mode: {}", test_mode);
+        let mut new = self;
+        new.samsung_test_mode = test_mode;
+        new
+    }
+
+    /// Stores the publisher Web API key and the app id required for validating Steam microtransactions.
+    /// See: <https://partner.steamgames.com/doc/features/microtransactions/implementation>
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_steam_credentials(self, key: String, app_id: u32) -> Self {
+        tracing::info!("Setting steam credentials, app_id: {}", app_id);
+        let mut new = self;
+        new.steam_credentials = Some(SteamCredentials { key, app_id });
+        new
+    }
+
+    /// Queries and finalizes Steam orders through the `ISteamMicroTxnSandbox` interface instead of `ISteamMicroTxn`.
+    /// Sandbox orders are never charged, never enable it in production.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_steam_sandbox(self, sandbox: bool) -> Self {
+        tracing::info!("Setting steam sandbox: {}", sandbox);
+        let mut new = self;
+        new.steam_sandbox = sandbox;
+        new
+    }
+
+    /// Finalizes a Steam order the user has approved with `FinalizeTxn`, then validates it.
+    /// Orders which cannot be finalized, ie: because the user did not approve them, are `Invalid`
+    /// with the Steam error code as `InvalidReason::StoreStatus`.
+    pub async fn finalize_steam_order(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let (credentials, data) = match self.steam_receipt_data(receipt) {
+            Ok(result) => result,
+            Err(verdict) => return verdict,
+        };
+
+        match self
+            .guarded(
+                Platform::Steam,
+                steam::finalize_steam_order(
+                    &data,
+                    credentials,
+                    &self.steam_urls,
+                    self.steam_sandbox,
+                ),
+            )
+            .await
+        {
+            Ok(response) if !response.is_success() => PurchaseVerdict::Invalid {
+                reason: InvalidReason::StoreStatus(
+                    response
+                        .error
+                        .map(|error| error.error_code)
+                        .unwrap_or_default(),
+                ),
+                product_id: None,
+            },
+            Ok(_) => self.with_entitlements(self.steam_verdict(now, receipt).await),
+            Err(err) => PurchaseVerdict::from_error(err),
         }
     }
-}
 
-#[async_trait]
-impl ReceiptDataFetcher for UnityPurchaseValidator<'_> {
-    async fn fetch_apple_receipt_data(
+    /// Validates every transaction of an App Store receipt, from both `receipt.in_app` and `latest_receipt_info`,
+    /// returning the verdicts of the transactions per product id, sorted by purchase date.
+    ///
+    /// This restores all purchases of a user in one call, including non-consumables bought years ago. Expired
+    /// renewals of a subscription are `Invalid` even if the subscription is active, check its latest transaction.
+    /// # Errors
+    /// Will return an error if the receipt could not be fetched, or if Apple rejected the receipt as a whole.
+    pub async fn apple_transaction_verdicts(
         &self,
+        now: DateTime<Utc>,
         receipt: &UnityPurchaseReceipt,
-    ) -> Result<AppleResponse> {
-        fetch_apple_receipt_data_with_urls(receipt, &self.apple_urls, self.secret.as_ref()).await
+    ) -> Result<BTreeMap<String, Vec<(String, PurchaseVerdict)>>> {
+        let response = self.fetch_apple_receipt(receipt).await?;
+
+        if response.status != 0 {
+            return Err(error::Error::AppleStatusError {
+                status: response.status,
+                is_retryable: response.is_retryable,
+            });
+        }
+
+        let mut verdicts = BTreeMap::<String, Vec<(String, PurchaseVerdict)>>::new();
+
+        for transaction in response.get_transactions() {
+            let (Some(product_id), Some(transaction_id)) = (
+                transaction.product_id.clone(),
+                transaction.transaction_id.clone(),
+            ) else {
+                continue;
+            };
+
+            let verdict = self.apple_transaction_verdict(now, &response, &transaction);
+            verdicts
+                .entry(product_id)
+                .or_default()
+                .push((transaction_id, self.with_entitlements(verdict)));
+        }
+
+        Ok(verdicts)
     }
 
-    async fn fetch_google_receipt_data(
+    /// Validates each product of a Google Play purchase separately, returning the verdict per product id.
+    /// Purchases made with Play Billing Library 5+ may contain several products.
+    /// # Errors
+    /// Will return an error if the `payload` of the receipt is malformed.
+    pub async fn google_product_verdicts(
         &self,
+        now: DateTime<Utc>,
         receipt: &UnityPurchaseReceipt,
-    ) -> Result<(GoogleResponse, SkuType)> {
+    ) -> Result<Vec<(String, PurchaseVerdict)>> {
         let data = google::GooglePlayData::from(&receipt.payload)?;
-        let sku_type = data.get_sku_details()?.sku_type;
-        fetch_google_receipt_data_with_uri(
-            self.service_account_key.as_ref(),
-            data.get_uri(&sku_type)?,
-            Some(data),
-        )
-        .await
-        .map(|response| (response, sku_type))
+        let parameters = data.get_parameters()?;
+        parameters.first_product_id()?;
+
+        let sku_type = match data.get_sku_details() {
+            Ok(details) => Some(details.sku_type),
+            Err(err) => {
+                tracing::info!(
+                    "google sku details missing or malformed, inferring sku type: {}",
+                    err
+                );
+                None
+            }
+        };
+
+        let mut verdicts = Vec::new();
+        for product_id in parameters.product_ids() {
+            let verdict = self
+                .google_purchase_verdict(
+                    now,
+                    sku_type,
+                    &parameters.package_name,
+                    product_id.clone(),
+                    parameters.token.clone(),
+                )
+                .await;
+            verdicts.push((product_id, self.with_entitlements(verdict)));
+        }
+
+        Ok(verdicts)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        apple::{AppleInAppReceipt, AppleReceipt, AppleResponse},
-        google::{validate_google_subscription, GoogleResponse},
-    };
-    use chrono::{Duration, Utc};
-    use mockito::mock;
-    use serial_test::serial;
+    /// Enables dev mode, in which receipts of Unity IAP's FakeStore are accepted and validated with the canned `response`.
+    /// The `original_transaction_id` defaults to the transaction id of the receipt.
+    /// Never enable this in production, anyone can create FakeStore receipts.
+    #[allow(clippy::missing_const_for_fn)]
+    #[must_use]
+    pub fn set_fake_store_response(self, response: PurchaseResponse) -> Self {
+        tracing::warn!(
+            "FakeStore dev mode enabled, FakeStore receipts will be accepted without validation. Never use this in production!"
+        );
+        let mut new = self;
+        new.fake_store_response = Some(response);
+        new
+    }
 
-    fn new_for_test<'a>(prod_url: &'a str, sandbox_url: &'a str) -> UnityPurchaseValidator<'a> {
-        UnityPurchaseValidator {
-            secret: Some(String::from("secret")),
-            apple_urls: AppleUrls {
-                production: prod_url,
-                sandbox: sandbox_url,
-            },
-            service_account_key: None,
-        }
+    /// Stores the `TransactionStore` used by `redeem` to record which user redeemed which transaction.
+    #[must_use]
+    pub fn set_transaction_store(self, transaction_store: Arc<dyn TransactionStore>) -> Self {
+        let mut new = self;
+        new.transaction_store = Some(transaction_store);
+        new
     }
 
-    #[tokio::test]
-    #[serial]
-    async fn test_sandbox_response() {
-        let expiry = (Utc::now() + Duration::days(1))
-            .timestamp_millis()
-            .to_string();
-        let apple_response = AppleResponse {
-            receipt: Some(AppleReceipt {
-                in_app: Some(vec![AppleInAppReceipt {
-                    product_id: Some("prod".to_string()),
-                    expires_date_ms: Some(expiry),
-                    transaction_id: Some("txn".to_string()),
-                    ..AppleInAppReceipt::default()
-                }]),
-                ..AppleReceipt::default()
-            }),
-            ..AppleResponse::default()
+    /// Validates the receipt like `Validator::verdict`, and additionally records the redemption of a valid purchase
+    /// by `user_id` in the `TransactionStore`. If the purchase was already redeemed by a different user, the verdict
+    /// is `Invalid` with `InvalidReason::AlreadyRedeemed`. Redeeming the same purchase again by the same user
+    /// (ie: when restoring purchases) is valid.
+    pub async fn redeem(
+        &self,
+        now: DateTime<Utc>,
+        user_id: &str,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let Some(transaction_store) = self.transaction_store.as_ref() else {
+            return PurchaseVerdict::Indeterminate(error::Error::NotConfigured(
+                "no transaction store has been set",
+            ));
         };
 
-        let _m1 = mock("POST", "/sb/verifyReceipt")
-            .with_status(200)
-            .with_body(&serde_json::to_string(&apple_response).unwrap())
-            .create();
-
-        let _m2 = mock("POST", "/verifyReceipt")
-            .with_status(200)
-            .with_body(r#"{"status": 21007}"#)
-            .create();
+        let response = match self.verdict(now, receipt).await {
+            PurchaseVerdict::Valid(response) => response,
+            verdict => return verdict,
+        };
 
-        let url = &mockito::server_url();
+        let Some(transaction_id) = response.original_transaction_id.as_deref() else {
+            tracing::warn!("valid purchase without original transaction id, cannot redeem");
+            return PurchaseVerdict::Invalid {
+                reason: InvalidReason::TransactionNotFound,
+                product_id: response.product_id,
+            };
+        };
 
-        let sandbox = format!("{}/sb", url);
-        let validator = new_for_test(url, &sandbox);
+        match transaction_store
+            .redeem(receipt.store, transaction_id, user_id)
+            .await
+        {
+            Ok(owner) if owner == user_id => PurchaseVerdict::Valid(response),
+            Ok(owner) => {
+                tracing::warn!(
+                    "transaction already redeemed by another user, store: {:?}, user_id: {}, redeemed_by: {}",
+                    receipt.store,
+                    user_id,
+                    owner
+                );
+                PurchaseVerdict::Invalid {
+                    reason: InvalidReason::AlreadyRedeemed { user_id: owner },
+                    product_id: response.product_id,
+                }
+            }
+            Err(err) => PurchaseVerdict::Indeterminate(err),
+        }
+    }
 
-        let response = validator
-            .validate(
+    /// Stores the `ConsumableLedger` used by `redeem_consumable` to record which consumables were granted.
+    #[must_use]
+    pub fn set_consumable_ledger(self, consumable_ledger: Arc<dyn ConsumableLedger>) -> Self {
+        let mut new = self;
+        new.consumable_ledger = Some(consumable_ledger);
+        new
+    }
+
+    /// Stores the `GoogleRateLimiter` applied to all calls to the Google Play Developer API. Share the same
+    /// limiter between validators of the same app, as the quotas apply to the app.
+    #[must_use]
+    pub fn set_google_rate_limiter(self, google_rate_limiter: Arc<GoogleRateLimiter>) -> Self {
+        let mut new = self;
+        new.google_rate_limiter = Some(google_rate_limiter);
+        new
+    }
+
+    /// Stores the `CircuitBreaker` applied to all calls to the stores. While the circuit of a store is open, its
+    /// validations are `Indeterminate` with a retryable `CircuitOpen` error, without calling the store.
+    #[must_use]
+    pub fn set_circuit_breaker(self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        let mut new = self;
+        new.circuit_breaker = Some(circuit_breaker);
+        new
+    }
+
+    /// Stores the `OfflineFallback` applied to all validations. Valid results are recorded, and validations which
+    /// fail because the store cannot be reached return the last valid result as a `Stale` verdict.
+    #[must_use]
+    pub fn set_offline_fallback(self, offline_fallback: OfflineFallback) -> Self {
+        let mut new = self;
+        new.offline_fallback = Some(offline_fallback);
+        new
+    }
+
+    /// Validates a consumable purchase on the App Store or Google Play with `validate_apple_package` or
+    /// `validate_google_package`, and records the grant of its transaction id (Google's order id) and quantity to
+    /// `user_id` in the `ConsumableLedger`.
+    ///
+    /// Redeeming the same purchase again returns `ConsumableRedemption::AlreadyGranted` with the earlier grant, so
+    /// the items are delivered exactly once, even when the client retries.
+    pub async fn redeem_consumable(
+        &self,
+        user_id: &str,
+        receipt: &UnityPurchaseReceipt,
+    ) -> ConsumableRedemption {
+        let Some(consumable_ledger) = self.consumable_ledger.as_ref() else {
+            return ConsumableRedemption::NotGranted(PurchaseVerdict::Indeterminate(
+                error::Error::NotConfigured("no consumable ledger has been set"),
+            ));
+        };
+
+        let grant = match receipt.store {
+            Platform::AppleAppStore => self.apple_consumable_grant(user_id, receipt).await,
+            Platform::GooglePlay => self.google_consumable_grant(user_id, receipt).await,
+            store => Err(PurchaseVerdict::Indeterminate(error::Error::Custom(
+                format!("consumable redemption is not supported for {store:?}"),
+            ))),
+        };
+
+        let grant = match grant {
+            Ok(grant) => grant,
+            Err(verdict) => return ConsumableRedemption::NotGranted(verdict),
+        };
+
+        match consumable_ledger.record(&grant).await {
+            Ok(None) => {
+                tracing::info!(
+                    store = ?grant.store,
+                    transaction_id = %redact::redact(&grant.transaction_id),
+                    quantity = grant.quantity,
+                    user_id = %grant.user_id,
+                    "consumable granted"
+                );
+                ConsumableRedemption::Granted(grant)
+            }
+            Ok(Some(existing)) => {
+                tracing::warn!(
+                    store = ?existing.store,
+                    transaction_id = %redact::redact(&existing.transaction_id),
+                    user_id = %user_id,
+                    granted_to = %existing.user_id,
+                    "consumable already granted"
+                );
+                ConsumableRedemption::AlreadyGranted(existing)
+            }
+            Err(err) => ConsumableRedemption::NotGranted(PurchaseVerdict::Indeterminate(err)),
+        }
+    }
+}
+
+impl UnityPurchaseValidator<'_> {
+    /// The verdicts of the `inputs` at the `batch` indices, which are either a single input or the transactions of
+    /// the same App Store receipt. If the receipt cannot be fetched, the other transactions are fetched on their own
+    /// so that each verdict carries its own error.
+    async fn batch_verdicts(
+        &self,
+        now: DateTime<Utc>,
+        inputs: &[PurchaseInput],
+        batch: Vec<usize>,
+    ) -> Vec<(usize, PurchaseVerdict)> {
+        let mut verdicts = Vec::with_capacity(batch.len());
+        let mut indices = batch.into_iter();
+
+        let Some(first) = indices.next() else {
+            return verdicts;
+        };
+
+        let Some((receipt_data, transaction_id)) =
+            inputs[first].apple_receipt().filter(|_| indices.len() > 0)
+        else {
+            verdicts.push((first, self.input_verdict(now, &inputs[first]).await));
+            return verdicts;
+        };
+
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::AppleAppStore,
+            payload: receipt_data.to_string(),
+            transaction_id: transaction_id.to_string(),
+        };
+
+        match self.fetch_apple_receipt(&receipt).await {
+            Ok(response) => {
+                tracing::debug!(
+                    "apple receipt fetched once for {} transactions",
+                    indices.len() + 1
+                );
+                for index in std::iter::once(first).chain(indices) {
+                    let transaction_id = inputs[index]
+                        .apple_receipt()
+                        .map_or(transaction_id, |(_, transaction_id)| transaction_id);
+                    let verdict = self.apple_response_verdict(now, &response, transaction_id);
+                    verdicts.push((index, self.with_entitlements(verdict)));
+                }
+            }
+            Err(err) => {
+                let err = Arc::new(err);
+                for index in std::iter::once(first).chain(indices) {
+                    let verdict =
+                        PurchaseVerdict::from_error(error::Error::Shared(Arc::clone(&err)));
+                    verdicts.push((index, verdict));
+                }
+            }
+        }
+
+        verdicts
+    }
+
+    async fn apple_consumable_grant(
+        &self,
+        user_id: &str,
+        receipt: &UnityPurchaseReceipt,
+    ) -> std::result::Result<ConsumableGrant, PurchaseVerdict> {
+        let response = self
+            .fetch_apple_receipt(receipt)
+            .await
+            .map_err(PurchaseVerdict::from_error)?;
+
+        let transaction_id = &receipt.transaction_id;
+
+        if response.status != 0 {
+            return Err(PurchaseVerdict::Invalid {
+                reason: InvalidReason::StoreStatus(response.status),
+                product_id: response.get_product_id(transaction_id),
+            });
+        }
+
+        let result = validate_apple_package(&response, transaction_id);
+        if !result.valid {
+            return Err(PurchaseVerdict::Invalid {
+                reason: InvalidReason::TransactionNotFound,
+                product_id: result.product_id,
+            });
+        }
+
+        let transaction = response.get_receipt(transaction_id);
+        if transaction
+            .as_ref()
+            .is_some_and(|transaction| transaction.cancellation_date_ms.is_some())
+        {
+            return Err(PurchaseVerdict::Invalid {
+                reason: InvalidReason::NotPurchased,
+                product_id: result.product_id,
+            });
+        }
+
+        let quantity = transaction
+            .and_then(|receipt| receipt.quantity)
+            .and_then(|quantity| quantity.parse().ok())
+            .unwrap_or(1);
+
+        Ok(ConsumableGrant {
+            store: Platform::AppleAppStore,
+            transaction_id: transaction_id.clone(),
+            product_id: result.product_id,
+            quantity,
+            user_id: user_id.to_string(),
+        })
+    }
+
+    async fn google_consumable_grant(
+        &self,
+        user_id: &str,
+        receipt: &UnityPurchaseReceipt,
+    ) -> std::result::Result<ConsumableGrant, PurchaseVerdict> {
+        let malformed = |err: error::Error| {
+            tracing::warn!("malformed google receipt payload: {}", err);
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt(err.to_string()),
+                product_id: None,
+            }
+        };
+
+        let parameters = google::GooglePlayData::from(&receipt.payload)
+            .and_then(|data| data.get_parameters())
+            .map_err(malformed)?;
+        let product_id = parameters
+            .first_product_id()
+            .map_err(malformed)?
+            .to_string();
+
+        let (mut response, _) = self
+            .fetch_google_purchase(
+                Some(SkuType::Inapp),
+                &parameters.package_name,
+                &product_id,
+                &parameters.token,
+            )
+            .await
+            .map_err(PurchaseVerdict::from_error)?;
+        response.product_id.get_or_insert(product_id);
+
+        let result = validate_google_package(&response);
+        if !result.valid {
+            return Err(PurchaseVerdict::Invalid {
+                reason: InvalidReason::NotPurchased,
+                product_id: result.product_id,
+            });
+        }
+
+        Ok(ConsumableGrant {
+            store: Platform::GooglePlay,
+            transaction_id: response.order_id,
+            product_id: result.product_id,
+            quantity: response.quantity.or(parameters.quantity).unwrap_or(1),
+            user_id: user_id.to_string(),
+        })
+    }
+
+    async fn apple_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let response = match self.fetch_apple_receipt(receipt).await {
+            Ok(response) => response,
+            Err(err) => return PurchaseVerdict::from_error(err),
+        };
+
+        self.apple_response_verdict(now, &response, &receipt.transaction_id)
+    }
+
+    /// The verdict for a single transaction of `AppleResponse::get_transactions`
+    fn apple_transaction_verdict(
+        &self,
+        now: DateTime<Utc>,
+        response: &AppleResponse,
+        transaction: &apple::AppleInAppReceipt,
+    ) -> PurchaseVerdict {
+        let non_renewing = self.product_catalog.as_ref().filter(|catalog| {
+            transaction
+                .product_id
+                .as_deref()
+                .and_then(|product_id| catalog.product(product_id))
+                .is_some_and(|product| product.product_type == ProductType::NonRenewing)
+        });
+
+        let result = match (non_renewing, transaction.transaction_id.as_deref()) {
+            (Some(catalog), Some(transaction_id)) if transaction.cancellation_date_ms.is_none() => {
+                match validate_apple_non_renewing(response, transaction_id, catalog, now) {
+                    Ok(result) => result,
+                    Err(err) => return PurchaseVerdict::Indeterminate(err),
+                }
+            }
+            _ => validate_apple_in_app(response, transaction, now),
+        };
+
+        if result.valid {
+            return PurchaseVerdict::Valid(result);
+        }
+
+        let reason = if transaction.cancellation_date_ms.is_some() {
+            InvalidReason::NotPurchased
+        } else if result.product_id.is_some() {
+            InvalidReason::Expired
+        } else {
+            InvalidReason::TransactionNotFound
+        };
+
+        PurchaseVerdict::Invalid {
+            reason,
+            product_id: result.product_id,
+        }
+    }
+
+    /// The verdict for `transaction_id` in the already fetched receipt `response`
+    fn apple_response_verdict(
+        &self,
+        now: DateTime<Utc>,
+        response: &AppleResponse,
+        transaction_id: &str,
+    ) -> PurchaseVerdict {
+        if response.status != 0 {
+            return PurchaseVerdict::Invalid {
+                reason: InvalidReason::StoreStatus(response.status),
+                product_id: response.get_product_id(transaction_id),
+            };
+        }
+
+        if let Some(catalog) = self.product_catalog.as_ref().filter(|catalog| {
+            response
+                .get_product_id(transaction_id)
+                .and_then(|product_id| catalog.product(&product_id))
+                .is_some_and(|product| product.product_type == ProductType::NonRenewing)
+        }) {
+            return match validate_apple_non_renewing(response, transaction_id, catalog, now) {
+                Ok(result) if result.valid => PurchaseVerdict::Valid(result),
+                Ok(result) => {
+                    let refunded = response
+                        .get_receipt(transaction_id)
+                        .is_some_and(|transaction| transaction.cancellation_date_ms.is_some());
+                    PurchaseVerdict::Invalid {
+                        reason: if refunded {
+                            InvalidReason::NotPurchased
+                        } else {
+                            InvalidReason::Expired
+                        },
+                        product_id: result.product_id,
+                    }
+                }
+                Err(err) => PurchaseVerdict::Indeterminate(err),
+            };
+        }
+
+        if response.is_subscription(transaction_id) {
+            let result = validate_apple_subscription(response, transaction_id, now);
+            if result.valid {
+                PurchaseVerdict::Valid(result)
+            } else {
+                let reason = if response.get_latest_receipt().is_some() {
+                    InvalidReason::Expired
+                } else {
+                    InvalidReason::TransactionNotFound
+                };
+                PurchaseVerdict::Invalid {
+                    reason,
+                    product_id: result.product_id,
+                }
+            }
+        } else {
+            let result = validate_apple_package(response, transaction_id);
+            if result.valid {
+                PurchaseVerdict::Valid(result)
+            } else {
+                PurchaseVerdict::Invalid {
+                    reason: InvalidReason::TransactionNotFound,
+                    product_id: result.product_id,
+                }
+            }
+        }
+    }
+
+    async fn amazon_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        if let Err(err) = AmazonReceiptData::from(&receipt.payload) {
+            tracing::warn!("malformed amazon receipt payload: {}", err);
+            return PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt(err.to_string()),
+                product_id: None,
+            };
+        }
+
+        let response = match self
+            .guarded(
+                Platform::AmazonAppStore,
+                amazon::fetch_amazon_receipt_data_with_urls(
+                    receipt,
+                    &self.amazon_urls,
+                    self.amazon_secret.as_ref(),
+                    self.amazon_sandbox,
+                ),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => return PurchaseVerdict::from_error(err),
+        };
+
+        let result = validate_amazon_purchase(&response, now);
+        if result.valid {
+            PurchaseVerdict::Valid(result)
+        } else {
+            PurchaseVerdict::Invalid {
+                reason: if response.is_subscription() {
+                    InvalidReason::Expired
+                } else {
+                    InvalidReason::NotPurchased
+                },
+                product_id: result.product_id,
+            }
+        }
+    }
+
+    async fn huawei_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let Some(credentials) = self.huawei_credentials.as_ref() else {
+            return PurchaseVerdict::Indeterminate(error::Error::NotConfigured(
+                "no huawei credentials have been set",
+            ));
+        };
+
+        let (data, purchase) = match HuaweiReceiptData::from(&receipt.payload)
+            .and_then(|data| data.get_purchase_data().map(|purchase| (data, purchase)))
+        {
+            Ok(result) => result,
+            Err(err) => {
+                tracing::warn!("malformed huawei receipt payload: {}", err);
+                return PurchaseVerdict::Invalid {
+                    reason: InvalidReason::MalformedReceipt(err.to_string()),
+                    product_id: None,
+                };
+            }
+        };
+
+        if !data.verify_signature(&credentials.public_key) {
+            tracing::warn!(
+                order_id = ?purchase.order_id.as_deref().map(redact::redact),
+                "invalid huawei purchase data signature"
+            );
+            return PurchaseVerdict::Invalid {
+                reason: InvalidReason::InvalidSignature,
+                product_id: purchase.product_id,
+            };
+        }
+
+        let response = match self
+            .guarded(
+                Platform::HuaweiAppGallery,
+                huawei::fetch_huawei_purchase_with_clients(
+                    &self.clients,
+                    &purchase,
+                    credentials,
+                    &self.huawei_urls,
+                ),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => return PurchaseVerdict::from_error(err),
+        };
+
+        if !response.is_success() {
+            return match response.response_code.parse() {
+                Ok(response_code) => PurchaseVerdict::Invalid {
+                    reason: InvalidReason::StoreStatus(response_code),
+                    product_id: purchase.product_id,
+                },
+                Err(err) => PurchaseVerdict::Indeterminate(err.into()),
+            };
+        }
+
+        let purchase = match response.get_purchase_data() {
+            Ok(purchase) => purchase,
+            Err(err) => return PurchaseVerdict::Indeterminate(err),
+        };
+
+        let result = validate_huawei_purchase(&purchase, now);
+        if result.valid {
+            PurchaseVerdict::Valid(result)
+        } else {
+            PurchaseVerdict::Invalid {
+                reason: if purchase.is_subscription() {
+                    InvalidReason::Expired
+                } else {
+                    InvalidReason::NotPurchased
+                },
+                product_id: result.product_id,
+            }
+        }
+    }
+
+    async fn microsoft_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let Some(credentials) = self.microsoft_credentials.as_ref() else {
+            return PurchaseVerdict::Indeterminate(error::Error::NotConfigured(
+                "no microsoft credentials have been set",
+            ));
+        };
+
+        let data = match MicrosoftReceiptData::from(&receipt.payload) {
+            Ok(data) => data,
+            Err(err) => {
+                tracing::warn!("malformed microsoft receipt payload: {}", err);
+                return PurchaseVerdict::Invalid {
+                    reason: InvalidReason::MalformedReceipt(err.to_string()),
+                    product_id: None,
+                };
+            }
+        };
+
+        let response = match self
+            .guarded(
+                Platform::MicrosoftStore,
+                microsoft::fetch_microsoft_receipt_data(receipt, credentials, &self.microsoft_urls),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => return PurchaseVerdict::from_error(err),
+        };
+
+        let result = validate_microsoft_purchase(&response, now);
+        if result.valid {
+            PurchaseVerdict::Valid(result)
+        } else {
+            let reason = match &response {
+                MicrosoftResponse::Collection(items) if items.is_empty() => {
+                    InvalidReason::TransactionNotFound
+                }
+                MicrosoftResponse::Recurrence(items) if items.is_empty() => {
+                    InvalidReason::TransactionNotFound
+                }
+                MicrosoftResponse::Collection(_) => InvalidReason::NotPurchased,
+                MicrosoftResponse::Recurrence(_) => InvalidReason::Expired,
+            };
+            PurchaseVerdict::Invalid {
+                reason,
+                product_id: Some(data.product_id),
+            }
+        }
+    }
+
+    fn fake_store_verdict(&self, receipt: &UnityPurchaseReceipt) -> PurchaseVerdict {
+        let Some(response) = self.fake_store_response.as_ref() else {
+            tracing::warn!(
+                transaction_id = %redact::redact(&receipt.transaction_id),
+                "rejecting FakeStore receipt, dev mode is disabled"
+            );
+            return PurchaseVerdict::Invalid {
+                reason: InvalidReason::FakeStore,
+                product_id: None,
+            };
+        };
+
+        tracing::warn!(
+            transaction_id = %redact::redact(&receipt.transaction_id),
+            valid = response.valid,
+            "FakeStore dev mode: accepting FakeStore receipt without validation"
+        );
+
+        let response = PurchaseResponse {
+            original_transaction_id: response
+                .original_transaction_id
+                .clone()
+                .or_else(|| Some(receipt.transaction_id.clone())),
+            ..response.clone()
+        };
+
+        if response.valid {
+            PurchaseVerdict::Valid(response)
+        } else {
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::NotPurchased,
+                product_id: response.product_id,
+            }
+        }
+    }
+
+    async fn samsung_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let Some(package_name) = self.samsung_package_name.as_deref() else {
+            return PurchaseVerdict::Indeterminate(error::Error::NotConfigured(
+                "no samsung package name has been set",
+            ));
+        };
+
+        if let Err(err) = SamsungReceiptData::from(&receipt.payload) {
+            tracing::warn!("malformed samsung receipt payload: {}", err);
+            return PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt(err.to_string()),
+                product_id: None,
+            };
+        }
+
+        let response = match self
+            .guarded(
+                Platform::SamsungGalaxyStore,
+                samsung::fetch_samsung_receipt_data(receipt, &self.samsung_urls),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => return PurchaseVerdict::from_error(err),
+        };
+
+        if !response.is_success() {
+            return PurchaseVerdict::Invalid {
+                reason: response.error_code.map_or(
+                    InvalidReason::TransactionNotFound,
+                    InvalidReason::StoreStatus,
+                ),
+                product_id: response.item_id,
+            };
+        }
+
+        let result =
+            validate_samsung_purchase(&response, package_name, self.samsung_test_mode, now);
+        if result.valid {
+            PurchaseVerdict::Valid(result)
+        } else {
+            let reason = if response.package_name.as_deref() != Some(package_name) {
+                InvalidReason::TransactionNotFound
+            } else if response.is_paid()
+                && (self.samsung_test_mode || !response.is_test())
+                && response.is_subscription()
+            {
+                InvalidReason::Expired
+            } else {
+                InvalidReason::NotPurchased
+            };
+            PurchaseVerdict::Invalid {
+                reason,
+                product_id: result.product_id,
+            }
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn steam_receipt_data(
+        &self,
+        receipt: &UnityPurchaseReceipt,
+    ) -> std::result::Result<(&SteamCredentials, SteamReceiptData), PurchaseVerdict> {
+        let Some(credentials) = self.steam_credentials.as_ref() else {
+            return Err(PurchaseVerdict::Indeterminate(error::Error::NotConfigured(
+                "no steam credentials have been set",
+            )));
+        };
+
+        match SteamReceiptData::from(&receipt.payload) {
+            Ok(data) if data.steam_id.is_some() => Ok((credentials, data)),
+            result => {
+                let err =
+                    result.map_or_else(|err| err.to_string(), |_| "missing steamId".to_string());
+                tracing::warn!("malformed steam receipt payload: {}", err);
+                Err(PurchaseVerdict::Invalid {
+                    reason: InvalidReason::MalformedReceipt(err),
+                    product_id: None,
+                })
+            }
+        }
+    }
+
+    async fn steam_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let (credentials, data) = match self.steam_receipt_data(receipt) {
+            Ok(result) => result,
+            Err(verdict) => return verdict,
+        };
+
+        if let Some(agreement_id) = data.agreement_id.as_deref() {
+            let response = match self
+                .guarded(
+                    Platform::Steam,
+                    steam::fetch_steam_agreements(
+                        &data,
+                        credentials,
+                        &self.steam_urls,
+                        self.steam_sandbox,
+                    ),
+                )
+                .await
+            {
+                Ok(response) => response,
+                Err(err) => return PurchaseVerdict::from_error(err),
+            };
+
+            let agreement = response
+                .params
+                .into_iter()
+                .flat_map(|params| params.agreements)
+                .find(|agreement| agreement.agreement_id.as_deref() == Some(agreement_id));
+
+            let Some(agreement) = agreement else {
+                return PurchaseVerdict::Invalid {
+                    reason: response
+                        .error
+                        .map_or(InvalidReason::TransactionNotFound, |error| {
+                            InvalidReason::StoreStatus(error.error_code)
+                        }),
+                    product_id: None,
+                };
+            };
+
+            let result = validate_steam_agreement(&agreement, now);
+            if result.valid {
+                PurchaseVerdict::Valid(result)
+            } else {
+                PurchaseVerdict::Invalid {
+                    reason: InvalidReason::Expired,
+                    product_id: result.product_id,
+                }
+            }
+        } else {
+            let response = match self
+                .guarded(
+                    Platform::Steam,
+                    steam::fetch_steam_order(
+                        &data,
+                        credentials,
+                        &self.steam_urls,
+                        self.steam_sandbox,
+                    ),
+                )
+                .await
+            {
+                Ok(response) => response,
+                Err(err) => return PurchaseVerdict::from_error(err),
+            };
+
+            let Some(order) = response.params else {
+                return PurchaseVerdict::Invalid {
+                    reason: response
+                        .error
+                        .map_or(InvalidReason::TransactionNotFound, |error| {
+                            InvalidReason::StoreStatus(error.error_code)
+                        }),
+                    product_id: None,
+                };
+            };
+
+            let steam_id = data.steam_id.as_deref().unwrap_or_default();
+            let result = validate_steam_order(&order, steam_id, now);
+            if result.valid {
+                PurchaseVerdict::Valid(result)
+            } else {
+                PurchaseVerdict::Invalid {
+                    reason: if order.steam_id.as_deref() == Some(steam_id) {
+                        InvalidReason::NotPurchased
+                    } else {
+                        InvalidReason::TransactionNotFound
+                    },
+                    product_id: result.product_id,
+                }
+            }
+        }
+    }
+
+    /// Sets the entitlements a valid purchase grants according to the `ProductCatalog`
+    fn with_entitlements(&self, verdict: PurchaseVerdict) -> PurchaseVerdict {
+        match (verdict, self.product_catalog.as_ref()) {
+            (PurchaseVerdict::Valid(response), Some(catalog)) => {
+                let entitlements = response
+                    .product_id
+                    .as_deref()
+                    .map(|product_id| catalog.entitlements(product_id))
+                    .unwrap_or_default();
+                PurchaseVerdict::Valid(PurchaseResponse {
+                    entitlements,
+                    ..response
+                })
+            }
+            (verdict, _) => verdict,
+        }
+    }
+
+    async fn google_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        let verdicts = match self.google_product_verdicts(now, receipt).await {
+            Ok(verdicts) => verdicts,
+            Err(err) => {
+                tracing::warn!("malformed google receipt payload: {}", err);
+                return PurchaseVerdict::Invalid {
+                    reason: InvalidReason::MalformedReceipt(err.to_string()),
+                    product_id: None,
+                };
+            }
+        };
+
+        // a purchase of several products is only valid if each of its products is
+        verdicts
+            .into_iter()
+            .map(|(_, verdict)| verdict)
+            .reduce(|verdict, next| {
+                if verdict.is_valid() && !next.is_valid() {
+                    next
+                } else {
+                    verdict
+                }
+            })
+            .unwrap_or_else(|| PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt("no products in the purchase".to_string()),
+                product_id: None,
+            })
+    }
+
+    /// Fetches the purchase at `uri` through the `CircuitBreaker`, within the quotas of the `GoogleRateLimiter` once the
+    /// circuit admits the call
+    async fn fetch_google_uri(
+        &self,
+        uri: String,
+        data: Option<google::GooglePlayData>,
+    ) -> Result<GoogleResponse> {
+        self.guarded(Platform::GooglePlay, async {
+            if let Some(google_rate_limiter) = self.google_rate_limiter.as_ref() {
+                google_rate_limiter.acquire().await?;
+            }
+
+            google::fetch_google_receipt_data_with_clients(
+                &self.clients,
+                self.service_account_key.as_ref(),
+                uri,
+                data,
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Fetches the App Store receipt, through the `CircuitBreaker` if one is set
+    async fn fetch_apple_receipt(&self, receipt: &UnityPurchaseReceipt) -> Result<AppleResponse> {
+        self.guarded(
+            Platform::AppleAppStore,
+            apple::fetch_apple_receipt_data_with_client(
+                self.clients.client(),
+                receipt,
+                &self.apple_urls,
+                self.secret.as_ref(),
+            ),
+        )
+        .await
+    }
+
+    /// Runs the `call` to `store` through the `CircuitBreaker` if one is set, and records its outcome
+    async fn guarded<T>(
+        &self,
+        store: Platform,
+        call: impl std::future::Future<Output = Result<T>> + Send,
+    ) -> Result<T> {
+        let Some(circuit_breaker) = self.circuit_breaker.as_ref() else {
+            return call.await;
+        };
+
+        circuit_breaker.acquire(store)?;
+        let result = call.await;
+        circuit_breaker.record(store, &result);
+        result
+    }
+
+    /// Fetches the purchase, when `sku_type` is unknown it is taken from `google_sku_types`, or else inferred by
+    /// looking the token up as a subscription first and as an in-app product if the subscription is not found.
+    async fn fetch_google_purchase(
+        &self,
+        sku_type: Option<SkuType>,
+        package_name: &str,
+        product_id: &str,
+        token: &str,
+    ) -> Result<(GoogleResponse, SkuType)> {
+        let fetch = |sku_type| {
+            let uri =
+                google_purchase_uri(&self.google_urls, package_name, product_id, token, sku_type);
+            async move {
+                self.fetch_google_uri(uri, None)
+                    .await
+                    .map(|response| (response, sku_type))
+            }
+        };
+
+        if let Some(sku_type) = sku_type {
+            return fetch(sku_type).await;
+        }
+
+        let configured = self.google_sku_types.get(product_id).copied().or_else(|| {
+            self.product_catalog
+                .as_ref()
+                .and_then(|catalog| catalog.product(product_id))
+                .map(|product| product.product_type.sku_type())
+        });
+
+        if let Some(sku_type) = configured {
+            tracing::info!(
+                "google sku type inferred from the configured sku types or product catalog, product_id: {}, sku_type: {:?}",
+                product_id,
+                sku_type
+            );
+            return fetch(sku_type).await;
+        }
+
+        match fetch(SkuType::Subs).await {
+            Err(error::Error::GoogleApiError { status, .. }) if status == StatusCode::NOT_FOUND => {
+                tracing::info!(
+                    "google sku type inferred as inapp, no subscription found, product_id: {}",
+                    product_id
+                );
+                fetch(SkuType::Inapp).await
+            }
+            result => {
+                if result.is_ok() {
+                    tracing::info!(
+                        "google sku type inferred as subs, product_id: {}",
+                        product_id
+                    );
+                }
+                result
+            }
+        }
+    }
+
+    async fn google_purchase_verdict(
+        &self,
+        now: DateTime<Utc>,
+        sku_type: Option<SkuType>,
+        package_name: &str,
+        product_id: String,
+        token: String,
+    ) -> PurchaseVerdict {
+        let (mut response, sku_type) = match self
+            .fetch_google_purchase(sku_type, package_name, &product_id, &token)
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::error!(
+                    "google validation failed, retryable: {}, error: {}",
+                    err.is_retryable(),
+                    err
+                );
+                return PurchaseVerdict::from_error(err);
+            }
+        };
+
+        response.product_id.get_or_insert(product_id);
+
+        let with_token = |result: PurchaseResponse| PurchaseResponse {
+            original_transaction_id: Some(token.clone()),
+            ..result
+        };
+
+        match sku_type {
+            SkuType::Subs => match validate_google_subscription(&response, now).map(with_token) {
+                Ok(result) if result.valid => PurchaseVerdict::Valid(result),
+                Ok(result) => PurchaseVerdict::Invalid {
+                    reason: InvalidReason::Expired,
+                    product_id: result.product_id,
+                },
+                Err(err) => PurchaseVerdict::Indeterminate(err),
+            },
+            SkuType::Inapp => {
+                let result = with_token(validate_google_package(&response));
+                if result.valid {
+                    PurchaseVerdict::Valid(result)
+                } else {
+                    PurchaseVerdict::Invalid {
+                        reason: InvalidReason::NotPurchased,
+                        product_id: result.product_id,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn storekit_verdict(&self, now: DateTime<Utc>, jws: &str) -> PurchaseVerdict {
+        let Some(credentials) = self.app_store_server_credentials.as_ref() else {
+            return PurchaseVerdict::Indeterminate(error::Error::NotConfigured(
+                "no app store server credentials have been set",
+            ));
+        };
+
+        let signed = match AppleTransaction::from_unverified_jws(jws) {
+            Ok(signed) => signed,
+            Err(err) => {
+                tracing::warn!("malformed apple signed transaction: {}", err);
+                return PurchaseVerdict::Invalid {
+                    reason: InvalidReason::MalformedReceipt(err.to_string()),
+                    product_id: None,
+                };
+            }
+        };
+
+        let Some(transaction_id) = signed.transaction_id.as_deref() else {
+            return PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt("missing transactionId".to_string()),
+                product_id: signed.product_id,
+            };
+        };
+
+        let transaction = match self
+            .guarded(
+                Platform::AppleAppStore,
+                storekit::fetch_apple_transaction(
+                    transaction_id,
+                    self.app_store_server_sandbox,
+                    credentials,
+                    &self.app_store_server_urls,
+                ),
+            )
+            .await
+        {
+            Ok(Some(transaction))
+                if transaction.bundle_id.as_deref() == Some(credentials.bundle_id.as_str())
+                    && (self.app_store_server_sandbox || !transaction.is_sandbox()) =>
+            {
+                transaction
+            }
+            Ok(transaction) => {
+                tracing::warn!(
+                    transaction_id = %redact::redact(transaction_id),
+                    bundle_id = ?transaction.and_then(|transaction| transaction.bundle_id),
+                    "apple transaction not found"
+                );
+                return PurchaseVerdict::Invalid {
+                    reason: InvalidReason::TransactionNotFound,
+                    product_id: signed.product_id,
+                };
+            }
+            Err(err) => return PurchaseVerdict::from_error(err),
+        };
+
+        let result = validate_apple_transaction(&transaction, now);
+        if result.valid {
+            PurchaseVerdict::Valid(result)
+        } else {
+            let reason = if transaction.revocation_date.is_some() {
+                InvalidReason::NotPurchased
+            } else {
+                InvalidReason::Expired
+            };
+            PurchaseVerdict::Invalid {
+                reason,
+                product_id: result.product_id,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Validator for UnityPurchaseValidator<'_> {
+    async fn validate(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> Result<PurchaseResponse> {
+        self.verdict(now, receipt).await.into_result()
+    }
+
+    async fn verdict(&self, now: DateTime<Utc>, receipt: &UnityPurchaseReceipt) -> PurchaseVerdict {
+        let verdict = self.receipt_verdict(now, receipt).await;
+
+        match self.offline_fallback.as_ref() {
+            Some(fallback) if !receipt.transaction_id.is_empty() => {
+                fallback
+                    .apply(
+                        now,
+                        receipt.store,
+                        &receipt.transaction_id,
+                        &receipt.payload,
+                        verdict,
+                    )
+                    .await
+            }
+            _ => verdict,
+        }
+    }
+
+    async fn verdict_input(&self, now: DateTime<Utc>, input: &PurchaseInput) -> PurchaseVerdict {
+        let verdict = self.input_verdict(now, input).await;
+        self.with_fallback(now, input, verdict).await
+    }
+
+    /// Validates the purchases in batches, each App Store receipt is fetched only once for all its transactions.
+    async fn verdict_many(
+        &self,
+        now: DateTime<Utc>,
+        inputs: &[PurchaseInput],
+        concurrency: usize,
+    ) -> Vec<PurchaseVerdict> {
+        let mut batches = Vec::<Vec<usize>>::new();
+        let mut apple_batches = HashMap::<&str, usize>::new();
+
+        for (index, input) in inputs.iter().enumerate() {
+            if let Some((receipt_data, _)) = input.apple_receipt() {
+                let batch = *apple_batches.entry(receipt_data).or_insert_with(|| {
+                    batches.push(Vec::new());
+                    batches.len() - 1
+                });
+                batches[batch].push(index);
+            } else {
+                batches.push(vec![index]);
+            }
+        }
+
+        let mut verdicts = stream::iter(batches)
+            .map(|batch| self.batch_verdicts(now, inputs, batch))
+            .buffer_unordered(concurrency.max(1))
+            .flat_map(stream::iter)
+            .collect::<Vec<_>>()
+            .await;
+
+        verdicts.sort_by_key(|(index, _)| *index);
+        join_all(
+            verdicts
+                .into_iter()
+                .map(|(index, verdict)| self.with_fallback(now, &inputs[index], verdict)),
+        )
+        .await
+    }
+}
+
+impl UnityPurchaseValidator<'_> {
+    /// Same as `Validator::verdict`, without the `OfflineFallback`
+    async fn receipt_verdict(
+        &self,
+        now: DateTime<Utc>,
+        receipt: &UnityPurchaseReceipt,
+    ) -> PurchaseVerdict {
+        tracing::debug!(
+            store = ?receipt.store,
+            transaction_id = %redact::redact(&receipt.transaction_id),
+            payload = %redact::redact(&receipt.payload),
+            "validating receipt"
+        );
+
+        let verdict = match receipt.store {
+            Platform::AppleAppStore => self.apple_verdict(now, receipt).await,
+            Platform::GooglePlay => self.google_verdict(now, receipt).await,
+            Platform::AmazonAppStore => self.amazon_verdict(now, receipt).await,
+            Platform::HuaweiAppGallery => self.huawei_verdict(now, receipt).await,
+            Platform::MicrosoftStore => self.microsoft_verdict(now, receipt).await,
+            Platform::Steam => self.steam_verdict(now, receipt).await,
+            Platform::SamsungGalaxyStore => self.samsung_verdict(now, receipt).await,
+            Platform::FakeStore => self.fake_store_verdict(receipt),
+        };
+
+        self.with_entitlements(verdict)
+    }
+
+    /// Same as `Validator::verdict_input`, without the `OfflineFallback`
+    async fn input_verdict(&self, now: DateTime<Utc>, input: &PurchaseInput) -> PurchaseVerdict {
+        let verdict = match input {
+            PurchaseInput::Unity(receipt) => self.receipt_verdict(now, receipt).await,
+            PurchaseInput::AppleReceipt {
+                receipt_data,
+                transaction_id,
+            } => {
+                let receipt = UnityPurchaseReceipt {
+                    store: Platform::AppleAppStore,
+                    payload: receipt_data.clone(),
+                    transaction_id: transaction_id.clone(),
+                };
+                self.apple_verdict(now, &receipt).await
+            }
+            PurchaseInput::AppleSignedTransaction(jws) => self.storekit_verdict(now, jws).await,
+            PurchaseInput::GooglePlay {
+                package_name,
+                product_id,
+                purchase_token,
+                sku_type,
+            } => {
+                self.google_purchase_verdict(
+                    now,
+                    Some(*sku_type),
+                    package_name,
+                    product_id.clone(),
+                    purchase_token.clone(),
+                )
+                .await
+            }
+        };
+
+        self.with_entitlements(verdict)
+    }
+
+    /// Applies the `OfflineFallback` to the verdict of `input`, if one is set
+    async fn with_fallback(
+        &self,
+        now: DateTime<Utc>,
+        input: &PurchaseInput,
+        verdict: PurchaseVerdict,
+    ) -> PurchaseVerdict {
+        let Some(fallback) = self.offline_fallback.as_ref() else {
+            return verdict;
+        };
+
+        match input.transaction_key() {
+            Some(transaction_id) => {
+                fallback
+                    .apply(
+                        now,
+                        input.store(),
+                        &transaction_id,
+                        input.payload(),
+                        verdict,
+                    )
+                    .await
+            }
+            None => verdict,
+        }
+    }
+}
+
+#[async_trait]
+impl ReceiptDataFetcher for UnityPurchaseValidator<'_> {
+    async fn fetch_apple_receipt_data(
+        &self,
+        receipt: &UnityPurchaseReceipt,
+    ) -> Result<AppleResponse> {
+        self.fetch_apple_receipt(receipt).await
+    }
+
+    async fn fetch_google_receipt_data(
+        &self,
+        receipt: &UnityPurchaseReceipt,
+    ) -> Result<(GoogleResponse, SkuType)> {
+        let data = google::GooglePlayData::from(&receipt.payload)?;
+        let sku_type = data.get_sku_details()?.sku_type;
+        self.fetch_google_uri(data.get_uri(&sku_type)?, Some(data))
+            .await
+            .map(|response| (response, sku_type))
+    }
+}
+
+#[cfg(test)]
+#[allow(
+    clippy::unwrap_used,
+    clippy::panic,
+    clippy::needless_update,
+    clippy::needless_borrows_for_generic_args,
+    clippy::uninlined_format_args,
+    clippy::manual_string_new
+)]
+mod tests {
+    use super::*;
+    use crate::{
+        apple::{AppleInAppReceipt, AppleLatestReceipt, AppleReceipt, AppleResponse},
+        google::{validate_google_subscription, GoogleResponse},
+    };
+    use chrono::{Duration, Utc};
+    use mockito::mock;
+    use serial_test::serial;
+
+    fn new_for_test<'a>(prod_url: &'a str, sandbox_url: &'a str) -> UnityPurchaseValidator<'a> {
+        UnityPurchaseValidator {
+            secret: Some(String::from("secret")),
+            apple_urls: AppleUrls {
+                production: prod_url,
+                sandbox: sandbox_url,
+            },
+            ..UnityPurchaseValidator::default()
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_sandbox_response() {
+        let expiry = (Utc::now() + Duration::days(1))
+            .timestamp_millis()
+            .to_string();
+        let apple_response = AppleResponse {
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![AppleInAppReceipt {
+                    product_id: Some("prod".to_string()),
+                    expires_date_ms: Some(expiry),
+                    transaction_id: Some("txn".to_string()),
+                    ..AppleInAppReceipt::default()
+                }]),
+                ..AppleReceipt::default()
+            }),
+            ..AppleResponse::default()
+        };
+
+        let _m1 = mock("POST", "/sb/verifyReceipt")
+            .with_status(200)
+            .with_body(&serde_json::to_string(&apple_response).unwrap())
+            .create();
+
+        let _m2 = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(r#"{"status": 21007}"#)
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{}/sb", url);
+        let validator = new_for_test(url, &sandbox);
+
+        let response = validator
+            .validate(
                 Utc::now(),
                 &UnityPurchaseReceipt {
                     transaction_id: "txn".to_string(),
@@ -420,24 +2238,181 @@ mod tests {
                 in_app: Some(vec![
                     AppleInAppReceipt {
                         product_id: Some("prod".to_string()),
-                        expires_date_ms: Some(Utc::now().timestamp_millis().to_string()),
-                        transaction_id: Some("txn3".to_string()),
-                        ..AppleInAppReceipt::default()
-                    },
-                    AppleInAppReceipt {
-                        product_id: Some("prod".to_string()),
-                        expires_date_ms: Some(expiry),
-                        transaction_id: Some("txn1".to_string()),
+                        expires_date_ms: Some(Utc::now().timestamp_millis().to_string()),
+                        transaction_id: Some("txn3".to_string()),
+                        ..AppleInAppReceipt::default()
+                    },
+                    AppleInAppReceipt {
+                        product_id: Some("prod".to_string()),
+                        expires_date_ms: Some(expiry),
+                        transaction_id: Some("txn1".to_string()),
+                        ..AppleInAppReceipt::default()
+                    },
+                    AppleInAppReceipt {
+                        product_id: Some("prod".to_string()),
+                        expires_date_ms: Some(
+                            (Utc::now() - Duration::days(1))
+                                .timestamp_millis()
+                                .to_string(),
+                        ),
+                        transaction_id: Some("txn2".to_string()),
+                        ..AppleInAppReceipt::default()
+                    },
+                ]),
+                ..AppleReceipt::default()
+            }),
+            ..AppleResponse::default()
+        };
+
+        let _m1 = mock("POST", "/sb/verifyReceipt")
+            .with_status(200)
+            .with_body(&serde_json::to_string(&apple_response).unwrap())
+            .create();
+
+        let _m2 = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(r#"{"status": 21007}"#)
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{}/sb", url);
+        let validator = new_for_test(url, &sandbox);
+
+        let response = validator
+            .validate(
+                Utc::now(),
+                &UnityPurchaseReceipt {
+                    transaction_id: "".to_string(),
+                    ..UnityPurchaseReceipt::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("prod".to_string()));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_subscription_expired_fallback() {
+        let expiry = (Utc::now() + Duration::days(1))
+            .timestamp_millis()
+            .to_string();
+        let apple_response = AppleResponse {
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![
+                    AppleInAppReceipt {
+                        product_id: Some("prod".to_string()),
+                        expires_date_ms: Some(expiry),
+                        transaction_id: Some("txn1".to_string()),
+                        ..AppleInAppReceipt::default()
+                    },
+                    AppleInAppReceipt {
+                        product_id: Some("prod".to_string()),
+                        expires_date_ms: Some(
+                            (Utc::now() - Duration::days(1))
+                                .timestamp_millis()
+                                .to_string(),
+                        ),
+                        transaction_id: Some("txn2".to_string()),
+                        ..AppleInAppReceipt::default()
+                    },
+                ]),
+                ..AppleReceipt::default()
+            }),
+            ..AppleResponse::default()
+        };
+
+        let _m1 = mock("POST", "/sb/verifyReceipt")
+            .with_status(200)
+            .with_body(&serde_json::to_string(&apple_response).unwrap())
+            .create();
+
+        let _m2 = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(r#"{"status": 21007}"#)
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{}/sb", url);
+        let validator = new_for_test(url, &sandbox);
+
+        // look up the expired receipt, should still be valid since txn1 is not expired
+        let response = validator
+            .validate(
+                Utc::now(),
+                &UnityPurchaseReceipt {
+                    transaction_id: "txn2".to_string(),
+                    ..UnityPurchaseReceipt::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("prod".to_string()));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_invalid_receipt() {
+        let now = Utc::now().timestamp_millis().to_string();
+        let apple_response = AppleResponse {
+            latest_receipt: Some(String::default()),
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![AppleInAppReceipt {
+                    product_id: Some("prod".to_string()),
+                    expires_date_ms: Some(now),
+                    transaction_id: Some("txn".to_string()),
+                    ..AppleInAppReceipt::default()
+                }]),
+                ..AppleReceipt::default()
+            }),
+            ..AppleResponse::default()
+        };
+
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(&serde_json::to_string(&apple_response).unwrap())
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{}/sb", url);
+        let validator = new_for_test(url, &sandbox);
+
+        assert!(
+            !validator
+                .validate(
+                    Utc::now(),
+                    &UnityPurchaseReceipt {
+                        transaction_id: "txn".to_string(),
+                        ..UnityPurchaseReceipt::default()
+                    }
+                )
+                .await
+                .unwrap()
+                .valid
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_apple_purchase() {
+        let apple_response = AppleResponse {
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![
+                    AppleInAppReceipt {
+                        product_id: Some("prod".to_string()),
+                        transaction_id: Some("not_txn".to_string()),
                         ..AppleInAppReceipt::default()
                     },
                     AppleInAppReceipt {
                         product_id: Some("prod".to_string()),
-                        expires_date_ms: Some(
-                            (Utc::now() - Duration::days(1))
-                                .timestamp_millis()
-                                .to_string(),
-                        ),
-                        transaction_id: Some("txn2".to_string()),
+                        transaction_id: Some("txn".to_string()),
                         ..AppleInAppReceipt::default()
                     },
                 ]),
@@ -465,7 +2440,7 @@ mod tests {
             .validate(
                 Utc::now(),
                 &UnityPurchaseReceipt {
-                    transaction_id: "".to_string(),
+                    transaction_id: "txn".to_string(),
                     ..UnityPurchaseReceipt::default()
                 },
             )
@@ -473,258 +2448,997 @@ mod tests {
             .unwrap();
 
         assert!(response.valid);
-        assert_eq!(response.product_id, Some("prod".to_string()));
+        assert_eq!(response.product_id, Some("prod".to_string()));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_apple_purchase_failed_missing_txn() {
+        let apple_response = AppleResponse {
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![AppleInAppReceipt {
+                    product_id: Some("prod".to_string()),
+                    transaction_id: Some("not_txn".to_string()),
+                    ..AppleInAppReceipt::default()
+                }]),
+                ..AppleReceipt::default()
+            }),
+            ..AppleResponse::default()
+        };
+
+        let _m1 = mock("POST", "/sb/verifyReceipt")
+            .with_status(200)
+            .with_body(&serde_json::to_string(&apple_response).unwrap())
+            .create();
+
+        let _m2 = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(r#"{"status": 21007}"#)
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{}/sb", url);
+        let validator = new_for_test(url, &sandbox);
+
+        assert!(
+            !validator
+                .validate(
+                    Utc::now(),
+                    &UnityPurchaseReceipt {
+                        transaction_id: "txn".to_string(),
+                        ..UnityPurchaseReceipt::default()
+                    }
+                )
+                .await
+                .unwrap()
+                .valid
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_apple_fail() {
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(r#"{"status": 333}"#)
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{}/sb", url);
+        let validator = new_for_test(url, &sandbox);
+
+        assert!(
+            !validator
+                .validate(Utc::now(), &UnityPurchaseReceipt::default())
+                .await
+                .unwrap()
+                .valid
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_google_fail() {
+        let google_response = GoogleResponse {
+            expiry_time: Some(Utc::now().timestamp_millis().to_string()),
+            ..GoogleResponse::default()
+        };
+
+        let _m = mock("GET", "/test")
+            .with_status(200)
+            .with_body(&serde_json::to_string(&google_response).unwrap())
+            .create();
+
+        let url = &mockito::server_url();
+
+        assert!(
+            !validate_google_subscription(
+                &google::fetch_google_receipt_data_with_uri(None, url.clone(), None,)
+                    .await
+                    .unwrap(),
+                Utc::now()
+            )
+            .unwrap()
+            .valid
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_google_purchase_token_invalid() {
+        let _m = mock("GET", "/test")
+            .with_status(410)
+            .with_body(
+                r#"{"error": {"code": 410, "message": "The subscription purchase is no longer available for query because it has been expired for too long.", "errors": [{"message": "expired", "domain": "androidpublisher", "reason": "subscriptionPurchaseNoLongerAvailable"}]}}"#,
+            )
+            .create();
+
+        let url = &mockito::server_url();
+
+        let err = google::fetch_google_receipt_data_with_uri(None, url.clone(), None)
+            .await
+            .unwrap_err();
+
+        assert!(err.is_purchase_token_invalid());
+        assert!(!err.is_retryable());
+        match err {
+            error::Error::GooglePurchaseTokenInvalid(details) => {
+                assert_eq!(details.code, 410);
+                assert_eq!(
+                    details.errors[0].reason.as_deref(),
+                    Some("subscriptionPurchaseNoLongerAvailable")
+                );
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_google_api_error() {
+        let _m = mock("GET", "/test")
+            .with_status(401)
+            .with_body(
+                r#"{"error": {"code": 401, "message": "Request is missing required authentication credential.", "status": "UNAUTHENTICATED"}}"#,
+            )
+            .create();
+
+        let url = &mockito::server_url();
+
+        let err = google::fetch_google_receipt_data_with_uri(None, url.clone(), None)
+            .await
+            .unwrap_err();
+
+        assert!(!err.is_retryable());
+        match err {
+            error::Error::GoogleApiError { status, error } => {
+                assert_eq!(status, hyper::StatusCode::UNAUTHORIZED);
+                assert_eq!(error.status.as_deref(), Some("UNAUTHENTICATED"));
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        let _m = mock("GET", "/test")
+            .with_status(503)
+            .with_body("Service Unavailable")
+            .create();
+
+        let err = google::fetch_google_receipt_data_with_uri(None, url.clone(), None)
+            .await
+            .unwrap_err();
+
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_apple_errors() {
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(503)
+            .with_body("Service Unavailable")
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{url}/sb");
+        let validator = new_for_test(url, &sandbox);
+
+        let err = validator
+            .validate(Utc::now(), &UnityPurchaseReceipt::default())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, error::Error::AppleHttpError { .. }));
+        assert!(err.is_retryable());
+
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(r#"{"status": 21005}"#)
+            .create();
+
+        let err = validator
+            .validate(Utc::now(), &UnityPurchaseReceipt::default())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            error::Error::AppleStatusError { status: 21005, .. }
+        ));
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_apple_verdict() {
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(r#"{"status": 21003}"#)
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{url}/sb");
+        let validator = new_for_test(url, &sandbox);
+
+        match validator
+            .verdict(Utc::now(), &UnityPurchaseReceipt::default())
+            .await
+        {
+            PurchaseVerdict::Invalid { reason, .. } => {
+                assert_eq!(reason, InvalidReason::StoreStatus(21003));
+            }
+            verdict => panic!("unexpected verdict: {:?}", verdict),
+        }
+
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(500)
+            .with_body("Internal Server Error")
+            .create();
+
+        let verdict = validator
+            .verdict(Utc::now(), &UnityPurchaseReceipt::default())
+            .await;
+
+        assert!(matches!(verdict, PurchaseVerdict::Indeterminate(_)));
+        assert!(verdict.is_retryable());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_google_infer_sku_type() {
+        let package = GoogleResponse {
+            order_id: "GPA".to_string(),
+            purchase_state: Some(0),
+            ..GoogleResponse::default()
+        };
+        let subscription = mock(
+            "GET",
+            "/androidpublisher/v3/applications/com.example.app/purchases/subscriptions/coins/tokens/token/test",
+        )
+        .with_status(404)
+        .with_body(r#"{"error": {"code": 404, "message": "The purchase token was not found.", "status": "NOT_FOUND"}}"#)
+        .expect(1)
+        .create();
+        let product = mock(
+            "GET",
+            "/androidpublisher/v3/applications/com.example.app/purchases/products/coins/tokens/token/test",
+        )
+        .with_status(200)
+        .with_body(serde_json::to_string(&package).unwrap())
+        .expect(2)
+        .create();
+
+        // no skuDetails, as in restored purchases
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::GooglePlay,
+            payload: serde_json::json!({
+                "json": r#"{"packageName": "com.example.app", "productId": "coins", "purchaseToken": "token", "acknowledged": true, "purchaseTime": 0, "orderId": "GPA", "purchaseState": 0}"#,
+                "signature": "",
+            })
+            .to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        let url = mockito::server_url();
+        let validator = UnityPurchaseValidator {
+            google_urls: GoogleUrls { publisher: &url },
+            ..UnityPurchaseValidator::default()
+        };
+        let response = validator.validate(Utc::now(), &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("coins".to_string()));
+
+        // the configured sku type skips the subscription lookup
+        let validator =
+            validator.set_google_sku_types(HashMap::from([("coins".to_string(), SkuType::Inapp)]));
+        assert!(validator.verdict(Utc::now(), &receipt).await.is_valid());
+
+        subscription.assert();
+        product.assert();
+    }
+
+    #[tokio::test]
+    async fn test_google_malformed_verdict() {
+        let validator = UnityPurchaseValidator::default();
+
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::GooglePlay,
+            payload: "{}".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        let verdict = validator.verdict(Utc::now(), &receipt).await;
+
+        assert!(matches!(
+            verdict,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt(_),
+                ..
+            }
+        ));
+        assert!(
+            !validator
+                .validate(Utc::now(), &receipt)
+                .await
+                .unwrap()
+                .valid
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fake_store() {
+        let receipt = UnityPurchaseReceipt::from(
+            r#"{"Store": "fake", "TransactionID": "f2ae5e1f-5d2c-4bd4-9b25-8f0c1c7b6b3e", "Payload": "ThisIsFakeReceiptData"}"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.store, Platform::FakeStore);
+
+        assert!(matches!(
+            UnityPurchaseValidator::default()
+                .verdict(Utc::now(), &receipt)
+                .await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::FakeStore,
+                ..
+            }
+        ));
+
+        let validator =
+            UnityPurchaseValidator::default().set_fake_store_response(PurchaseResponse {
+                valid: true,
+                product_id: Some("fake_product".to_string()),
+                ..PurchaseResponse::default()
+            });
+
+        let response = validator.validate(Utc::now(), &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("fake_product".to_string()));
+        assert_eq!(
+            response.original_transaction_id,
+            Some(receipt.transaction_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_product_catalog_entitlements() {
+        let catalog = ProductCatalog::from_toml(
+            &std::fs::read_to_string("res/test_product_catalog.toml").unwrap(),
+        )
+        .unwrap();
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::FakeStore,
+            transaction_id: "txn".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        let validator = UnityPurchaseValidator::default()
+            .set_fake_store_response(PurchaseResponse {
+                valid: true,
+                product_id: Some("com.game.vip.monthly".to_string()),
+                ..PurchaseResponse::default()
+            })
+            .set_product_catalog(catalog);
+
+        let response = validator.validate(Utc::now(), &receipt).await.unwrap();
+        assert_eq!(response.entitlements, vec!["vip"]);
+
+        // invalid purchases grant nothing
+        let response = UnityPurchaseValidator::default()
+            .set_product_catalog(validator.product_catalog.clone().unwrap())
+            .validate(Utc::now(), &receipt)
+            .await
+            .unwrap();
+        assert!(response.entitlements.is_empty());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_apple_non_renewing() {
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let purchase = |transaction_id: &str, purchase_date: DateTime<Utc>| AppleInAppReceipt {
+            product_id: Some("com.game.season_pass".to_string()),
+            transaction_id: Some(transaction_id.to_string()),
+            original_transaction_id: Some(transaction_id.to_string()),
+            purchase_date_ms: Some(purchase_date.timestamp_millis().to_string()),
+            ..AppleInAppReceipt::default()
+        };
+        let first = now - chrono::Duration::days(100);
+        let apple_response = AppleResponse {
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![
+                    purchase("txn2", now - chrono::Duration::days(20)),
+                    purchase("txn1", first),
+                    purchase("old", now - chrono::Duration::days(1000)),
+                ]),
+            }),
+            ..AppleResponse::default()
+        };
+
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(200)
+            .with_body(serde_json::to_string(&apple_response).unwrap())
+            .create();
+
+        let url = &mockito::server_url();
+        let sandbox = format!("{url}/sb");
+        let catalog = ProductCatalog::from_toml(
+            &std::fs::read_to_string("res/test_product_catalog.toml").unwrap(),
+        )
+        .unwrap();
+        let validator = new_for_test(url, &sandbox).set_product_catalog(catalog);
+
+        let receipt = UnityPurchaseReceipt {
+            transaction_id: "txn2".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+        let response = validator.validate(now, &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.entitlements, vec!["season_pass"]);
+
+        // the second purchase extends the period of the first one, the old purchase ended long before
+        let season = ProductDuration::Months(3);
+        assert_eq!(
+            response.expiry_time,
+            season.add_to(season.add_to(first).unwrap())
+        );
+
+        assert!(matches!(
+            validator
+                .verdict(now + chrono::Duration::days(365), &receipt)
+                .await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::Expired,
+                ..
+            }
+        ));
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_subscription_expired_fallback() {
-        let expiry = (Utc::now() + Duration::days(1))
-            .timestamp_millis()
-            .to_string();
+    async fn test_apple_non_renewing_refunded() {
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let purchase = |transaction_id: &str, purchase_date: DateTime<Utc>| AppleInAppReceipt {
+            product_id: Some("com.game.season_pass".to_string()),
+            transaction_id: Some(transaction_id.to_string()),
+            original_transaction_id: Some(transaction_id.to_string()),
+            purchase_date_ms: Some(purchase_date.timestamp_millis().to_string()),
+            ..AppleInAppReceipt::default()
+        };
+        let first = now - chrono::Duration::days(200);
         let apple_response = AppleResponse {
             receipt: Some(AppleReceipt {
                 in_app: Some(vec![
+                    purchase("txn1", first),
                     AppleInAppReceipt {
-                        product_id: Some("prod".to_string()),
-                        expires_date_ms: Some(expiry),
-                        transaction_id: Some("txn1".to_string()),
-                        ..AppleInAppReceipt::default()
-                    },
-                    AppleInAppReceipt {
-                        product_id: Some("prod".to_string()),
-                        expires_date_ms: Some(
-                            (Utc::now() - Duration::days(1))
-                                .timestamp_millis()
-                                .to_string(),
-                        ),
-                        transaction_id: Some("txn2".to_string()),
-                        ..AppleInAppReceipt::default()
+                        cancellation_date_ms: Some(now.timestamp_millis().to_string()),
+                        ..purchase("refunded", now - chrono::Duration::days(150))
                     },
+                    purchase("txn3", now - chrono::Duration::days(120)),
                 ]),
-                ..AppleReceipt::default()
             }),
             ..AppleResponse::default()
         };
+        let catalog = ProductCatalog::from_toml(
+            &std::fs::read_to_string("res/test_product_catalog.toml").unwrap(),
+        )
+        .unwrap();
 
-        let _m1 = mock("POST", "/sb/verifyReceipt")
-            .with_status(200)
-            .with_body(&serde_json::to_string(&apple_response).unwrap())
-            .create();
+        // the refunded purchase is not stacked, so the period ended before now
+        let season = ProductDuration::Months(3);
+        let response = validate_apple_non_renewing(&apple_response, "txn3", &catalog, now).unwrap();
+        assert_eq!(
+            response.expiry_time,
+            season.add_to(season.add_to(first).unwrap())
+        );
+        assert!(!response.valid);
 
-        let _m2 = mock("POST", "/verifyReceipt")
+        let response =
+            validate_apple_non_renewing(&apple_response, "refunded", &catalog, first).unwrap();
+        assert!(!response.valid);
+
+        let _m = mock("POST", "/verifyReceipt")
             .with_status(200)
-            .with_body(r#"{"status": 21007}"#)
+            .with_body(serde_json::to_string(&apple_response).unwrap())
             .create();
 
         let url = &mockito::server_url();
+        let sandbox = format!("{url}/sb");
+        let validator = new_for_test(url, &sandbox).set_product_catalog(catalog);
+        let receipt = UnityPurchaseReceipt {
+            transaction_id: "refunded".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
 
-        let sandbox = format!("{}/sb", url);
-        let validator = new_for_test(url, &sandbox);
-
-        // look up the expired receipt, should still be valid since txn1 is not expired
-        let response = validator
-            .validate(
-                Utc::now(),
-                &UnityPurchaseReceipt {
-                    transaction_id: "txn2".to_string(),
-                    ..UnityPurchaseReceipt::default()
-                },
-            )
+        // the refund is reported the same way by `verdict` and `apple_transaction_verdicts`
+        assert!(matches!(
+            validator.verdict(first, &receipt).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::NotPurchased,
+                ..
+            }
+        ));
+        let verdicts = validator
+            .apple_transaction_verdicts(first, &receipt)
             .await
             .unwrap();
-
-        assert!(response.valid);
-        assert_eq!(response.product_id, Some("prod".to_string()));
+        assert!(verdicts["com.game.season_pass"]
+            .iter()
+            .any(|(id, verdict)| {
+                id == "refunded"
+                    && matches!(
+                        verdict,
+                        PurchaseVerdict::Invalid {
+                            reason: InvalidReason::NotPurchased,
+                            ..
+                        }
+                    )
+            }));
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_invalid_receipt() {
-        let now = Utc::now().timestamp_millis().to_string();
+    async fn test_redeem() {
         let apple_response = AppleResponse {
-            latest_receipt: Some(String::default()),
             receipt: Some(AppleReceipt {
                 in_app: Some(vec![AppleInAppReceipt {
                     product_id: Some("prod".to_string()),
-                    expires_date_ms: Some(now),
                     transaction_id: Some("txn".to_string()),
+                    original_transaction_id: Some("original_txn".to_string()),
                     ..AppleInAppReceipt::default()
                 }]),
-                ..AppleReceipt::default()
             }),
             ..AppleResponse::default()
         };
 
         let _m = mock("POST", "/verifyReceipt")
             .with_status(200)
-            .with_body(&serde_json::to_string(&apple_response).unwrap())
+            .with_body(serde_json::to_string(&apple_response).unwrap())
             .create();
 
         let url = &mockito::server_url();
 
-        let sandbox = format!("{}/sb", url);
-        let validator = new_for_test(url, &sandbox);
+        let sandbox = format!("{url}/sb");
+        let validator = new_for_test(url, &sandbox)
+            .set_transaction_store(Arc::new(InMemoryTransactionStore::default()));
 
-        assert!(
-            !validator
-                .validate(
-                    Utc::now(),
-                    &UnityPurchaseReceipt {
-                        transaction_id: "txn".to_string(),
-                        ..UnityPurchaseReceipt::default()
+        let receipt = UnityPurchaseReceipt {
+            transaction_id: "txn".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        assert!(validator
+            .redeem(Utc::now(), "user1", &receipt)
+            .await
+            .is_valid());
+        assert!(validator
+            .redeem(Utc::now(), "user1", &receipt)
+            .await
+            .is_valid());
+
+        match validator.redeem(Utc::now(), "user2", &receipt).await {
+            PurchaseVerdict::Invalid { reason, product_id } => {
+                assert_eq!(
+                    reason,
+                    InvalidReason::AlreadyRedeemed {
+                        user_id: "user1".to_string()
                     }
-                )
-                .await
-                .unwrap()
-                .valid
-        );
+                );
+                assert_eq!(product_id, Some("prod".to_string()));
+            }
+            verdict => panic!("unexpected verdict: {:?}", verdict),
+        }
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_apple_purchase() {
+    async fn test_redeem_consumable() {
         let apple_response = AppleResponse {
             receipt: Some(AppleReceipt {
                 in_app: Some(vec![
                     AppleInAppReceipt {
-                        product_id: Some("prod".to_string()),
-                        transaction_id: Some("not_txn".to_string()),
+                        product_id: Some("coins_100".to_string()),
+                        transaction_id: Some("txn".to_string()),
+                        original_transaction_id: Some("txn".to_string()),
+                        quantity: Some("3".to_string()),
                         ..AppleInAppReceipt::default()
                     },
                     AppleInAppReceipt {
-                        product_id: Some("prod".to_string()),
-                        transaction_id: Some("txn".to_string()),
+                        product_id: Some("coins_100".to_string()),
+                        transaction_id: Some("refunded".to_string()),
+                        original_transaction_id: Some("refunded".to_string()),
+                        cancellation_date_ms: Some(Utc::now().timestamp_millis().to_string()),
                         ..AppleInAppReceipt::default()
                     },
                 ]),
-                ..AppleReceipt::default()
             }),
             ..AppleResponse::default()
         };
 
-        let _m1 = mock("POST", "/sb/verifyReceipt")
-            .with_status(200)
-            .with_body(&serde_json::to_string(&apple_response).unwrap())
-            .create();
-
-        let _m2 = mock("POST", "/verifyReceipt")
+        let _m = mock("POST", "/verifyReceipt")
             .with_status(200)
-            .with_body(r#"{"status": 21007}"#)
+            .with_body(serde_json::to_string(&apple_response).unwrap())
             .create();
 
         let url = &mockito::server_url();
 
-        let sandbox = format!("{}/sb", url);
+        let sandbox = format!("{url}/sb");
         let validator = new_for_test(url, &sandbox);
 
-        let response = validator
-            .validate(
-                Utc::now(),
-                &UnityPurchaseReceipt {
-                    transaction_id: "txn".to_string(),
-                    ..UnityPurchaseReceipt::default()
-                },
-            )
-            .await
-            .unwrap();
+        let receipt = UnityPurchaseReceipt {
+            transaction_id: "txn".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
 
-        assert!(response.valid);
-        assert_eq!(response.product_id, Some("prod".to_string()));
+        assert!(matches!(
+            validator.redeem_consumable("user1", &receipt).await,
+            ConsumableRedemption::NotGranted(PurchaseVerdict::Indeterminate(
+                error::Error::NotConfigured(_)
+            ))
+        ));
+
+        let validator =
+            validator.set_consumable_ledger(Arc::new(InMemoryConsumableLedger::default()));
+
+        match validator.redeem_consumable("user1", &receipt).await {
+            ConsumableRedemption::Granted(grant) => {
+                assert_eq!(grant.transaction_id, "txn");
+                assert_eq!(grant.product_id, Some("coins_100".to_string()));
+                assert_eq!(grant.quantity, 3);
+            }
+            redemption => panic!("unexpected redemption: {:?}", redemption),
+        }
+
+        match validator.redeem_consumable("user2", &receipt).await {
+            ConsumableRedemption::AlreadyGranted(grant) => assert_eq!(grant.user_id, "user1"),
+            redemption => panic!("unexpected redemption: {:?}", redemption),
+        }
+
+        let refunded = UnityPurchaseReceipt {
+            transaction_id: "refunded".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+        assert!(matches!(
+            validator.redeem_consumable("user1", &refunded).await,
+            ConsumableRedemption::NotGranted(PurchaseVerdict::Invalid {
+                reason: InvalidReason::NotPurchased,
+                ..
+            })
+        ));
+
+        let unknown = UnityPurchaseReceipt {
+            transaction_id: "unknown".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+        assert!(matches!(
+            validator.redeem_consumable("user1", &unknown).await,
+            ConsumableRedemption::NotGranted(PurchaseVerdict::Invalid {
+                reason: InvalidReason::TransactionNotFound,
+                ..
+            })
+        ));
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_apple_purchase_failed_missing_txn() {
+    async fn test_verdict_many() {
         let apple_response = AppleResponse {
             receipt: Some(AppleReceipt {
-                in_app: Some(vec![AppleInAppReceipt {
-                    product_id: Some("prod".to_string()),
-                    transaction_id: Some("not_txn".to_string()),
-                    ..AppleInAppReceipt::default()
-                }]),
-                ..AppleReceipt::default()
+                in_app: Some(vec![
+                    AppleInAppReceipt {
+                        product_id: Some("remove_ads".to_string()),
+                        transaction_id: Some("txn1".to_string()),
+                        ..AppleInAppReceipt::default()
+                    },
+                    AppleInAppReceipt {
+                        product_id: Some("coins_100".to_string()),
+                        transaction_id: Some("txn2".to_string()),
+                        ..AppleInAppReceipt::default()
+                    },
+                ]),
             }),
             ..AppleResponse::default()
         };
 
-        let _m1 = mock("POST", "/sb/verifyReceipt")
-            .with_status(200)
-            .with_body(&serde_json::to_string(&apple_response).unwrap())
-            .create();
-
-        let _m2 = mock("POST", "/verifyReceipt")
+        let m = mock("POST", "/verifyReceipt")
             .with_status(200)
-            .with_body(r#"{"status": 21007}"#)
+            .with_body(serde_json::to_string(&apple_response).unwrap())
+            .expect(1)
             .create();
 
         let url = &mockito::server_url();
 
-        let sandbox = format!("{}/sb", url);
+        let sandbox = format!("{url}/sb");
         let validator = new_for_test(url, &sandbox);
 
+        let apple = |transaction_id: &str| PurchaseInput::AppleReceipt {
+            receipt_data: "receipt".to_string(),
+            transaction_id: transaction_id.to_string(),
+        };
+        let inputs = [
+            apple("txn1"),
+            PurchaseInput::Unity(UnityPurchaseReceipt {
+                store: Platform::FakeStore,
+                ..UnityPurchaseReceipt::default()
+            }),
+            apple("unknown"),
+            PurchaseInput::Unity(UnityPurchaseReceipt {
+                store: Platform::AppleAppStore,
+                payload: "receipt".to_string(),
+                transaction_id: "txn2".to_string(),
+            }),
+        ];
+
+        let verdicts = validator.verdict_many(Utc::now(), &inputs, 2).await;
+        m.assert();
+
+        assert_eq!(verdicts.len(), 4);
         assert!(
-            !validator
-                .validate(
-                    Utc::now(),
-                    &UnityPurchaseReceipt {
-                        transaction_id: "txn".to_string(),
-                        ..UnityPurchaseReceipt::default()
-                    }
-                )
-                .await
-                .unwrap()
-                .valid
+            matches!(&verdicts[0], PurchaseVerdict::Valid(response) if response.product_id == Some("remove_ads".to_string()))
+        );
+        assert!(matches!(
+            verdicts[1],
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::FakeStore,
+                ..
+            }
+        ));
+        assert!(matches!(
+            verdicts[2],
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::TransactionNotFound,
+                ..
+            }
+        ));
+        assert!(
+            matches!(&verdicts[3], PurchaseVerdict::Valid(response) if response.product_id == Some("coins_100".to_string()))
         );
+
+        // a failed fetch is not repeated for the other transactions of the receipt
+        let m = mock("POST", "/verifyReceipt")
+            .with_status(503)
+            .with_body("Service Unavailable")
+            .expect(1)
+            .create();
+
+        let verdicts = validator.verdict_many(Utc::now(), &inputs, 2).await;
+        m.assert();
+        for index in [0, 2, 3] {
+            assert!(
+                matches!(&verdicts[index], PurchaseVerdict::Indeterminate(err) if err.is_retryable())
+            );
+        }
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_apple_fail() {
+    async fn test_apple_transaction_verdicts() {
+        let now = Utc::now();
+        let millis = |time: DateTime<Utc>| Some(time.timestamp_millis().to_string());
+        let renewal = |transaction_id: &str, purchase: DateTime<Utc>| AppleLatestReceipt {
+            product_id: Some("vip_monthly".to_string()),
+            transaction_id: Some(transaction_id.to_string()),
+            original_transaction_id: Some("vip".to_string()),
+            purchase_date_ms: millis(purchase),
+            expires_date_ms: millis(purchase + Duration::days(30)),
+            ..AppleLatestReceipt::default()
+        };
+
+        let apple_response = AppleResponse {
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![
+                    AppleInAppReceipt {
+                        product_id: Some("remove_ads".to_string()),
+                        transaction_id: Some("ads".to_string()),
+                        purchase_date_ms: millis(now - Duration::days(1000)),
+                        ..AppleInAppReceipt::default()
+                    },
+                    AppleInAppReceipt {
+                        product_id: Some("vip_monthly".to_string()),
+                        transaction_id: Some("vip1".to_string()),
+                        purchase_date_ms: millis(now - Duration::days(40)),
+                        ..AppleInAppReceipt::default()
+                    },
+                ]),
+            }),
+            latest_receipt_info: Some(vec![
+                renewal("vip2", now - Duration::days(10)),
+                renewal("vip1", now - Duration::days(40)),
+                AppleLatestReceipt {
+                    product_id: Some("remove_ads".to_string()),
+                    transaction_id: Some("ads_refunded".to_string()),
+                    purchase_date_ms: millis(now - Duration::days(5)),
+                    cancellation_date_ms: millis(now - Duration::days(1)),
+                    cancellation_reason: Some("0".to_string()),
+                    ..AppleLatestReceipt::default()
+                },
+            ]),
+            ..AppleResponse::default()
+        };
+
         let _m = mock("POST", "/verifyReceipt")
             .with_status(200)
-            .with_body(r#"{"status": 333}"#)
+            .with_body(serde_json::to_string(&apple_response).unwrap())
             .create();
 
         let url = &mockito::server_url();
 
-        let sandbox = format!("{}/sb", url);
+        let sandbox = format!("{url}/sb");
         let validator = new_for_test(url, &sandbox);
 
+        let verdicts = validator
+            .apple_transaction_verdicts(now, &UnityPurchaseReceipt::default())
+            .await
+            .unwrap();
+
+        assert_eq!(verdicts.len(), 2);
+
+        let ads = &verdicts["remove_ads"];
+        assert_eq!(ads.len(), 2);
+        assert_eq!(ads[0].0, "ads");
+        assert!(ads[0].1.is_valid());
+        assert!(matches!(
+            ads[1],
+            (
+                _,
+                PurchaseVerdict::Invalid {
+                    reason: InvalidReason::NotPurchased,
+                    ..
+                }
+            )
+        ));
+
+        let vip = &verdicts["vip_monthly"];
+        assert_eq!(vip.len(), 2);
+        assert!(matches!(
+            &vip[0],
+            (transaction_id, PurchaseVerdict::Invalid { reason: InvalidReason::Expired, .. })
+                if transaction_id == "vip1"
+        ));
         assert!(
-            !validator
-                .validate(Utc::now(), &UnityPurchaseReceipt::default())
-                .await
-                .unwrap()
-                .valid
+            matches!(&vip[1], (transaction_id, PurchaseVerdict::Valid(_)) if transaction_id == "vip2")
         );
     }
 
+    #[tokio::test]
+    async fn test_google_rate_limited() {
+        let validator = UnityPurchaseValidator::default().set_google_rate_limiter(Arc::new(
+            GoogleRateLimiter::new(GoogleQuotas {
+                per_minute: 0,
+                max_wait: std::time::Duration::ZERO,
+                ..GoogleQuotas::default()
+            }),
+        ));
+
+        let input = PurchaseInput::GooglePlay {
+            package_name: "com.example.app".to_string(),
+            product_id: "coins_100".to_string(),
+            purchase_token: "token".to_string(),
+            sku_type: SkuType::Inapp,
+        };
+
+        let verdict = validator.verdict_input(Utc::now(), &input).await;
+        assert!(verdict.is_retryable());
+        assert!(matches!(
+            verdict,
+            PurchaseVerdict::Indeterminate(error::Error::RateLimited { .. })
+        ));
+    }
+
     #[tokio::test]
     #[serial]
-    async fn test_google_fail() {
-        let google_response = GoogleResponse {
-            expiry_time: Some(Utc::now().timestamp_millis().to_string()),
-            ..GoogleResponse::default()
+    async fn test_circuit_breaker() {
+        let m = mock("POST", "/verifyReceipt")
+            .with_status(503)
+            .with_body("Service Unavailable")
+            .expect(1)
+            .create();
+
+        let url = &mockito::server_url();
+
+        let sandbox = format!("{url}/sb");
+        let circuit_breaker = Arc::new(CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 1,
+            ..CircuitBreakerConfig::default()
+        }));
+        let validator = new_for_test(url, &sandbox).set_circuit_breaker(circuit_breaker.clone());
+
+        let verdict = validator
+            .verdict(Utc::now(), &UnityPurchaseReceipt::default())
+            .await;
+        assert!(matches!(
+            verdict,
+            PurchaseVerdict::Indeterminate(error::Error::AppleHttpError { .. })
+        ));
+        assert_eq!(
+            circuit_breaker.state(Platform::AppleAppStore),
+            CircuitState::Open
+        );
+
+        let verdict = validator
+            .verdict(Utc::now(), &UnityPurchaseReceipt::default())
+            .await;
+        assert!(verdict.is_retryable());
+        assert!(matches!(
+            verdict,
+            PurchaseVerdict::Indeterminate(error::Error::CircuitOpen {
+                store: Platform::AppleAppStore,
+                ..
+            })
+        ));
+        m.assert();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_offline_fallback() {
+        let now = Utc::now();
+        let apple_response = AppleResponse {
+            receipt: Some(AppleReceipt {
+                in_app: Some(vec![AppleInAppReceipt {
+                    product_id: Some("sub".to_string()),
+                    expires_date_ms: Some((now + Duration::days(1)).timestamp_millis().to_string()),
+                    transaction_id: Some("txn".to_string()),
+                    ..AppleInAppReceipt::default()
+                }]),
+            }),
+            ..AppleResponse::default()
         };
 
-        let _m = mock("GET", "/test")
+        let _m = mock("POST", "/verifyReceipt")
             .with_status(200)
-            .with_body(&serde_json::to_string(&google_response).unwrap())
+            .with_body(serde_json::to_string(&apple_response).unwrap())
             .create();
 
         let url = &mockito::server_url();
 
+        let sandbox = format!("{url}/sb");
+        let validator = new_for_test(url, &sandbox).set_offline_fallback(OfflineFallback::new(
+            Arc::new(InMemorySnapshotStore::default()),
+        ));
+        let receipt = UnityPurchaseReceipt {
+            transaction_id: "txn".to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        assert!(validator.verdict(now, &receipt).await.is_valid());
+
+        let _m = mock("POST", "/verifyReceipt")
+            .with_status(503)
+            .with_body("Service Unavailable")
+            .create();
+
+        let verdict = validator.verdict(now + Duration::hours(1), &receipt).await;
+        assert!(matches!(
+            verdict,
+            PurchaseVerdict::Stale { ref response, validated_at, error: error::Error::AppleHttpError { .. } }
+                if response.valid && validated_at == now
+        ));
         assert!(
-            !validate_google_subscription(
-                &google::fetch_google_receipt_data_with_uri(None, url.clone(), None,)
-                    .await
-                    .unwrap(),
-                Utc::now()
-            )
-            .unwrap()
-            .valid
+            validator
+                .validate(now + Duration::hours(1), &receipt)
+                .await
+                .unwrap()
+                .valid
         );
+
+        // the snapshot is not used for another receipt with the same transaction id
+        let forged = UnityPurchaseReceipt {
+            payload: "forged".to_string(),
+            ..receipt.clone()
+        };
+        assert!(matches!(
+            validator.verdict(now + Duration::hours(1), &forged).await,
+            PurchaseVerdict::Indeterminate(_)
+        ));
+
+        // the subscription expired since it was last validated
+        assert!(matches!(
+            validator.verdict(now + Duration::days(2), &receipt).await,
+            PurchaseVerdict::Indeterminate(_)
+        ));
     }
 
     #[test]
@@ -743,6 +3457,41 @@ mod tests {
         let _google_response: GoogleResponse = serde_json::from_slice(&file).unwrap();
     }
 
+    #[test]
+    fn test_deserialize_google_billing_5() {
+        let file = std::fs::read_to_string("res/test_google_billing_5.json").unwrap();
+        let receipt = UnityPurchaseReceipt::from(&file).unwrap();
+        let data = google::GooglePlayData::from(&receipt.payload).unwrap();
+        assert_eq!(data.get_sku_details().unwrap().sku_type, SkuType::Inapp);
+
+        let parameters = data.get_parameters().unwrap();
+        assert_eq!(parameters.product_ids(), vec!["coins_100", "gems_10"]);
+        assert_eq!(parameters.quantity, Some(2));
+        assert!(data
+            .get_uri(&SkuType::Inapp)
+            .unwrap()
+            .ends_with("/purchases/products/coins_100/tokens/ofkmnlkcaffpnjobfbmfdmjd.AO-J1Oz"));
+
+        // several products cannot be expressed as a single `PurchaseInput::GooglePlay`
+        assert!(matches!(
+            PurchaseInput::from(receipt),
+            PurchaseInput::Unity(_)
+        ));
+
+        // Unity IAP 4.x forwards an array of skuDetails
+        let legacy = google::GooglePlayData::from(
+            &serde_json::json!({
+                "json": r#"{"packageName": "com.example.app", "productId": "sub", "purchaseToken": "token", "acknowledged": true, "purchaseTime": 0, "orderId": "GPA", "purchaseState": 0}"#,
+                "signature": "",
+                "skuDetails": [r#"{"type": "subs"}"#],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        assert_eq!(legacy.get_sku_details().unwrap().sku_type, SkuType::Subs);
+        assert_eq!(legacy.get_parameters().unwrap().product_ids(), vec!["sub"]);
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_google() {
@@ -0,0 +1,494 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{apple::AppleLatestReceipt, redact::redact, AppleResponse, GoogleResponse};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+const APPLE_FLAG_SET: &str = "1";
+const GOOGLE_PAYMENT_PENDING: i32 = 0;
+const GOOGLE_CANCEL_REASON_DEVELOPER: i32 = 3;
+
+/// The state of a subscription at the time of a `SubscriptionSnapshot`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionState {
+    /// The current period is paid for
+    Active,
+    /// The renewal payment failed, but the store still grants access until the grace period ends
+    GracePeriod,
+    /// The renewal payment failed and the store keeps retrying it, access should not be granted.
+    /// On Google Play this is the account hold.
+    BillingRetry,
+    /// The subscription ended and is not being renewed
+    Expired,
+    /// The purchase was refunded or revoked
+    Refunded,
+}
+
+impl SubscriptionState {
+    /// Returns true if the subscription grants access in this state
+    #[must_use]
+    pub const fn is_entitled(self) -> bool {
+        matches!(self, Self::Active | Self::GracePeriod)
+    }
+}
+
+/// The state of a subscription as returned by the store, which can be stored and later compared with a fresh
+/// response to find out what changed, see `SubscriptionSnapshot::events`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionSnapshot {
+    /// The product id of the current period
+    pub product_id: Option<String>,
+    /// Apple's `original_transaction_id`, or the purchase token on Google Play
+    pub original_transaction_id: String,
+    /// Apple's `transaction_id` or Google's `order_id` of the latest period, which changes on every renewal
+    pub latest_transaction_id: Option<String>,
+    /// The state of the subscription
+    pub state: SubscriptionState,
+    /// Whether the subscription will renew at the end of the current period
+    pub auto_renewing: bool,
+    /// Time at which the current period ends
+    pub expiry_time: Option<DateTime<Utc>>,
+    /// The `original_transaction_id` of the subscription this one replaced: the `linkedPurchaseToken` of a Google
+    /// upgrade, downgrade or resubscription, which creates a new purchase token
+    #[serde(default)]
+    pub replaces: Option<String>,
+}
+
+/// A change of a subscription between two `SubscriptionSnapshot`s
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SubscriptionEvent {
+    /// The subscription was purchased, or purchased again after it had ended
+    Started {
+        /// The end of the first period
+        expiry_time: Option<DateTime<Utc>>,
+    },
+    /// A new period was paid for
+    Renewed {
+        /// The end of the new period
+        expiry_time: Option<DateTime<Utc>>,
+    },
+    /// The user turned off automatic renewal, the subscription ends with the current period
+    AutoRenewCancelled,
+    /// The renewal payment failed, access is still granted during the grace period
+    EnteredGracePeriod,
+    /// The renewal payment failed, the store keeps retrying it
+    EnteredBillingRetry,
+    /// The renewal payment succeeded after failing before
+    RecoveredFromBillingRetry {
+        /// The end of the recovered period
+        expiry_time: Option<DateTime<Utc>>,
+    },
+    /// The subscription ended
+    Expired,
+    /// The purchase was refunded or revoked
+    Refunded,
+    /// The user switched to a different product, ie: from a monthly to a yearly plan
+    Upgraded {
+        /// The previous product id
+        from_product_id: Option<String>,
+        /// The new product id
+        to_product_id: Option<String>,
+    },
+}
+
+impl SubscriptionSnapshot {
+    /// The snapshot of the auto-renewable subscription with `original_transaction_id`, using the `latest_receipt_info`
+    /// and `pending_renewal_info` of the response. `None` if the subscription is not in the response.
+    #[must_use]
+    pub fn from_apple(
+        response: &AppleResponse,
+        original_transaction_id: &str,
+        now: DateTime<Utc>,
+    ) -> Option<Self> {
+        let latest = response
+            .latest_receipt_info
+            .as_ref()?
+            .iter()
+            .filter(|receipt| {
+                receipt.original_transaction_id.as_deref() == Some(original_transaction_id)
+            })
+            .max_by_key(|receipt| millis(receipt.expires_date_ms.as_deref()))?;
+
+        let renewal = response.pending_renewal_info.as_ref().and_then(|info| {
+            info.iter().find(|renewal| {
+                renewal.original_transaction_id.as_deref() == Some(original_transaction_id)
+            })
+        });
+
+        let expiry_time = millis(latest.expires_date_ms.as_deref());
+        let grace_period_end =
+            renewal.and_then(|renewal| millis(renewal.grace_period_expires_date_ms.as_deref()));
+        let billing_retry = renewal.is_some_and(|renewal| {
+            renewal.is_in_billing_retry_period.as_deref() == Some(APPLE_FLAG_SET)
+        });
+
+        let state = if is_refunded(latest) {
+            SubscriptionState::Refunded
+        } else if expiry_time.is_some_and(|expiry| expiry > now) {
+            SubscriptionState::Active
+        } else if grace_period_end.is_some_and(|end| end > now) {
+            SubscriptionState::GracePeriod
+        } else if billing_retry {
+            SubscriptionState::BillingRetry
+        } else {
+            SubscriptionState::Expired
+        };
+
+        Some(Self {
+            product_id: latest.product_id.clone(),
+            original_transaction_id: original_transaction_id.to_string(),
+            latest_transaction_id: latest.transaction_id.clone(),
+            state,
+            auto_renewing: renewal.is_some_and(|renewal| {
+                renewal.auto_renew_status.as_deref() == Some(APPLE_FLAG_SET)
+            }),
+            expiry_time,
+            replaces: None,
+        })
+    }
+
+    /// The snapshot of the Google Play subscription with `purchase_token`. During the grace period Google extends
+    /// the expiry with a pending payment, and subscriptions on account hold are expired but still auto renewing.
+    /// Subscriptions cancelled by the developer, ie: when refunding and revoking them, are `Refunded` once expired.
+    ///
+    /// Upgrades create a new purchase token, which `replaces` the previous one. Compare the new snapshot with the
+    /// snapshot of the replaced token to get the `Upgraded` event, the replaced token itself simply expires.
+    #[must_use]
+    pub fn from_google(
+        response: &GoogleResponse,
+        purchase_token: &str,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let expiry_time = millis(response.expiry_time.as_deref());
+        let auto_renewing = response.auto_renewing.unwrap_or_default();
+
+        let state = if expiry_time.is_some_and(|expiry| expiry > now) {
+            if response.payment_state == Some(GOOGLE_PAYMENT_PENDING) {
+                SubscriptionState::GracePeriod
+            } else {
+                SubscriptionState::Active
+            }
+        } else if response.cancel_reason == Some(GOOGLE_CANCEL_REASON_DEVELOPER) {
+            SubscriptionState::Refunded
+        } else if auto_renewing {
+            SubscriptionState::BillingRetry
+        } else {
+            SubscriptionState::Expired
+        };
+
+        Self {
+            product_id: response.product_id.clone(),
+            original_transaction_id: purchase_token.to_string(),
+            latest_transaction_id: Some(response.order_id.clone()),
+            state,
+            auto_renewing,
+            expiry_time,
+            replaces: response.linked_purchase_token.clone(),
+        }
+    }
+
+    /// The lifecycle events between the `previous` snapshot, `None` if the subscription was not known before, and `self`.
+    /// If there is no snapshot of `self` yet but of the subscription it `replaces`, pass that one as `previous`.
+    #[must_use]
+    pub fn events(&self, previous: Option<&Self>) -> Vec<SubscriptionEvent> {
+        let mut events = Vec::new();
+
+        let Some(previous) = previous else {
+            events.push(SubscriptionEvent::Started {
+                expiry_time: self.expiry_time,
+            });
+            if self.state != SubscriptionState::Active {
+                events.extend(self.state_event());
+            } else if !self.auto_renewing {
+                events.push(SubscriptionEvent::AutoRenewCancelled);
+            }
+            return events;
+        };
+
+        let upgraded = previous.product_id != self.product_id;
+        if upgraded {
+            events.push(SubscriptionEvent::Upgraded {
+                from_product_id: previous.product_id.clone(),
+                to_product_id: self.product_id.clone(),
+            });
+        }
+
+        if previous.state != self.state {
+            match (previous.state, self.state) {
+                (
+                    SubscriptionState::Expired | SubscriptionState::Refunded,
+                    SubscriptionState::Active,
+                ) => {
+                    events.push(SubscriptionEvent::Started {
+                        expiry_time: self.expiry_time,
+                    });
+                }
+                (
+                    SubscriptionState::GracePeriod | SubscriptionState::BillingRetry,
+                    SubscriptionState::Active,
+                ) => {
+                    events.push(SubscriptionEvent::RecoveredFromBillingRetry {
+                        expiry_time: self.expiry_time,
+                    });
+                }
+                _ => events.extend(self.state_event()),
+            }
+        } else if self.state == SubscriptionState::Active
+            && !upgraded
+            && previous.latest_transaction_id != self.latest_transaction_id
+            && self.expiry_time > previous.expiry_time
+        {
+            events.push(SubscriptionEvent::Renewed {
+                expiry_time: self.expiry_time,
+            });
+        }
+
+        if previous.auto_renewing && !self.auto_renewing && self.state.is_entitled() {
+            events.push(SubscriptionEvent::AutoRenewCancelled);
+        }
+
+        tracing::debug!(
+            original_transaction_id = %redact(&self.original_transaction_id),
+            previous = ?previous.state,
+            current = ?self.state,
+            events = ?events,
+            "subscription lifecycle"
+        );
+
+        events
+    }
+
+    /// The event of entering the current state, `None` if `Active`
+    const fn state_event(&self) -> Option<SubscriptionEvent> {
+        match self.state {
+            SubscriptionState::Active => None,
+            SubscriptionState::GracePeriod => Some(SubscriptionEvent::EnteredGracePeriod),
+            SubscriptionState::BillingRetry => Some(SubscriptionEvent::EnteredBillingRetry),
+            SubscriptionState::Expired => Some(SubscriptionEvent::Expired),
+            SubscriptionState::Refunded => Some(SubscriptionEvent::Refunded),
+        }
+    }
+}
+
+/// Upgrades also set the `cancellation_date` of the replaced transaction, but only refunds set a `cancellation_reason`
+const fn is_refunded(receipt: &AppleLatestReceipt) -> bool {
+    receipt.cancellation_date_ms.is_some() && receipt.cancellation_reason.is_some()
+}
+
+fn millis(value: Option<&str>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|value| value.parse::<i64>().ok())
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::apple::ApplePendingRenewal;
+    use chrono::Duration;
+
+    fn apple_response(
+        transaction_id: &str,
+        product_id: &str,
+        expiry: DateTime<Utc>,
+        renewal: ApplePendingRenewal,
+    ) -> AppleResponse {
+        AppleResponse {
+            latest_receipt_info: Some(vec![AppleLatestReceipt {
+                product_id: Some(product_id.to_string()),
+                transaction_id: Some(transaction_id.to_string()),
+                original_transaction_id: Some("original".to_string()),
+                expires_date_ms: Some(expiry.timestamp_millis().to_string()),
+                ..AppleLatestReceipt::default()
+            }]),
+            pending_renewal_info: Some(vec![ApplePendingRenewal {
+                original_transaction_id: Some("original".to_string()),
+                ..renewal
+            }]),
+            ..AppleResponse::default()
+        }
+    }
+
+    fn auto_renew(status: &str) -> ApplePendingRenewal {
+        ApplePendingRenewal {
+            auto_renew_status: Some(status.to_string()),
+            ..ApplePendingRenewal::default()
+        }
+    }
+
+    #[test]
+    fn test_apple_lifecycle() {
+        let now = Utc::now();
+        let month = Duration::days(30);
+
+        let started = SubscriptionSnapshot::from_apple(
+            &apple_response("1", "monthly", now + month, auto_renew("1")),
+            "original",
+            now,
+        )
+        .unwrap();
+        assert_eq!(started.state, SubscriptionState::Active);
+        assert_eq!(
+            started.events(None),
+            vec![SubscriptionEvent::Started {
+                expiry_time: started.expiry_time
+            }]
+        );
+
+        let later = now + month + Duration::hours(1);
+        let grace = SubscriptionSnapshot::from_apple(
+            &apple_response(
+                "1",
+                "monthly",
+                now + month,
+                ApplePendingRenewal {
+                    grace_period_expires_date_ms: Some(
+                        (later + Duration::days(6)).timestamp_millis().to_string(),
+                    ),
+                    is_in_billing_retry_period: Some("1".to_string()),
+                    ..auto_renew("1")
+                },
+            ),
+            "original",
+            later,
+        )
+        .unwrap();
+        assert_eq!(
+            grace.events(Some(&started)),
+            vec![SubscriptionEvent::EnteredGracePeriod]
+        );
+
+        let recovered = SubscriptionSnapshot::from_apple(
+            &apple_response("2", "monthly", later + month, auto_renew("1")),
+            "original",
+            later,
+        )
+        .unwrap();
+        assert_eq!(
+            recovered.events(Some(&grace)),
+            vec![SubscriptionEvent::RecoveredFromBillingRetry {
+                expiry_time: recovered.expiry_time
+            }]
+        );
+
+        let upgraded = SubscriptionSnapshot::from_apple(
+            &apple_response("3", "yearly", later + Duration::days(365), auto_renew("0")),
+            "original",
+            later,
+        )
+        .unwrap();
+        assert_eq!(
+            upgraded.events(Some(&recovered)),
+            vec![
+                SubscriptionEvent::Upgraded {
+                    from_product_id: Some("monthly".to_string()),
+                    to_product_id: Some("yearly".to_string()),
+                },
+                SubscriptionEvent::AutoRenewCancelled,
+            ]
+        );
+
+        let mut refund =
+            apple_response("3", "yearly", later + Duration::days(365), auto_renew("0"));
+        if let Some(receipts) = refund.latest_receipt_info.as_mut() {
+            receipts[0].cancellation_date_ms = Some(later.timestamp_millis().to_string());
+            receipts[0].cancellation_reason = Some("0".to_string());
+        }
+        let refunded = SubscriptionSnapshot::from_apple(&refund, "original", later).unwrap();
+        assert_eq!(
+            refunded.events(Some(&upgraded)),
+            vec![SubscriptionEvent::Refunded]
+        );
+
+        assert!(SubscriptionSnapshot::from_apple(&refund, "unknown", later).is_none());
+    }
+
+    #[test]
+    fn test_google_lifecycle() {
+        let now = Utc::now();
+        let response =
+            |order_id: &str, expiry: DateTime<Utc>, auto_renewing: bool| GoogleResponse {
+                order_id: order_id.to_string(),
+                product_id: Some("monthly".to_string()),
+                expiry_time: Some(expiry.timestamp_millis().to_string()),
+                auto_renewing: Some(auto_renewing),
+                payment_state: Some(1),
+                ..GoogleResponse::default()
+            };
+
+        let started = SubscriptionSnapshot::from_google(
+            &response("GPA.1", now + Duration::days(30), true),
+            "token",
+            now,
+        );
+        let later = now + Duration::days(31);
+        let renewed = SubscriptionSnapshot::from_google(
+            &response("GPA.1..0", later + Duration::days(30), true),
+            "token",
+            later,
+        );
+        assert_eq!(
+            renewed.events(Some(&started)),
+            vec![SubscriptionEvent::Renewed {
+                expiry_time: renewed.expiry_time
+            }]
+        );
+
+        let on_hold = SubscriptionSnapshot::from_google(
+            &response("GPA.1..0", later + Duration::days(30), true),
+            "token",
+            later + Duration::days(31),
+        );
+        assert_eq!(on_hold.state, SubscriptionState::BillingRetry);
+        assert_eq!(
+            on_hold.events(Some(&renewed)),
+            vec![SubscriptionEvent::EnteredBillingRetry]
+        );
+
+        let cancelled = SubscriptionSnapshot::from_google(
+            &response("GPA.1..0", later + Duration::days(30), false),
+            "token",
+            later,
+        );
+        assert_eq!(
+            cancelled.events(Some(&renewed)),
+            vec![SubscriptionEvent::AutoRenewCancelled]
+        );
+
+        let expired = SubscriptionSnapshot::from_google(
+            &response("GPA.1..0", later + Duration::days(30), false),
+            "token",
+            later + Duration::days(31),
+        );
+        assert_eq!(
+            expired.events(Some(&cancelled)),
+            vec![SubscriptionEvent::Expired]
+        );
+        // upgrading creates a new purchase token linked to the replaced one
+        let upgraded = SubscriptionSnapshot::from_google(
+            &GoogleResponse {
+                product_id: Some("yearly".to_string()),
+                linked_purchase_token: Some("token".to_string()),
+                ..response("GPA.2", later + Duration::days(365), true)
+            },
+            "upgraded_token",
+            later,
+        );
+        assert_eq!(upgraded.replaces.as_deref(), Some("token"));
+        assert_eq!(
+            upgraded.events(Some(&renewed)),
+            vec![SubscriptionEvent::Upgraded {
+                from_product_id: Some("monthly".to_string()),
+                to_product_id: Some("yearly".to_string()),
+            }]
+        );
+
+        assert_eq!(
+            serde_json::to_string(&SubscriptionEvent::Expired).unwrap(),
+            r#"{"type":"expired"}"#
+        );
+    }
+}
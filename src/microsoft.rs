@@ -0,0 +1,571 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::{Error, Result},
+    redact::redact,
+    PurchaseResponse, UnityPurchaseReceipt,
+};
+use chrono::{DateTime, Utc};
+use hyper::{body, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+const MICROSOFT_COLLECTIONS: &str = "https://collections.mp.microsoft.com";
+const MICROSOFT_PURCHASE: &str = "https://purchase.mp.microsoft.com";
+const MICROSOFT_LOGIN: &str = "https://login.microsoftonline.com";
+/// The audience of the Azure AD access token for the Microsoft Store collection and purchase services
+const MICROSOFT_STORE_RESOURCE: &str = "https://onestore.microsoft.com";
+const MICROSOFT_STATUS_ACTIVE: &str = "Active";
+const MICROSOFT_PRODUCT_TYPE_CONSUMABLE: &str = "UnmanagedConsumable";
+/// Recurrence states in which the subscription is active, see <https://learn.microsoft.com/en-us/windows/uwp/monetize/get-subscriptions-for-a-user#response>
+const MICROSOFT_RECURRENCE_STATES_VALID: [&str; 2] = ["Active", "InDunning"];
+
+/// Convenience struct for storing the base URLs of the Microsoft Store services.
+/// See: <https://learn.microsoft.com/en-us/windows/uwp/monetize/view-and-grant-products-from-a-service>
+pub struct MicrosoftUrls<'a> {
+    /// By default, <https://collections.mp.microsoft.com>
+    pub collections: &'a str,
+    /// By default, <https://purchase.mp.microsoft.com>
+    pub purchase: &'a str,
+    /// By default, <https://login.microsoftonline.com>
+    pub login: &'a str,
+}
+
+impl Default for MicrosoftUrls<'_> {
+    fn default() -> Self {
+        MicrosoftUrls {
+            collections: MICROSOFT_COLLECTIONS,
+            purchase: MICROSOFT_PURCHASE,
+            login: MICROSOFT_LOGIN,
+        }
+    }
+}
+
+/// The Azure AD application used to authenticate against the Microsoft Store services with client credentials.
+#[derive(Clone, Debug, Default)]
+pub struct MicrosoftCredentials {
+    /// The Azure AD tenant id
+    pub tenant_id: String,
+    /// The client id of the Azure AD application
+    pub client_id: String,
+    /// The client secret of the Azure AD application
+    pub client_secret: String,
+}
+
+/// The payload of a receipt for purchases made through the Microsoft Store.
+///
+/// The store keys have to be generated by the client with `GetCustomerCollectionsIdAsync` and `GetCustomerPurchaseIdAsync`,
+/// and sent as the JSON payload of a receipt with `"Store": "MicrosoftStore"`, eg:
+/// `{"storeIdKey": "...", "purchaseIdKey": "...", "productId": "9NBLGGH4R315"}`.
+/// The XML app receipt in Unity IAP's `WinRT` receipts can not be verified by the Store services.
+/// See <https://learn.microsoft.com/en-us/windows/uwp/monetize/view-and-grant-products-from-a-service#step-4>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MicrosoftReceiptData {
+    /// The Microsoft Store ID key for the collections service
+    pub store_id_key: String,
+    /// The Microsoft Store ID key for the purchase service, required for subscriptions
+    pub purchase_id_key: Option<String>,
+    /// The Store ID of the purchased add-on
+    pub product_id: String,
+    /// The Store ID of the SKU, if not set all SKUs of the product are queried
+    pub sku_id: Option<String>,
+}
+
+impl MicrosoftReceiptData {
+    /// Construct the `MicrosoftReceiptData` from the `UnityPurchaseReceipt` payload
+    /// # Errors
+    /// Will return an error if the payload cannot be deserialized
+    pub fn from(payload: &str) -> Result<Self> {
+        Ok(serde_json::from_str(payload)?)
+    }
+
+    /// Returns true if the receipt is for a subscription, ie: has a purchase id key
+    #[must_use]
+    pub const fn is_subscription(&self) -> bool {
+        self.purchase_id_key.is_some()
+    }
+}
+
+/// A product owned by the user.
+/// See <https://learn.microsoft.com/en-us/windows/uwp/monetize/query-for-products#collectionitemcontractv6> for more details on each field.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MicrosoftCollectionItem {
+    /// The Store ID of the product
+    pub product_id: Option<String>,
+    /// The Store ID of the SKU
+    pub sku_id: Option<String>,
+    /// Either `Application`, `Durable` or `UnmanagedConsumable`
+    pub product_type: Option<String>,
+    /// Either `Active`, `Expired`, `Revoked` or `Banned`
+    pub status: Option<String>,
+    /// The quantity of the item, for consumables the remaining balance
+    pub quantity: Option<i64>,
+    /// The date the user acquired the item
+    pub acquired_date: Option<DateTime<Utc>>,
+    /// The date at which the item stops being valid
+    pub end_date: Option<DateTime<Utc>>,
+    /// The order id of the purchase
+    pub order_id: Option<String>,
+    /// The transaction id of the purchase
+    pub transaction_id: Option<String>,
+    /// The id of the item, used to consume it
+    pub item_id: Option<String>,
+}
+
+impl MicrosoftCollectionItem {
+    /// Returns true if the item is a consumable
+    #[must_use]
+    pub fn is_consumable(&self) -> bool {
+        self.product_type.as_deref() == Some(MICROSOFT_PRODUCT_TYPE_CONSUMABLE)
+    }
+}
+
+/// A subscription of the user.
+/// See <https://learn.microsoft.com/en-us/windows/uwp/monetize/get-subscriptions-for-a-user#response> for more details on each field.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MicrosoftRecurrenceItem {
+    /// The id of the subscription
+    pub id: Option<String>,
+    /// The Store ID of the subscription add-on
+    pub product_id: Option<String>,
+    /// The Store ID of the SKU
+    pub sku_id: Option<String>,
+    /// Whether the subscription will automatically renew
+    pub auto_renew: Option<bool>,
+    /// Either `None`, `Active`, `Inactive`, `Canceled`, `InDunning` or `Failed`
+    pub recurrence_state: Option<String>,
+    /// The date the subscription expires
+    pub expiration_time: Option<DateTime<Utc>>,
+    /// The date the subscription expires including the grace period
+    pub expiration_time_with_grace: Option<DateTime<Utc>>,
+    /// Whether the subscription is a trial
+    pub is_trial: Option<bool>,
+}
+
+/// Response of the Microsoft Store services, either the collection items or the recurrence items of the user.
+#[derive(Clone, Debug)]
+pub enum MicrosoftResponse {
+    /// Response of the collections query, for durables and consumables
+    Collection(Vec<MicrosoftCollectionItem>),
+    /// Response of the recurrence query, for subscriptions
+    Recurrence(Vec<MicrosoftRecurrenceItem>),
+}
+
+#[derive(Deserialize)]
+struct MicrosoftItemsResponse<T> {
+    items: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct MicrosoftTokenResponse {
+    access_token: String,
+}
+
+/// Retrieves the user's collection items, or recurrence items for subscriptions, matching the product of the receipt.
+/// # Errors
+/// Will return an error if the `payload` in the `UnityPurchaseReceipt` is malformed, if authentication fails,
+/// or if the endpoint responds with an error.
+pub async fn fetch_microsoft_receipt_data(
+    receipt: &UnityPurchaseReceipt,
+    credentials: &MicrosoftCredentials,
+    urls: &MicrosoftUrls<'_>,
+) -> Result<MicrosoftResponse> {
+    let data = MicrosoftReceiptData::from(&receipt.payload)?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let access_token = fetch_access_token(&client, credentials, urls).await?;
+
+    let response = if let Some(purchase_id_key) = data.purchase_id_key.as_ref() {
+        let request_body = serde_json::json!({ "b2bKey": purchase_id_key });
+        let buf = post(
+            &client,
+            &access_token,
+            format!("{}/v8.0/b2b/recurrences/query", urls.purchase),
+            &request_body,
+        )
+        .await?;
+        let items =
+            serde_json::from_slice::<MicrosoftItemsResponse<MicrosoftRecurrenceItem>>(&buf)?.items;
+
+        MicrosoftResponse::Recurrence(
+            items
+                .into_iter()
+                .filter(|item| item.product_id.as_deref() == Some(&data.product_id))
+                .collect(),
+        )
+    } else {
+        let request_body = serde_json::json!({
+            "beneficiaries": [{
+                "identityType": "b2b",
+                "identityValue": data.store_id_key,
+                "localTicketReference": "",
+            }],
+            "productSkuIds": [{
+                "productId": data.product_id,
+                "skuId": data.sku_id.clone().unwrap_or_default(),
+            }],
+            "productTypes": ["Application", "Durable", "UnmanagedConsumable"],
+            "validityType": "All",
+        });
+        let buf = post(
+            &client,
+            &access_token,
+            format!("{}/v6.0/collections/query", urls.collections),
+            &request_body,
+        )
+        .await?;
+        let items =
+            serde_json::from_slice::<MicrosoftItemsResponse<MicrosoftCollectionItem>>(&buf)?.items;
+
+        MicrosoftResponse::Collection(
+            items
+                .into_iter()
+                .filter(|item| item.product_id.as_deref() == Some(&data.product_id))
+                .collect(),
+        )
+    };
+
+    let states = match &response {
+        MicrosoftResponse::Collection(items) => items
+            .iter()
+            .map(|item| item.status.clone())
+            .collect::<Vec<_>>(),
+        MicrosoftResponse::Recurrence(items) => items
+            .iter()
+            .map(|item| item.recurrence_state.clone())
+            .collect(),
+    };
+
+    tracing::info!(target = "microsoft_response",
+        product_id = %data.product_id,
+        is_subscription = %data.is_subscription(),
+        states = ?states,
+    );
+
+    Ok(response)
+}
+
+/// Reports a consumable as fulfilled, reducing its remaining quantity by `quantity`.
+///
+/// `tracking_id` has to be unique per fulfilment, retrying with the same `tracking_id` will not consume the item twice.
+/// See <https://learn.microsoft.com/en-us/windows/uwp/monetize/report-consumable-products-as-fulfilled>
+/// # Errors
+/// Will return an error if the `payload` in the `UnityPurchaseReceipt` is malformed, if authentication fails,
+/// or if the endpoint responds with an error.
+pub async fn consume_microsoft_purchase(
+    receipt: &UnityPurchaseReceipt,
+    credentials: &MicrosoftCredentials,
+    urls: &MicrosoftUrls<'_>,
+    tracking_id: &str,
+    quantity: i64,
+) -> Result<MicrosoftCollectionItem> {
+    let data = MicrosoftReceiptData::from(&receipt.payload)?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let access_token = fetch_access_token(&client, credentials, urls).await?;
+
+    let request_body = serde_json::json!({
+        "beneficiary": {
+            "identityType": "b2b",
+            "identityValue": data.store_id_key,
+            "localTicketReference": "",
+        },
+        "productId": data.product_id,
+        "trackingId": tracking_id,
+        "removeQuantity": quantity,
+    });
+
+    let buf = post(
+        &client,
+        &access_token,
+        format!("{}/v6.0/collections/consume", urls.collections),
+        &request_body,
+    )
+    .await?;
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+async fn post(
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+    access_token: &str,
+    uri: String,
+    request_body: &serde_json::Value,
+) -> Result<body::Bytes> {
+    let req = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {access_token}"))
+        .body(Body::from(serde_json::to_string(request_body)?))?;
+
+    let resp = client.request(req).await?;
+    let status = resp.status();
+    let buf = body::to_bytes(resp).await?;
+
+    tracing::debug!(
+        status = %status,
+        response_body = %redact(&String::from_utf8_lossy(&buf).replace('\n', "")),
+        "microsoft response"
+    );
+
+    if !status.is_success() {
+        return Err(Error::MicrosoftApiError {
+            status,
+            body: String::from_utf8_lossy(&buf).into_owned(),
+        });
+    }
+
+    Ok(buf)
+}
+
+async fn fetch_access_token(
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+    credentials: &MicrosoftCredentials,
+    urls: &MicrosoftUrls<'_>,
+) -> Result<String> {
+    let request_body = format!(
+        "grant_type=client_credentials&client_id={}&client_secret={}&resource={}",
+        utf8_percent_encode(&credentials.client_id, NON_ALPHANUMERIC),
+        utf8_percent_encode(&credentials.client_secret, NON_ALPHANUMERIC),
+        utf8_percent_encode(MICROSOFT_STORE_RESOURCE, NON_ALPHANUMERIC),
+    );
+
+    let req = Request::builder()
+        .method("POST")
+        .uri(format!(
+            "{}/{}/oauth2/token",
+            urls.login,
+            utf8_percent_encode(&credentials.tenant_id, NON_ALPHANUMERIC)
+        ))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(Body::from(request_body))?;
+
+    let resp = client.request(req).await?;
+    let status = resp.status();
+    let buf = body::to_bytes(resp).await?;
+
+    if !status.is_success() {
+        return Err(Error::MicrosoftApiError {
+            status,
+            body: String::from_utf8_lossy(&buf).into_owned(),
+        });
+    }
+
+    Ok(serde_json::from_slice::<MicrosoftTokenResponse>(&buf)?.access_token)
+}
+
+/// Validates a Microsoft Store purchase.
+///
+/// Subscriptions are valid if they are active (or in dunning) and have not expired including the grace period. Other products are valid if they are active and have not ended, consumables
+/// additionally need a remaining quantity.
+#[must_use]
+pub fn validate_microsoft_purchase(
+    response: &MicrosoftResponse,
+    now: DateTime<Utc>,
+) -> PurchaseResponse {
+    let result = match response {
+        MicrosoftResponse::Recurrence(items) => items
+            .iter()
+            .filter(|item| {
+                item.recurrence_state
+                    .as_deref()
+                    .is_some_and(|state| MICROSOFT_RECURRENCE_STATES_VALID.contains(&state))
+            })
+            .filter_map(|item| {
+                item.expiration_time_with_grace
+                    .or(item.expiration_time)
+                    .map(|expiry| (item, expiry))
+            })
+            .max_by_key(|(_, expiry)| *expiry)
+            .map(|(item, expiry)| PurchaseResponse {
+                valid: expiry > now,
+                product_id: item.product_id.clone(),
+                original_transaction_id: item.id.clone(),
+                expiry_time: Some(expiry),
+                ..PurchaseResponse::default()
+            }),
+        MicrosoftResponse::Collection(items) => items
+            .iter()
+            .find(|item| {
+                item.status.as_deref() == Some(MICROSOFT_STATUS_ACTIVE)
+                    && item.end_date.map_or(true, |end_date| end_date > now)
+                    && (!item.is_consumable() || item.quantity.unwrap_or_default() > 0)
+            })
+            .map(|item| PurchaseResponse {
+                valid: true,
+                product_id: item.product_id.clone(),
+                original_transaction_id: item
+                    .transaction_id
+                    .clone()
+                    .or_else(|| item.order_id.clone()),
+                ..PurchaseResponse::default()
+            }),
+    }
+    .unwrap_or_default();
+
+    tracing::info!(
+        "microsoft receipt verification, valid: {}, now: {}, product_id: {:?}",
+        result.valid,
+        now,
+        result.product_id,
+    );
+
+    result
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{InvalidReason, Platform, PurchaseVerdict, UnityPurchaseValidator, Validator};
+    use mockito::mock;
+    use serial_test::serial;
+
+    fn credentials() -> MicrosoftCredentials {
+        MicrosoftCredentials {
+            tenant_id: "tenant".to_string(),
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_microsoft_subscription() {
+        let now = Utc::now();
+        let _oauth = mock("POST", "/tenant/oauth2/token")
+            .with_status(200)
+            .with_body(r#"{"access_token": "access", "token_type": "Bearer"}"#)
+            .create();
+        let _recurrences = mock("POST", "/v8.0/b2b/recurrences/query")
+            .match_header("Authorization", "Bearer access")
+            .with_status(200)
+            .with_body(
+                serde_json::json!({
+                    "items": [MicrosoftRecurrenceItem {
+                        id: Some("sub".to_string()),
+                        product_id: Some("prod".to_string()),
+                        recurrence_state: Some("Active".to_string()),
+                        expiration_time: Some(now + chrono::Duration::days(1)),
+                        ..MicrosoftRecurrenceItem::default()
+                    }]
+                })
+                .to_string(),
+            )
+            .create();
+
+        let url = mockito::server_url();
+        let validator = UnityPurchaseValidator {
+            microsoft_urls: MicrosoftUrls {
+                collections: &url,
+                purchase: &url,
+                login: &url,
+            },
+            ..UnityPurchaseValidator::default()
+        }
+        .set_microsoft_credentials(credentials());
+
+        let receipt = UnityPurchaseReceipt::from(
+            r#"{"Store": "MicrosoftStore", "TransactionID": "txn", "Payload": "{\"storeIdKey\": \"store\", \"purchaseIdKey\": \"purchase\", \"productId\": \"prod\"}"}"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.store, Platform::MicrosoftStore);
+        assert!(UnityPurchaseReceipt::from(
+            r#"{"Store": "WinRT", "TransactionID": "txn", "Payload": "<Receipt/>"}"#
+        )
+        .is_err());
+
+        let response = validator.validate(now, &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.original_transaction_id, Some("sub".to_string()));
+
+        assert!(matches!(
+            validator
+                .verdict(now + chrono::Duration::days(2), &receipt)
+                .await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::Expired,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_microsoft_consumable() {
+        let consumable = MicrosoftCollectionItem {
+            product_id: Some("prod".to_string()),
+            product_type: Some("UnmanagedConsumable".to_string()),
+            status: Some("Active".to_string()),
+            quantity: Some(1),
+            transaction_id: Some("txn".to_string()),
+            ..MicrosoftCollectionItem::default()
+        };
+
+        let _oauth = mock("POST", "/tenant/oauth2/token")
+            .with_status(200)
+            .with_body(r#"{"access_token": "access"}"#)
+            .create();
+        let _collections = mock("POST", "/v6.0/collections/query")
+            .with_status(200)
+            .with_body(serde_json::json!({ "items": [consumable.clone()] }).to_string())
+            .create();
+        let _consume = mock("POST", "/v6.0/collections/consume")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"trackingId": "tracking", "removeQuantity": 1}"#.to_string(),
+            ))
+            .with_status(200)
+            .with_body(
+                serde_json::to_string(&MicrosoftCollectionItem {
+                    quantity: Some(0),
+                    ..consumable.clone()
+                })
+                .unwrap(),
+            )
+            .create();
+
+        let url = mockito::server_url();
+        let validator = UnityPurchaseValidator {
+            microsoft_urls: MicrosoftUrls {
+                collections: &url,
+                purchase: &url,
+                login: &url,
+            },
+            ..UnityPurchaseValidator::default()
+        }
+        .set_microsoft_credentials(credentials());
+
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::MicrosoftStore,
+            payload: r#"{"storeIdKey": "store", "productId": "prod"}"#.to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        assert!(
+            validator
+                .validate(Utc::now(), &receipt)
+                .await
+                .unwrap()
+                .valid
+        );
+
+        let fulfilled = validator
+            .fulfill_microsoft_consumable(&receipt, "tracking", 1)
+            .await
+            .unwrap();
+        assert_eq!(fulfilled.original_transaction_id, Some("txn".to_string()));
+
+        let consumed = MicrosoftResponse::Collection(vec![MicrosoftCollectionItem {
+            quantity: Some(0),
+            ..consumable
+        }]);
+        assert!(!validate_microsoft_purchase(&consumed, Utc::now()).valid);
+    }
+}
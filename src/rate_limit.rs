@@ -0,0 +1,277 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::error::{Error, Result};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Default number of queries per day of the Google Play Developer API, see <https://developers.google.com/android-publisher/quotas>
+const GOOGLE_QUERIES_PER_DAY: u32 = 200_000;
+/// Default number of queries per minute of the Google Play Developer API, see <https://developers.google.com/android-publisher/quotas>
+const GOOGLE_QUERIES_PER_MINUTE: u32 = 3_000;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(86_400);
+
+/// The quotas enforced by a `GoogleRateLimiter`. Defaults to the quotas Google grants each app.
+#[derive(Clone, Debug)]
+pub struct GoogleQuotas {
+    /// Maximum number of queries per minute
+    pub per_minute: u32,
+    /// Maximum number of queries per day
+    pub per_day: u32,
+    /// Calls past the limit wait for the quota to refill if they can be made within `max_wait`, and are rejected
+    /// otherwise. Zero rejects all calls past the limit.
+    pub max_wait: Duration,
+}
+
+impl Default for GoogleQuotas {
+    fn default() -> Self {
+        Self {
+            per_minute: GOOGLE_QUERIES_PER_MINUTE,
+            per_day: GOOGLE_QUERIES_PER_DAY,
+            max_wait: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The remaining budget of a `GoogleRateLimiter`, reported after each call
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitBudget {
+    /// Queries left in the current minute
+    pub per_minute: u32,
+    /// Queries left in the current day
+    pub per_day: u32,
+    /// False if the call was rejected
+    pub admitted: bool,
+}
+
+/// Receives the remaining budget of a `GoogleRateLimiter`, ie: to export it as a gauge.
+pub trait RateLimitMetrics: Send + Sync {
+    /// Called after each call was admitted or rejected
+    fn record(&self, budget: &RateLimitBudget);
+}
+
+/// A bucket holding up to `capacity` tokens, refilled continuously over `period`. Tokens may be reserved ahead,
+/// in which case the bucket goes negative and later calls wait for the refill.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    period: Duration,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, period: Duration) -> Self {
+        Self {
+            capacity: capacity.into(),
+            tokens: capacity.into(),
+            period,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        let refilled = elapsed.as_secs_f64() / self.period.as_secs_f64() * self.capacity;
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+    }
+
+    /// How long until a token is available
+    fn wait(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            self.period
+                .mul_f64((1.0 - self.tokens) / self.capacity.max(1.0))
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn remaining(&self) -> u32 {
+        self.tokens.max(0.0).floor() as u32
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    per_minute: TokenBucket,
+    per_day: TokenBucket,
+    refilled_at: Instant,
+}
+
+/// Token bucket rate limiter for the Google Play Developer API, so that bulk validations stay within the quotas
+/// of the app instead of failing until the quota resets.
+/// ```
+/// use iap::{GoogleQuotas, GoogleRateLimiter, UnityPurchaseValidator};
+/// use std::sync::Arc;
+///
+/// let validator = UnityPurchaseValidator::default()
+///     .set_google_rate_limiter(Arc::new(GoogleRateLimiter::new(GoogleQuotas::default())));
+/// ```
+pub struct GoogleRateLimiter {
+    quotas: GoogleQuotas,
+    buckets: Mutex<Buckets>,
+    metrics: Option<Arc<dyn RateLimitMetrics>>,
+}
+
+impl GoogleRateLimiter {
+    /// Creates a rate limiter with full buckets
+    #[must_use]
+    pub fn new(quotas: GoogleQuotas) -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                per_minute: TokenBucket::new(quotas.per_minute, MINUTE),
+                per_day: TokenBucket::new(quotas.per_day, DAY),
+                refilled_at: Instant::now(),
+            }),
+            quotas,
+            metrics: None,
+        }
+    }
+
+    /// Stores the `RateLimitMetrics` receiving the remaining budget after each call
+    #[must_use]
+    pub fn set_metrics(self, metrics: Arc<dyn RateLimitMetrics>) -> Self {
+        let mut new = self;
+        new.metrics = Some(metrics);
+        new
+    }
+
+    /// The remaining budget, without using any of it
+    #[must_use]
+    pub fn budget(&self) -> RateLimitBudget {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        Self::refill(&mut buckets);
+        Self::budget_of(&buckets, true)
+    }
+
+    /// Takes a token from the buckets, waiting for the refill if the quota is exhausted but refills within
+    /// `max_wait`.
+    /// # Errors
+    /// Will return a `RateLimited` error if the quota does not refill within `max_wait`
+    pub async fn acquire(&self) -> Result<()> {
+        let (wait, budget) = self.reserve();
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.record(&budget);
+        }
+
+        if !budget.admitted {
+            tracing::warn!(
+                "google rate limit exceeded, retry_after: {:?}, budget: {:?}",
+                wait,
+                budget
+            );
+            return Err(Error::RateLimited { retry_after: wait });
+        }
+
+        if !wait.is_zero() {
+            tracing::debug!("google rate limit reached, waiting: {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+
+        Ok(())
+    }
+
+    /// Reserves a token if it is available within `max_wait`, returns how long to wait for it
+    fn reserve(&self) -> (Duration, RateLimitBudget) {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        Self::refill(&mut buckets);
+
+        let wait = buckets.per_minute.wait().max(buckets.per_day.wait());
+        let admitted = wait <= self.quotas.max_wait;
+        if admitted {
+            buckets.per_minute.tokens -= 1.0;
+            buckets.per_day.tokens -= 1.0;
+        }
+
+        let budget = Self::budget_of(&buckets, admitted);
+        drop(buckets);
+        (wait, budget)
+    }
+
+    fn refill(buckets: &mut Buckets) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(buckets.refilled_at);
+        buckets.per_minute.refill(elapsed);
+        buckets.per_day.refill(elapsed);
+        buckets.refilled_at = now;
+    }
+
+    fn budget_of(buckets: &Buckets, admitted: bool) -> RateLimitBudget {
+        RateLimitBudget {
+            per_minute: buckets.per_minute.remaining(),
+            per_day: buckets.per_day.remaining(),
+            admitted,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<RateLimitBudget>>);
+
+    impl RateLimitMetrics for Recorder {
+        fn record(&self, budget: &RateLimitBudget) {
+            self.0.lock().unwrap().push(*budget);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let recorder = Arc::new(Recorder::default());
+        let limiter = GoogleRateLimiter::new(GoogleQuotas {
+            per_minute: 2,
+            per_day: 10,
+            max_wait: Duration::ZERO,
+        })
+        .set_metrics(recorder.clone());
+
+        limiter.acquire().await.unwrap();
+        limiter.acquire().await.unwrap();
+
+        let err = limiter.acquire().await.unwrap_err();
+        assert!(err.is_retryable());
+        assert!(
+            matches!(err, Error::RateLimited { retry_after } if retry_after > Duration::from_secs(20))
+        );
+
+        let budgets = recorder.0.lock().unwrap();
+        assert_eq!(budgets.len(), 3);
+        assert_eq!(budgets[0].per_minute, 1);
+        assert_eq!(budgets[0].per_day, 9);
+        assert!(budgets[1].admitted);
+        assert!(!budgets[2].admitted);
+        assert_eq!(budgets[2].per_minute, 0);
+        drop(budgets);
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let limiter = GoogleRateLimiter::new(GoogleQuotas {
+            per_minute: 600,
+            per_day: 1000,
+            max_wait: Duration::from_secs(1),
+        });
+
+        for _ in 0..600 {
+            limiter.acquire().await.unwrap();
+        }
+
+        // the bucket refills one token every 100ms
+        let start = Instant::now();
+        limiter.acquire().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(limiter.budget().per_day, 399);
+    }
+}
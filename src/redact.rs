@@ -0,0 +1,139 @@
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Names of the tracing fields holding sensitive values, which are written according to the `RedactionLevel`.
+/// Filter on these to drop them from the logs entirely.
+pub const REDACTED_FIELDS: &[&str] = &[
+    "payload",
+    "token",
+    "response_body",
+    "transaction_id",
+    "original_transaction_id",
+    "order_id",
+    "agreement_id",
+    "purchase_id",
+    "receipt_id",
+];
+
+const TRUNCATE_CHARS: usize = 4;
+const HASH_CHARS: usize = 16;
+
+static REDACTION_LEVEL: AtomicU8 = AtomicU8::new(RedactionLevel::Hash as u8);
+
+/// How receipt payloads, purchase tokens, transaction ids, secrets and raw store responses are written to the logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RedactionLevel {
+    /// Values are logged as they are, only use this for local debugging
+    Off,
+    /// Values are cut down to their first and last characters and their length
+    Truncate,
+    /// Values are replaced by the beginning of their SHA-256 hash, so log lines of the same value still correlate
+    #[default]
+    Hash,
+    /// Values are replaced by `[redacted]`
+    Full,
+}
+
+impl RedactionLevel {
+    /// Redacts `value` according to the level
+    #[must_use]
+    pub fn redact(self, value: &str) -> String {
+        match self {
+            Self::Off => value.to_string(),
+            Self::Truncate => {
+                let len = value.chars().count();
+                if len <= TRUNCATE_CHARS * 2 {
+                    format!("[{len} chars]")
+                } else {
+                    let head = value.chars().take(TRUNCATE_CHARS).collect::<String>();
+                    let tail = value.chars().skip(len - TRUNCATE_CHARS).collect::<String>();
+                    format!("{head}...{tail} [{len} chars]")
+                }
+            }
+            Self::Hash => {
+                let hash = format!("{:x}", Sha256::digest(value.as_bytes()));
+                format!("sha256:{}", &hash[..HASH_CHARS])
+            }
+            Self::Full => "[redacted]".to_string(),
+        }
+    }
+
+    const fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Self::Off,
+            1 => Self::Truncate,
+            3 => Self::Full,
+            _ => Self::Hash,
+        }
+    }
+}
+
+/// Sets how sensitive values are written to the logs, for all validators of the process. Defaults to
+/// `RedactionLevel::Hash`.
+pub fn set_redaction_level(level: RedactionLevel) {
+    REDACTION_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// The `RedactionLevel` set with `set_redaction_level`
+#[must_use]
+pub fn redaction_level() -> RedactionLevel {
+    RedactionLevel::from_u8(REDACTION_LEVEL.load(Ordering::Relaxed))
+}
+
+/// A sensitive value, redacted according to the current `RedactionLevel` when formatted
+pub struct Redacted<'a>(&'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&redaction_level().redact(self.0))
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Wraps a sensitive value for logging
+pub const fn redact(value: &str) -> Redacted<'_> {
+    Redacted(value)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let token = "opaque-token-of-the-purchase";
+
+        assert_eq!(RedactionLevel::Off.redact(token), token);
+        assert_eq!(
+            RedactionLevel::Truncate.redact(token),
+            "opaq...hase [28 chars]"
+        );
+        assert_eq!(RedactionLevel::Truncate.redact("short"), "[5 chars]");
+        assert_eq!(RedactionLevel::Full.redact(token), "[redacted]");
+
+        let hashed = RedactionLevel::Hash.redact(token);
+        assert_eq!(hashed.len(), "sha256:".len() + HASH_CHARS);
+        assert_eq!(hashed, RedactionLevel::Hash.redact(token));
+        assert_ne!(hashed, RedactionLevel::Hash.redact("other-token"));
+
+        assert_eq!(redaction_level(), RedactionLevel::Hash);
+        assert_eq!(redact(token).to_string(), hashed);
+
+        let err = crate::error::Error::AppleHttpError {
+            status: hyper::StatusCode::BAD_GATEWAY,
+            body: token.to_string(),
+        };
+        assert!(!err.to_string().contains(token));
+        assert!(err.to_string().contains(&hashed));
+    }
+}
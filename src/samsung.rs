@@ -0,0 +1,380 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::{Error, Result},
+    redact::redact,
+    PurchaseResponse, UnityPurchaseReceipt,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use hyper::{body, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Deserializer, Serialize};
+
+const SAMSUNG_IAP: &str = "https://iap.samsungapps.com";
+const SAMSUNG_STATUS_SUCCESS: &str = "true";
+const SAMSUNG_PAYMENT_STATUS_SUCCESS: &str = "Success";
+const SAMSUNG_ITEM_TYPE_SUBSCRIPTION: &str = "subscription";
+const SAMSUNG_MODE_TEST: &str = "TEST";
+const SAMSUNG_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Convenience struct for storing the base URL of the Galaxy Store IAP server.
+/// See: <https://developer.samsung.com/iap/programming-guide/iap-helper-programming.html>
+pub struct SamsungUrls<'a> {
+    /// By default, <https://iap.samsungapps.com>
+    pub verify: &'a str,
+}
+
+impl Default for SamsungUrls<'_> {
+    fn default() -> Self {
+        SamsungUrls {
+            verify: SAMSUNG_IAP,
+        }
+    }
+}
+
+/// The payload of a receipt for purchases made through the Galaxy Store IAP SDK.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SamsungReceiptData {
+    /// The purchase id returned by the IAP SDK
+    #[serde(rename = "purchaseId", alias = "mPurchaseId")]
+    pub purchase_id: String,
+}
+
+impl SamsungReceiptData {
+    /// Construct the `SamsungReceiptData` from the `UnityPurchaseReceipt` payload
+    /// # Errors
+    /// Will return an error if the payload cannot be deserialized
+    pub fn from(payload: &str) -> Result<Self> {
+        Ok(serde_json::from_str(payload)?)
+    }
+}
+
+/// Response of the Galaxy Store receipt verification, see
+/// <https://developer.samsung.com/iap/programming-guide/iap-helper-programming.html#Verify-a-purchase>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SamsungResponse {
+    /// `true` if the purchase was found, `false` otherwise
+    pub status: Option<String>,
+    /// Set if `status` is `false`
+    #[serde(default, deserialize_with = "deserialize_code")]
+    pub error_code: Option<i32>,
+    /// Set if `status` is `false`
+    pub error_message: Option<String>,
+    /// The id of the purchase
+    pub purchase_id: Option<String>,
+    /// The id of the purchased item
+    pub item_id: Option<String>,
+    /// The order id of the purchase
+    pub order_id: Option<String>,
+    /// The payment id of the purchase
+    pub payment_id: Option<String>,
+    /// The package name of the app
+    pub package_name: Option<String>,
+    /// Either `item` or `subscription`
+    pub item_type: Option<String>,
+    /// Payment status of the order, ie: `Success` or `Cancel`
+    pub payment_status: Option<String>,
+    /// Time of the purchase, formatted `yyyy-MM-dd HH:mm:ss`
+    pub purchase_date: Option<String>,
+    /// Time at which the current subscription period ends, formatted `yyyy-MM-dd HH:mm:ss`
+    pub subscription_end_date: Option<String>,
+    /// `TEST` for test purchases, `REAL` otherwise
+    pub mode: Option<String>,
+    /// `Y` if the item has been consumed
+    #[serde(rename = "consumeYN")]
+    pub consume_yn: Option<String>,
+}
+
+impl SamsungResponse {
+    /// Returns true if the `status` indicates the purchase was found
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.status.as_deref() == Some(SAMSUNG_STATUS_SUCCESS)
+    }
+
+    /// Returns true if the purchase was found and its payment succeeded
+    #[must_use]
+    pub fn is_paid(&self) -> bool {
+        self.is_success()
+            && self
+                .payment_status
+                .as_deref()
+                .is_some_and(|status| status.eq_ignore_ascii_case(SAMSUNG_PAYMENT_STATUS_SUCCESS))
+    }
+
+    /// Returns true if the purchase is a test purchase, which was never paid for
+    #[must_use]
+    pub fn is_test(&self) -> bool {
+        self.mode.as_deref() == Some(SAMSUNG_MODE_TEST)
+    }
+
+    /// Returns true if the response is for a subscription purchase
+    #[must_use]
+    pub fn is_subscription(&self) -> bool {
+        self.item_type.as_deref() == Some(SAMSUNG_ITEM_TYPE_SUBSCRIPTION)
+    }
+
+    /// The end of the current subscription period, if set
+    #[must_use]
+    pub fn subscription_end_time(&self) -> Option<DateTime<Utc>> {
+        self.subscription_end_date
+            .as_deref()
+            .and_then(|date| NaiveDateTime::parse_from_str(date, SAMSUNG_DATE_FORMAT).ok())
+            .map(|date| date.and_utc())
+    }
+}
+
+/// The error code is returned either as a number or as a string.
+fn deserialize_code<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<i32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Code {
+        String(String),
+        Number(i32),
+    }
+
+    Ok(
+        Option::<Code>::deserialize(deserializer)?.and_then(|code| match code {
+            Code::String(code) => code.parse().ok(),
+            Code::Number(code) => Some(code),
+        }),
+    )
+}
+
+/// Retrieves the response body from the Galaxy Store receipt verification
+/// # Errors
+/// Will return an error if the `payload` in the `UnityPurchaseReceipt` is malformed, or if the endpoint responds with a non success http status.
+/// Purchases which could not be found are not errors, check `SamsungResponse::is_success`.
+pub async fn fetch_samsung_receipt_data(
+    receipt: &UnityPurchaseReceipt,
+    urls: &SamsungUrls<'_>,
+) -> Result<SamsungResponse> {
+    let data = SamsungReceiptData::from(&receipt.payload)?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+
+    let req = Request::builder()
+        .method("GET")
+        .uri(format!(
+            "{}/iap/appsItemVerifyIAPReceipt.as?protocolVersion=2.0&purchaseID={}",
+            urls.verify,
+            utf8_percent_encode(&data.purchase_id, NON_ALPHANUMERIC),
+        ))
+        .body(Body::empty())?;
+
+    let resp = client.request(req).await?;
+    let status = resp.status();
+    let buf = body::to_bytes(resp).await?;
+
+    tracing::debug!(
+        status = %status,
+        response_body = %redact(&String::from_utf8_lossy(&buf).replace('\n', "")),
+        "samsung response"
+    );
+
+    if !status.is_success() {
+        return Err(Error::SamsungApiError {
+            status,
+            body: String::from_utf8_lossy(&buf).into_owned(),
+        });
+    }
+
+    let response = serde_json::from_slice::<SamsungResponse>(&buf)?;
+
+    tracing::info!(target = "samsung_response",
+        item_id = ?response.item_id,
+        item_type = ?response.item_type,
+        payment_status = ?response.payment_status,
+        subscription_end_date = ?response.subscription_end_date,
+        mode = ?response.mode,
+    );
+
+    Ok(response)
+}
+
+/// Validates a Galaxy Store purchase of the app with `package_name`.
+///
+/// Subscriptions are valid until their `subscription_end_date` has passed, all other purchases are valid if their
+/// payment succeeded. Test purchases are only valid if `test_mode` is set.
+#[must_use]
+pub fn validate_samsung_purchase(
+    response: &SamsungResponse,
+    package_name: &str,
+    test_mode: bool,
+    now: DateTime<Utc>,
+) -> PurchaseResponse {
+    let paid = response.is_paid()
+        && response.package_name.as_deref() == Some(package_name)
+        && (test_mode || !response.is_test());
+
+    let (valid, expiry_time) = if response.is_subscription() {
+        let expiry_time = response.subscription_end_time();
+        (
+            paid && expiry_time.is_some_and(|expiry| expiry > now),
+            expiry_time,
+        )
+    } else {
+        (paid, None)
+    };
+
+    tracing::info!(
+        valid,
+        now = %now,
+        purchase_id = ?response.purchase_id.as_deref().map(redact),
+        payment_status = ?response.payment_status,
+        "samsung receipt verification"
+    );
+
+    PurchaseResponse {
+        valid,
+        product_id: response.item_id.clone(),
+        original_transaction_id: response
+            .purchase_id
+            .clone()
+            .or_else(|| response.order_id.clone()),
+        expiry_time,
+        ..PurchaseResponse::default()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{InvalidReason, Platform, PurchaseVerdict, UnityPurchaseValidator, Validator};
+    use mockito::{mock, Matcher};
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_samsung_subscription() {
+        let now = Utc::now();
+        let end_date = (now + chrono::Duration::days(1))
+            .format(SAMSUNG_DATE_FORMAT)
+            .to_string();
+        let _verify = mock(
+            "GET",
+            Matcher::Regex(
+                r"^/iap/appsItemVerifyIAPReceipt.as\?protocolVersion=2.0&purchaseID=purchase$"
+                    .to_string(),
+            ),
+        )
+        .with_status(200)
+        .with_body(format!(
+            r#"{{"status": "true", "purchaseId": "purchase", "itemId": "sub", "orderId": "order", "packageName": "com.example.app", "itemType": "subscription", "paymentStatus": "Success", "subscriptionEndDate": "{end_date}", "mode": "TEST"}}"#
+        ))
+        .create();
+
+        let url = mockito::server_url();
+        let validator = UnityPurchaseValidator {
+            samsung_urls: SamsungUrls { verify: &url },
+            ..UnityPurchaseValidator::default()
+        }
+        .set_samsung_package_name("com.example.app".to_string());
+
+        let receipt = UnityPurchaseReceipt::from(
+            r#"{"Store": "SamsungApps", "TransactionID": "purchase", "Payload": "{\"mPurchaseId\": \"purchase\"}"}"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.store, Platform::SamsungGalaxyStore);
+
+        // test purchases are rejected unless the test mode is enabled
+        assert!(matches!(
+            validator.verdict(now, &receipt).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::NotPurchased,
+                ..
+            }
+        ));
+
+        let validator = validator.set_samsung_test_mode(true);
+        let response = validator.validate(now, &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("sub".to_string()));
+        assert_eq!(
+            response.original_transaction_id,
+            Some("purchase".to_string())
+        );
+
+        assert!(matches!(
+            validator
+                .verdict(now + chrono::Duration::days(2), &receipt)
+                .await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::Expired,
+                ..
+            }
+        ));
+
+        let other_app = validator.set_samsung_package_name("com.other.app".to_string());
+        assert!(matches!(
+            other_app.verdict(now, &receipt).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::TransactionNotFound,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_samsung_not_found() {
+        let _verify = mock(
+            "GET",
+            Matcher::Regex(r"^/iap/appsItemVerifyIAPReceipt.as".to_string()),
+        )
+        .with_status(200)
+        .with_body(r#"{"status": "false", "errorCode": "9135", "errorMessage": "Not exist order"}"#)
+        .create();
+
+        let url = mockito::server_url();
+        let validator = UnityPurchaseValidator {
+            samsung_urls: SamsungUrls { verify: &url },
+            ..UnityPurchaseValidator::default()
+        }
+        .set_samsung_package_name("com.example.app".to_string());
+
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::SamsungGalaxyStore,
+            payload: r#"{"purchaseId": "unknown"}"#.to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        assert!(matches!(
+            validator.verdict(Utc::now(), &receipt).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::StoreStatus(9135),
+                ..
+            }
+        ));
+
+        let paid = SamsungResponse {
+            status: Some("true".to_string()),
+            package_name: Some("com.example.app".to_string()),
+            item_type: Some("item".to_string()),
+            payment_status: Some("Success".to_string()),
+            ..SamsungResponse::default()
+        };
+        assert!(validate_samsung_purchase(&paid, "com.example.app", false, Utc::now()).valid);
+
+        let cancelled = SamsungResponse {
+            payment_status: Some("Cancel".to_string()),
+            ..paid.clone()
+        };
+        assert!(!validate_samsung_purchase(&cancelled, "com.example.app", false, Utc::now()).valid);
+
+        let unknown_payment = SamsungResponse {
+            payment_status: None,
+            ..paid
+        };
+        assert!(
+            !validate_samsung_purchase(&unknown_payment, "com.example.app", false, Utc::now())
+                .valid
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use super::{error::Result, Platform};
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A `SQLite` connection shared by the calls of a store. rusqlite blocks, so the calls run on tokio's blocking
+/// thread pool instead of the async runtime.
+#[derive(Clone)]
+pub struct SharedConnection(Arc<Mutex<Connection>>);
+
+impl SharedConnection {
+    pub fn new(connection: Connection) -> Self {
+        Self(Arc::new(Mutex::new(connection)))
+    }
+
+    /// Runs `call` with the connection on the blocking thread pool
+    pub async fn call<T, F>(&self, call: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+            call(&connection)
+        })
+        .await
+        .map_err(std::io::Error::from)?
+    }
+}
+
+/// The value of the `store` column, which must not change with the `Debug` or serde representation of `Platform`
+pub const fn store_key(store: Platform) -> &'static str {
+    match store {
+        Platform::AppleAppStore => "AppleAppStore",
+        Platform::GooglePlay => "GooglePlay",
+        Platform::AmazonAppStore => "AmazonAppStore",
+        Platform::HuaweiAppGallery => "HuaweiAppGallery",
+        Platform::MicrosoftStore => "MicrosoftStore",
+        Platform::Steam => "Steam",
+        Platform::SamsungGalaxyStore => "SamsungGalaxyStore",
+        Platform::FakeStore => "FakeStore",
+    }
+}
@@ -0,0 +1,619 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{
+    error::{Error, Result},
+    redact::redact,
+    PurchaseResponse,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use hyper::{body, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Deserializer, Serialize};
+
+const STEAM_PARTNER_API: &str = "https://partner.steam-api.com";
+const STEAM_INTERFACE: &str = "ISteamMicroTxn";
+const STEAM_INTERFACE_SANDBOX: &str = "ISteamMicroTxnSandbox";
+const STEAM_RESULT_OK: &str = "OK";
+/// Order statuses in which the items have been paid for, see <https://partner.steamgames.com/doc/webapi/ISteamMicroTxn#QueryTxn>
+const STEAM_ORDER_STATUSES_VALID: [&str; 2] = ["Succeeded", "PartialRefund"];
+const STEAM_AGREEMENT_STATUS_INACTIVE: &str = "Inactive";
+
+/// Convenience struct for storing the base URL of the Steam partner Web API.
+/// See: <https://partner.steamgames.com/doc/webapi/ISteamMicroTxn>
+pub struct SteamUrls<'a> {
+    /// By default, <https://partner.steam-api.com>
+    pub base: &'a str,
+}
+
+impl Default for SteamUrls<'_> {
+    fn default() -> Self {
+        SteamUrls {
+            base: STEAM_PARTNER_API,
+        }
+    }
+}
+
+/// The publisher Web API key and the app id used to query the microtransactions of the app.
+#[derive(Clone, Debug, Default)]
+pub struct SteamCredentials {
+    /// The publisher Web API key
+    pub key: String,
+    /// The app id of the game
+    pub app_id: u32,
+}
+
+/// The payload of a receipt for purchases made through Steam microtransactions.
+///
+/// `steamId` is the user the purchase is validated for, orders of other users are rejected.
+/// `agreementId` is only required for subscriptions.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SteamReceiptData {
+    /// The order id passed to `InitTxn`
+    pub order_id: String,
+    /// The 64 bit Steam id of the user, required
+    pub steam_id: Option<String>,
+    /// The id of the recurring billing agreement of a subscription
+    pub agreement_id: Option<String>,
+    /// True if the client reports the order was made through the `ISteamMicroTxnSandbox` interface. This is not
+    /// trusted, the interface queried is chosen with `UnityPurchaseValidator::set_steam_sandbox`.
+    #[serde(default)]
+    pub is_sandbox: bool,
+}
+
+impl SteamReceiptData {
+    /// Construct the `SteamReceiptData` from the `UnityPurchaseReceipt` payload
+    /// # Errors
+    /// Will return an error if the payload cannot be deserialized
+    pub fn from(payload: &str) -> Result<Self> {
+        Ok(serde_json::from_str(payload)?)
+    }
+
+    /// Returns true if the receipt is for a subscription, ie: has an agreement id
+    #[must_use]
+    pub const fn is_subscription(&self) -> bool {
+        self.agreement_id.is_some()
+    }
+}
+
+/// The `response` object returned by all `ISteamMicroTxn` methods.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SteamResponse<T> {
+    /// Either `OK` or `Failure`
+    pub result: String,
+    /// The result of the method, only set on success
+    pub params: Option<T>,
+    /// Only set on failure
+    pub error: Option<SteamError>,
+}
+
+impl<T> SteamResponse<T> {
+    /// Returns true if the `result` indicates success
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.result == STEAM_RESULT_OK
+    }
+}
+
+/// See <https://partner.steamgames.com/doc/webapi/ISteamMicroTxn#ErrorCodes>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SteamError {
+    /// The error code
+    #[serde(rename = "errorcode")]
+    pub error_code: i32,
+    /// Description of the error
+    #[serde(rename = "errordesc")]
+    pub error_description: Option<String>,
+}
+
+/// See <https://partner.steamgames.com/doc/webapi/ISteamMicroTxn#QueryTxn> for more details on each field.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SteamOrder {
+    /// The order id
+    #[serde(rename = "orderid", default, deserialize_with = "deserialize_id")]
+    pub order_id: Option<String>,
+    /// Steam's transaction id
+    #[serde(rename = "transid", default, deserialize_with = "deserialize_id")]
+    pub trans_id: Option<String>,
+    /// The 64 bit Steam id of the user
+    #[serde(rename = "steamid", default, deserialize_with = "deserialize_id")]
+    pub steam_id: Option<String>,
+    /// Either `Init`, `Approved`, `Succeeded`, `Failed`, `Refunded`, `PartialRefund`, `Chargedback`,
+    /// `RefundedSuspectedFraud` or `RefundedFriendlyFraud`
+    pub status: Option<String>,
+    /// ISO 4217 currency code
+    pub currency: Option<String>,
+    /// Time of the transaction, ie: `2014-01-31T15:24:27Z`
+    pub time: Option<String>,
+    /// The items of the order
+    #[serde(default)]
+    pub items: Vec<SteamOrderItem>,
+}
+
+/// An item of a `SteamOrder`.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SteamOrderItem {
+    /// The game's item id
+    #[serde(rename = "itemid", default, deserialize_with = "deserialize_id")]
+    pub item_id: Option<String>,
+    /// The quantity of the item
+    #[serde(rename = "qty")]
+    pub quantity: Option<i64>,
+    /// Total cost excluding VAT, in cents
+    pub amount: Option<i64>,
+    /// The VAT, in cents
+    pub vat: Option<i64>,
+    /// Status of the item, same values as `SteamOrder::status`
+    #[serde(rename = "itemstatus")]
+    pub item_status: Option<String>,
+}
+
+/// See <https://partner.steamgames.com/doc/webapi/ISteamMicroTxn#FinalizeTxn>
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SteamFinalizedOrder {
+    /// The order id
+    #[serde(rename = "orderid", default, deserialize_with = "deserialize_id")]
+    pub order_id: Option<String>,
+    /// Steam's transaction id
+    #[serde(rename = "transid", default, deserialize_with = "deserialize_id")]
+    pub trans_id: Option<String>,
+}
+
+/// The recurring billing agreements of a user.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SteamAgreements {
+    /// The agreements
+    #[serde(default)]
+    pub agreements: Vec<SteamAgreement>,
+}
+
+/// See <https://partner.steamgames.com/doc/webapi/ISteamMicroTxn#GetUserAgreementInfo> for more details on each field.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SteamAgreement {
+    /// The id of the agreement
+    #[serde(rename = "agreementid", default, deserialize_with = "deserialize_id")]
+    pub agreement_id: Option<String>,
+    /// The game's item id
+    #[serde(rename = "itemid", default, deserialize_with = "deserialize_id")]
+    pub item_id: Option<String>,
+    /// Either `Active`, `Canceled` or `Inactive`
+    pub status: Option<String>,
+    /// Billing period unit, ie: `Month`
+    pub period: Option<String>,
+    /// Number of periods between payments
+    pub frequency: Option<i64>,
+    /// Date the agreement started, formatted `YYYYMMDD`
+    #[serde(rename = "startdate")]
+    pub start_date: Option<String>,
+    /// Date the agreement ends, formatted `YYYYMMDD`
+    #[serde(rename = "enddate")]
+    pub end_date: Option<String>,
+    /// Date of the next payment, formatted `YYYYMMDD`
+    #[serde(rename = "nextpayment")]
+    pub next_payment: Option<String>,
+    /// Date of the last successful payment, formatted `YYYYMMDD`
+    #[serde(rename = "lastpayment")]
+    pub last_payment: Option<String>,
+    /// Number of failed payment attempts
+    #[serde(rename = "failedattempts")]
+    pub failed_attempts: Option<i64>,
+}
+
+impl SteamAgreement {
+    /// The time until which the subscription has been paid for, ie: the end of the day of the next payment,
+    /// or of the end date if no payment is scheduled.
+    #[must_use]
+    pub fn expiry_time(&self) -> Option<DateTime<Utc>> {
+        self.next_payment
+            .as_deref()
+            .or(self.end_date.as_deref())
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+            .and_then(|date| date.succ_opt())
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|date| date.and_utc())
+    }
+}
+
+#[derive(Deserialize)]
+struct SteamEnvelope<T> {
+    response: SteamResponse<T>,
+}
+
+/// Steam ids are 64 bit integers, which are returned either as numbers or as strings.
+fn deserialize_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        String(String),
+        Number(u64),
+    }
+
+    Ok(Option::<Id>::deserialize(deserializer)?.map(|id| match id {
+        Id::String(id) => id,
+        Id::Number(id) => id.to_string(),
+    }))
+}
+
+/// Retrieves the order of a receipt with `QueryTxn`. `sandbox` selects the `ISteamMicroTxnSandbox` interface.
+/// # Errors
+/// Will return an error if the endpoint responds with a non success http status or the response cannot be deserialized.
+/// Failures reported in the `result` of the response are not errors, check `SteamResponse::is_success`.
+pub async fn fetch_steam_order(
+    data: &SteamReceiptData,
+    credentials: &SteamCredentials,
+    urls: &SteamUrls<'_>,
+    sandbox: bool,
+) -> Result<SteamResponse<SteamOrder>> {
+    let response = request::<SteamOrder>(
+        "GET",
+        format!(
+            "{}/{}/QueryTxn/v3/?key={}&appid={}&orderid={}",
+            urls.base,
+            interface(sandbox),
+            utf8_percent_encode(&credentials.key, NON_ALPHANUMERIC),
+            credentials.app_id,
+            utf8_percent_encode(&data.order_id, NON_ALPHANUMERIC),
+        ),
+        Body::empty(),
+    )
+    .await?;
+
+    tracing::info!(target = "steam_response",
+        order_id = %redact(&data.order_id),
+        result = %response.result,
+        status = ?response.params.as_ref().and_then(|order| order.status.as_ref()),
+    );
+
+    Ok(response)
+}
+
+/// Completes an order approved by the user with `FinalizeTxn`, after which the user is charged. `sandbox` selects the `ISteamMicroTxnSandbox` interface.
+/// # Errors
+/// Will return an error if the endpoint responds with a non success http status or the response cannot be deserialized.
+/// Failures reported in the `result` of the response are not errors, check `SteamResponse::is_success`.
+pub async fn finalize_steam_order(
+    data: &SteamReceiptData,
+    credentials: &SteamCredentials,
+    urls: &SteamUrls<'_>,
+    sandbox: bool,
+) -> Result<SteamResponse<SteamFinalizedOrder>> {
+    let response = request::<SteamFinalizedOrder>(
+        "POST",
+        format!("{}/{}/FinalizeTxn/v2/", urls.base, interface(sandbox)),
+        Body::from(format!(
+            "key={}&appid={}&orderid={}",
+            utf8_percent_encode(&credentials.key, NON_ALPHANUMERIC),
+            credentials.app_id,
+            utf8_percent_encode(&data.order_id, NON_ALPHANUMERIC),
+        )),
+    )
+    .await?;
+
+    tracing::info!(target = "steam_response",
+        order_id = %redact(&data.order_id),
+        result = %response.result,
+        finalized = %response.is_success(),
+    );
+
+    Ok(response)
+}
+
+/// Retrieves the recurring billing agreements of the user of a receipt with `GetUserAgreementInfo`. `sandbox` selects the `ISteamMicroTxnSandbox` interface.
+/// # Errors
+/// Will return an error if the receipt has no `steam_id`, if the endpoint responds with a non success http status
+/// or if the response cannot be deserialized.
+/// Failures reported in the `result` of the response are not errors, check `SteamResponse::is_success`.
+pub async fn fetch_steam_agreements(
+    data: &SteamReceiptData,
+    credentials: &SteamCredentials,
+    urls: &SteamUrls<'_>,
+    sandbox: bool,
+) -> Result<SteamResponse<SteamAgreements>> {
+    let steam_id = data.steam_id.as_ref().ok_or_else(|| {
+        Error::Custom("the steam id is required to query the user's agreements".to_string())
+    })?;
+
+    let response = request::<SteamAgreements>(
+        "GET",
+        format!(
+            "{}/{}/GetUserAgreementInfo/v2/?key={}&appid={}&steamid={}",
+            urls.base,
+            interface(sandbox),
+            utf8_percent_encode(&credentials.key, NON_ALPHANUMERIC),
+            credentials.app_id,
+            utf8_percent_encode(steam_id, NON_ALPHANUMERIC),
+        ),
+        Body::empty(),
+    )
+    .await?;
+
+    tracing::info!(target = "steam_response",
+        agreement_id = ?data.agreement_id.as_deref().map(redact),
+        result = %response.result,
+        agreements = ?response.params.as_ref().map(|params| params.agreements.len()),
+    );
+
+    Ok(response)
+}
+
+const fn interface(sandbox: bool) -> &'static str {
+    if sandbox {
+        STEAM_INTERFACE_SANDBOX
+    } else {
+        STEAM_INTERFACE
+    }
+}
+
+async fn request<T: serde::de::DeserializeOwned>(
+    method: &str,
+    uri: String,
+    request_body: Body,
+) -> Result<SteamResponse<T>> {
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(request_body)?;
+
+    let resp = client.request(req).await?;
+    let status = resp.status();
+    let buf = body::to_bytes(resp).await?;
+
+    tracing::debug!(
+        status = %status,
+        response_body = %redact(&String::from_utf8_lossy(&buf).replace('\n', "")),
+        "steam response"
+    );
+
+    if !status.is_success() {
+        return Err(Error::SteamApiError {
+            status,
+            body: String::from_utf8_lossy(&buf).into_owned(),
+        });
+    }
+
+    Ok(serde_json::from_slice::<SteamEnvelope<T>>(&buf)?.response)
+}
+
+/// Validates a Steam order of the user with `steam_id`, which is valid once it has been finalized and not (fully)
+/// refunded.
+#[must_use]
+pub fn validate_steam_order(
+    order: &SteamOrder,
+    steam_id: &str,
+    now: DateTime<Utc>,
+) -> PurchaseResponse {
+    let valid = order.steam_id.as_deref() == Some(steam_id)
+        && order
+            .status
+            .as_deref()
+            .is_some_and(|status| STEAM_ORDER_STATUSES_VALID.contains(&status));
+
+    tracing::info!(
+        valid,
+        now = %now,
+        order_id = ?order.order_id.as_deref().map(redact),
+        status = ?order.status,
+        "steam order verification"
+    );
+
+    PurchaseResponse {
+        valid,
+        product_id: order.items.first().and_then(|item| item.item_id.clone()),
+        original_transaction_id: order.order_id.clone(),
+        expiry_time: None,
+        ..PurchaseResponse::default()
+    }
+}
+
+/// Validates a Steam subscription, which is valid until the end of the paid period unless the agreement is inactive.
+#[must_use]
+pub fn validate_steam_agreement(
+    agreement: &SteamAgreement,
+    now: DateTime<Utc>,
+) -> PurchaseResponse {
+    let expiry_time = agreement.expiry_time();
+    let valid = agreement.status.as_deref() != Some(STEAM_AGREEMENT_STATUS_INACTIVE)
+        && expiry_time.is_some_and(|expiry| expiry > now);
+
+    tracing::info!(
+        valid,
+        now = %now,
+        agreement_id = ?agreement.agreement_id.as_deref().map(redact),
+        status = ?agreement.status,
+        expiry_time = ?expiry_time,
+        "steam agreement verification"
+    );
+
+    PurchaseResponse {
+        valid,
+        product_id: agreement.item_id.clone(),
+        original_transaction_id: agreement.agreement_id.clone(),
+        expiry_time,
+        ..PurchaseResponse::default()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        InvalidReason, Platform, PurchaseVerdict, UnityPurchaseReceipt, UnityPurchaseValidator,
+        Validator,
+    };
+    use mockito::{mock, Matcher};
+    use serial_test::serial;
+
+    fn validator(url: &str) -> UnityPurchaseValidator<'_> {
+        UnityPurchaseValidator {
+            steam_urls: SteamUrls { base: url },
+            ..UnityPurchaseValidator::default()
+        }
+        .set_steam_credentials("key".to_string(), 480)
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_steam_order() {
+        let _query = mock(
+            "GET",
+            Matcher::Regex(r"^/ISteamMicroTxnSandbox/QueryTxn/v3/\?key=key&appid=480&orderid=42$".to_string()),
+        )
+        .with_status(200)
+        .with_body(
+            r#"{"response": {"result": "OK", "params": {"orderid": "42", "transid": 1234, "steamid": "76561197960287930", "status": "Succeeded", "currency": "USD", "time": "2024-01-31T15:24:27Z", "items": [{"itemid": 7, "qty": 1, "amount": 150, "vat": 0, "itemstatus": "Succeeded"}]}}}"#,
+        )
+        .create();
+
+        let url = mockito::server_url();
+        let receipt = UnityPurchaseReceipt::from(
+            r#"{"Store": "Steam", "TransactionID": "42", "Payload": "{\"orderId\": \"42\", \"steamId\": \"76561197960287930\", \"isSandbox\": true}"}"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.store, Platform::Steam);
+
+        let validator = validator(&url).set_steam_sandbox(true);
+        let response = validator.validate(Utc::now(), &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("7".to_string()));
+        assert_eq!(response.original_transaction_id, Some("42".to_string()));
+
+        // the order of another user
+        let other_user = UnityPurchaseReceipt {
+            payload: r#"{"orderId": "42", "steamId": "76561197960287931"}"#.to_string(),
+            ..receipt.clone()
+        };
+        assert!(matches!(
+            validator.verdict(Utc::now(), &other_user).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::TransactionNotFound,
+                ..
+            }
+        ));
+
+        let missing_steam_id = UnityPurchaseReceipt {
+            payload: r#"{"orderId": "42"}"#.to_string(),
+            ..receipt
+        };
+        assert!(matches!(
+            validator.verdict(Utc::now(), &missing_steam_id).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt(_),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_steam_ignores_client_sandbox() {
+        let _query = mock(
+            "GET",
+            Matcher::Regex(r"^/ISteamMicroTxn/QueryTxn/v3/\?key=key&appid=480&orderid=42$".to_string()),
+        )
+        .with_status(200)
+        .with_body(
+            r#"{"response": {"result": "Failure", "error": {"errorcode": 100, "errordesc": "Invalid order id"}}}"#,
+        )
+        .create();
+
+        let url = mockito::server_url();
+        let receipt = UnityPurchaseReceipt::from(
+            r#"{"Store": "Steam", "TransactionID": "42", "Payload": "{\"orderId\": \"42\", \"steamId\": \"76561197960287930\", \"isSandbox\": true}"}"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            validator(&url).verdict(Utc::now(), &receipt).await,
+            PurchaseVerdict::Invalid { .. }
+        ));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_steam_finalize_failure() {
+        let _finalize = mock("POST", "/ISteamMicroTxn/FinalizeTxn/v2/")
+            .match_body("key=key&appid=480&orderid=42")
+            .with_status(200)
+            .with_body(
+                r#"{"response": {"result": "Failure", "error": {"errorcode": 100, "errordesc": "Order not approved"}}}"#,
+            )
+            .create();
+
+        let url = mockito::server_url();
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::Steam,
+            payload: r#"{"orderId": "42", "steamId": "76561197960287930"}"#.to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+
+        assert!(matches!(
+            validator(&url)
+                .finalize_steam_order(Utc::now(), &receipt)
+                .await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::StoreStatus(100),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_steam_subscription() {
+        let next_payment = (Utc::now() + chrono::Duration::days(10))
+            .format("%Y%m%d")
+            .to_string();
+        let _agreements = mock(
+            "GET",
+            Matcher::Regex(r"^/ISteamMicroTxn/GetUserAgreementInfo/v2/\?key=key&appid=480&steamid=76561197960287930$".to_string()),
+        )
+        .with_status(200)
+        .with_body(format!(
+            r#"{{"response": {{"result": "OK", "params": {{"agreements": [{{"agreementid": "1", "itemid": "9", "status": "Canceled", "period": "Month", "frequency": 1, "nextpayment": "{next_payment}"}}]}}}}}}"#
+        ))
+        .create();
+
+        let url = mockito::server_url();
+        let receipt = UnityPurchaseReceipt {
+            store: Platform::Steam,
+            payload: r#"{"orderId": "42", "steamId": "76561197960287930", "agreementId": "1"}"#
+                .to_string(),
+            ..UnityPurchaseReceipt::default()
+        };
+        let validator = validator(&url);
+
+        let response = validator.validate(Utc::now(), &receipt).await.unwrap();
+        assert!(response.valid);
+        assert_eq!(response.product_id, Some("9".to_string()));
+
+        assert!(matches!(
+            validator
+                .verdict(Utc::now() + chrono::Duration::days(12), &receipt)
+                .await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::Expired,
+                ..
+            }
+        ));
+
+        let missing_steam_id = UnityPurchaseReceipt {
+            payload: r#"{"orderId": "42", "agreementId": "1"}"#.to_string(),
+            ..receipt
+        };
+        assert!(matches!(
+            validator.verdict(Utc::now(), &missing_steam_id).await,
+            PurchaseVerdict::Invalid {
+                reason: InvalidReason::MalformedReceipt(_),
+                ..
+            }
+        ));
+    }
+}
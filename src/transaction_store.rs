@@ -0,0 +1,165 @@
+#![allow(clippy::module_name_repetitions)]
+
+use super::{error::Result, Platform};
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::Mutex};
+
+/// Storage for redeemed transactions, used to prevent the same purchase from being redeemed by multiple users.
+/// Transactions are identified by Apple's `original_transaction_id` or Google's purchase token.
+#[async_trait]
+pub trait TransactionStore: Send + Sync {
+    /// Records that `user_id` redeemed the transaction, unless it was already redeemed before.
+    /// This has to be atomic, returns the id of the user who redeemed the transaction first.
+    async fn redeem(&self, store: Platform, transaction_id: &str, user_id: &str) -> Result<String>;
+}
+
+/// `TransactionStore` which keeps the redeemed transactions in memory. Useful for tests or single instance deployments.
+#[derive(Default, Debug)]
+pub struct InMemoryTransactionStore {
+    redemptions: Mutex<HashMap<(Platform, String), String>>,
+}
+
+#[async_trait]
+impl TransactionStore for InMemoryTransactionStore {
+    async fn redeem(&self, store: Platform, transaction_id: &str, user_id: &str) -> Result<String> {
+        let mut redemptions = self
+            .redemptions
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        Ok(redemptions
+            .entry((store, transaction_id.to_string()))
+            .or_insert_with(|| user_id.to_string())
+            .clone())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteTransactionStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{Platform, Result, TransactionStore};
+    use crate::sqlite::{store_key, SharedConnection};
+    use async_trait::async_trait;
+    use rusqlite::{params, Connection};
+    use std::path::Path;
+
+    /// `TransactionStore` backed by a `SQLite` database. Requires the `sqlite` feature.
+    pub struct SqliteTransactionStore {
+        connection: SharedConnection,
+    }
+
+    impl SqliteTransactionStore {
+        /// Opens (or creates) the database at `path` and creates the `iap_redemptions` table if it does not exist.
+        /// # Errors
+        /// Will return an error if the database cannot be opened or the table cannot be created
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            Self::new(Connection::open(path)?)
+        }
+
+        /// Creates the store from an existing connection, creating the `iap_redemptions` table if it does not exist.
+        /// # Errors
+        /// Will return an error if the table cannot be created
+        pub fn new(connection: Connection) -> Result<Self> {
+            connection.execute(
+                "CREATE TABLE IF NOT EXISTS iap_redemptions (
+                    store TEXT NOT NULL,
+                    transaction_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    redeemed_at INTEGER NOT NULL,
+                    PRIMARY KEY (store, transaction_id)
+                )",
+                [],
+            )?;
+
+            Ok(Self {
+                connection: SharedConnection::new(connection),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl TransactionStore for SqliteTransactionStore {
+        async fn redeem(
+            &self,
+            store: Platform,
+            transaction_id: &str,
+            user_id: &str,
+        ) -> Result<String> {
+            let store = store_key(store);
+            let transaction_id = transaction_id.to_string();
+            let user_id = user_id.to_string();
+
+            self.connection
+                .call(move |connection| {
+                    connection.execute(
+                        "INSERT OR IGNORE INTO iap_redemptions (store, transaction_id, user_id, redeemed_at) VALUES (?1, ?2, ?3, ?4)",
+                        params![store, transaction_id, user_id, chrono::Utc::now().timestamp()],
+                    )?;
+
+                    Ok(connection.query_row(
+                        "SELECT user_id FROM iap_redemptions WHERE store = ?1 AND transaction_id = ?2",
+                        params![store, transaction_id],
+                        |row| row.get(0),
+                    )?)
+                })
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_redeem() {
+        let store = InMemoryTransactionStore::default();
+
+        assert_eq!(
+            store
+                .redeem(Platform::AppleAppStore, "txn", "user1")
+                .await
+                .unwrap(),
+            "user1"
+        );
+        assert_eq!(
+            store
+                .redeem(Platform::AppleAppStore, "txn", "user2")
+                .await
+                .unwrap(),
+            "user1"
+        );
+        assert_eq!(
+            store
+                .redeem(Platform::GooglePlay, "txn", "user2")
+                .await
+                .unwrap(),
+            "user2"
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_redeem() {
+        let store =
+            SqliteTransactionStore::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+
+        assert_eq!(
+            store
+                .redeem(Platform::GooglePlay, "token", "user1")
+                .await
+                .unwrap(),
+            "user1"
+        );
+        assert_eq!(
+            store
+                .redeem(Platform::GooglePlay, "token", "user2")
+                .await
+                .unwrap(),
+            "user1"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use super::{
+    error::{Error, Result},
+    PurchaseResponse,
+};
+use chrono::{DateTime, Utc};
+
+/// The outcome of a validation, which distinguishes between receipts that were rejected by the store
+/// and validations which could not be completed.
+#[derive(Debug)]
+pub enum PurchaseVerdict {
+    /// The receipt represents a valid purchase and/or active subscription.
+    Valid(PurchaseResponse),
+    /// The receipt does not represent a valid purchase, the purchase should be denied.
+    Invalid {
+        /// Why the receipt was deemed invalid
+        reason: InvalidReason,
+        /// Product identifier, if it could be determined
+        product_id: Option<String>,
+    },
+    /// The validation could not be completed, ie: because of network issues, store outages or misconfiguration.
+    /// Use `Error::is_retryable` to decide whether to retry at a later time.
+    Indeterminate(Error),
+    /// The validation could not be completed, but the purchase was valid when it was last validated and is still
+    /// honored by the `OfflineFallback` of the validator.
+    Stale {
+        /// The response of the last successful validation
+        response: PurchaseResponse,
+        /// When the purchase was last validated
+        validated_at: DateTime<Utc>,
+        /// Why the validation could not be completed
+        error: Error,
+    },
+}
+
+/// The reason a receipt was deemed invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvalidReason {
+    /// The receipt payload could not be parsed
+    MalformedReceipt(String),
+    /// The store rejected the receipt with this status code, see <https://developer.apple.com/documentation/appstorereceipts/status>
+    StoreStatus(i32),
+    /// The transaction could not be found in the receipt
+    TransactionNotFound,
+    /// The subscription has expired
+    Expired,
+    /// The product is not in the purchased state, ie: it was cancelled or is still pending
+    NotPurchased,
+    /// The store reported the purchase token as no longer valid
+    PurchaseTokenInvalid,
+    /// The signature of the purchase data could not be verified
+    InvalidSignature,
+    /// The purchase was already redeemed by a different user
+    AlreadyRedeemed {
+        /// The user who redeemed the purchase first
+        user_id: String,
+    },
+    /// The receipt was created by Unity IAP's FakeStore, which is only accepted in dev mode
+    FakeStore,
+    /// The validator did not provide a reason
+    Unspecified,
+}
+
+impl PurchaseVerdict {
+    /// Creates the verdict for a failed validation. Errors which prove the purchase itself is invalid
+    /// result in `Invalid`, all other errors result in `Indeterminate`.
+    #[must_use]
+    pub fn from_error(err: Error) -> Self {
+        if err.is_purchase_token_invalid() {
+            Self::Invalid {
+                reason: InvalidReason::PurchaseTokenInvalid,
+                product_id: None,
+            }
+        } else {
+            Self::Indeterminate(err)
+        }
+    }
+
+    /// Returns true if the verdict is `Valid`
+    #[must_use]
+    pub const fn is_valid(&self) -> bool {
+        matches!(self, Self::Valid(_))
+    }
+
+    /// Returns true if the verdict is `Stale`, ie: valid according to the last successful validation
+    #[must_use]
+    pub const fn is_stale(&self) -> bool {
+        matches!(self, Self::Stale { .. })
+    }
+
+    /// Returns true if the validation could not be completed and can be retried at a later time
+    #[must_use]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Indeterminate(err) | Self::Stale { error: err, .. } => err.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Converts the verdict into the `PurchaseResponse` returned by `Validator::validate`.
+    /// # Errors
+    /// Will return the error of an `Indeterminate` verdict, `Stale` verdicts return the last valid response
+    pub fn into_result(self) -> Result<PurchaseResponse> {
+        match self {
+            Self::Valid(response) | Self::Stale { response, .. } => Ok(response),
+            Self::Invalid { product_id, .. } => Ok(PurchaseResponse {
+                valid: false,
+                product_id,
+                ..PurchaseResponse::default()
+            }),
+            Self::Indeterminate(err) => Err(err),
+        }
+    }
+}
+
+impl From<Result<PurchaseResponse>> for PurchaseVerdict {
+    fn from(result: Result<PurchaseResponse>) -> Self {
+        match result {
+            Ok(response) if response.valid => Self::Valid(response),
+            Ok(response) => Self::Invalid {
+                reason: InvalidReason::Unspecified,
+                product_id: response.product_id,
+            },
+            Err(err) => Self::from_error(err),
+        }
+    }
+}